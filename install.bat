@@ -2,6 +2,8 @@
 
 cargo build --bin pasm
 cargo build --bin pemu
+cargo build --bin pcc
 mkdir %userprofile%\.poc
 copy target\debug\pasm.exe %userprofile%\.poc
-copy target\debug\pemu.exe %userprofile%\.poc
\ No newline at end of file
+copy target\debug\pemu.exe %userprofile%\.poc
+copy target\debug\pcc.exe %userprofile%\.poc
\ No newline at end of file
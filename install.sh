@@ -1,5 +1,7 @@
 #!/bin/sh
 cargo build --bin pasm
 cargo build --bin pemu
+cargo build --bin pcc
 mv target/debug/pasm ~/bin
-mv target/debug/pemu ~/bin
\ No newline at end of file
+mv target/debug/pemu ~/bin
+mv target/debug/pcc ~/bin
\ No newline at end of file
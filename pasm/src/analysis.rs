@@ -0,0 +1,542 @@
+use std::collections::{HashMap, HashSet, BTreeMap, btree_map::Entry};
+
+use crate::{cfg::{ControlFlowGraph, is_register, label_argument}, parser::{Node, NodeKind}, message::{Message, Result, human_count}, lexer::TokenKind, source::Span, code::Code};
+
+struct StackChecker<'a, 'b> {
+    cfg: &'b ControlFlowGraph<'a>,
+    warnings: Vec<Message>,
+    reported: HashSet<usize>
+}
+
+impl<'a, 'b> StackChecker<'a, 'b> {
+    fn warn(&mut self, node: usize, message: Message) {
+        if self.reported.insert(node) {
+            self.warnings.push(message);
+        }
+    }
+
+    fn routine_name(&self, block: usize) -> String {
+        self.cfg.blocks[block].labels.first().cloned().unwrap_or_default()
+    }
+
+    /// Walks every path from `entry`, which is reached with `depth` values
+    /// on the stack. Routines are entered with their return address on the
+    /// stack, so `routine` is given for them and they must `ret` at depth 1.
+    /// Returns the routines called along the way.
+    fn check(&mut self, entry: usize, depth: usize, routine: Option<usize>) -> Vec<usize> {
+        let mut depths: HashMap<usize, usize> = HashMap::new();
+        let mut worklist = vec![(entry, depth)];
+        let mut calls = vec![];
+
+        'blocks: while let Some((block, mut depth)) = worklist.pop() {
+            if let Some(previous) = depths.get(&block) {
+                if *previous != depth {
+                    let node = self.cfg.blocks[block].nodes.start;
+                    let span = self.cfg.program[node].span.clone();
+
+                    self.warn(node, Message::warning(match self.cfg.blocks[block].labels.first() {
+                        Some(label) => format!("stack depth at '{}' depends on the path taken", label),
+                        None => String::from("stack depth depends on the path taken")
+                    })
+                        .with_id(Code::InconsistentStackDepth)
+                        .with_code(format!("reached with {} and {} on the stack",
+                            previous, human_count("value", depth)
+                        ), span)
+                    );
+                }
+
+                continue;
+            }
+            depths.insert(block, depth);
+
+            for i in self.cfg.blocks[block].nodes.clone() {
+                let node = &self.cfg.program[i];
+
+                match &node.value {
+                    NodeKind::Instruction { name, .. } => match name.value.as_str() {
+                        "psh" => depth += 1,
+                        "pop" | "ret" if depth == 0 => {
+                            let message = match routine {
+                                Some(routine) => Message::warning(format!(
+                                    "'{}' pops below the return address of routine '{}'",
+                                    &name.value, self.routine_name(routine)
+                                )).with_id(Code::StackUnderflow),
+                                None => Message::warning(format!("'{}' pops from an empty stack", &name.value)).with_id(Code::StackUnderflow)
+                            };
+
+                            self.warn(i, message.with_code(String::from("stack is empty here"), node.span.clone()));
+                        },
+                        "pop" => depth -= 1,
+                        "ret" => if let Some(routine) = routine {
+                            if depth != 1 {
+                                let span = self.cfg.program[self.cfg.blocks[routine].nodes.start].span.clone();
+
+                                self.warn(i, Message::warning(format!(
+                                    "routine '{}' returns with {} left on the stack",
+                                    self.routine_name(routine), human_count("extra value", depth - 1)
+                                ))
+                                    .with_id(Code::UnbalancedReturn)
+                                    .with_code(String::from("does not return to the caller"), node.span.clone())
+                                    .with_code_context(String::from("routine entered here"), span)
+                                );
+                            }
+                        },
+                        _ => ()
+                    },
+                    NodeKind::Value { .. } => continue 'blocks,
+                    | NodeKind::Label { .. }
+                    | NodeKind::AnonymousLabel
+                    | NodeKind::Directive { .. }
+                    | NodeKind::Assert { .. }
+                    | NodeKind::Constant { .. } => ()
+                }
+            }
+
+            match self.cfg.call(block) {
+                Some((callee, return_block)) => {
+                    calls.push(callee);
+                    worklist.push((return_block, depth - 1));
+                },
+                None => worklist.extend(self.cfg.blocks[block].successors.iter()
+                    .map(|(_, successor)| (*successor, depth))
+                )
+            }
+        }
+
+        calls
+    }
+}
+
+/// Warns about paths that pop an empty stack and routines that reach `ret`
+/// with the wrong stack depth.
+pub fn check_stack(cfg: &ControlFlowGraph) -> Vec<Message> {
+    let mut checker = StackChecker {
+        cfg,
+        warnings: vec![],
+        reported: HashSet::new()
+    };
+
+    if cfg.blocks.is_empty() {
+        return vec![];
+    }
+
+    let mut routines = checker.check(0, 0, None);
+    let mut checked = HashSet::new();
+
+    while let Some(routine) = routines.pop() {
+        if checked.insert(routine) {
+            routines.extend(checker.check(routine, 1, Some(routine)));
+        }
+    }
+
+    checker.warnings
+}
+
+const REGISTERS: [&str; 3] = ["rx", "ry", "rz"];
+
+/// Collects the registers named by `%clobbers` and `%preserves` directives,
+/// keyed by the label each directive precedes.
+pub fn contracts(program: &[Node]) -> Result<HashMap<String, Vec<&'static str>>> {
+    let mut errors = vec![];
+    let mut contracts = HashMap::new();
+    let mut pending: Option<(&Node, Vec<&'static str>)> = None;
+
+    for node in program {
+        match &node.value {
+            NodeKind::Directive { name, arguments } if name.value == "clobbers" || name.value == "preserves" => {
+                let mut registers = vec![];
+
+                for argument in arguments {
+                    match REGISTERS.iter().find(|register| argument.value == TokenKind::Word(register.to_string())) {
+                        Some(register) => registers.push(*register),
+                        None => errors.push(
+                            Message::error(format!("expected register, found '{}'", argument.span.get_text()))
+                                .with_id(Code::InvalidContract)
+                                .with_code(String::from("expected register"), argument.span.clone())
+                                .with_note(String::from("valid registers are: 'rx', 'ry' and 'rz'"))
+                        )
+                    }
+                }
+
+                if name.value == "preserves" {
+                    registers = REGISTERS.into_iter()
+                        .filter(|register| !registers.contains(register))
+                        .collect();
+                }
+
+                pending = Some((node, registers));
+            }
+            NodeKind::Label { name } => if let Some((_, registers)) = pending.take() {
+                contracts.insert(name.value.clone(), registers);
+            },
+            _ => if let Some((directive, _)) = pending.take() {
+                errors.push(
+                    Message::error(String::from("register contracts must be followed by a label"))
+                        .with_id(Code::InvalidContract)
+                        .with_code(String::from("expected label"), node.span.clone())
+                        .with_code_context(String::from("contract declared here"), directive.span.clone())
+                );
+            }
+        }
+    }
+
+    if let Some((directive, _)) = pending {
+        errors.push(
+            Message::error(String::from("register contracts must be followed by a label"))
+                .with_id(Code::InvalidContract)
+                .with_code(String::from("expected label"), directive.span.clone())
+        );
+    }
+
+    if errors.is_empty() {
+        Ok((contracts, vec![]))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Returns the registers an instruction reads and the register it writes.
+fn register_effects(node: &Node) -> (Vec<&str>, Option<&str>) {
+    let NodeKind::Instruction { name, arguments } = &node.value else {
+        return (vec![], None)
+    };
+
+    let registers = arguments.iter().map(|argument| match &argument.value {
+        TokenKind::Word(word) if is_register(word) => Some(word.as_str()),
+        _ => None
+    });
+
+    let overwrites = matches!(name.value.as_str(), "put" | "ldr" | "pop");
+    let modifies = matches!(name.value.as_str(), "inc" | "dec" | "neg" | "add" | "sub" | "and" | "or");
+
+    let reads = registers.clone()
+        .enumerate()
+        .filter(|(i, _)| *i != 0 || !overwrites)
+        .filter_map(|(_, register)| register)
+        .collect();
+    let writes = registers.clone()
+        .next()
+        .flatten()
+        .filter(|_| overwrites || modifies);
+
+    (reads, writes)
+}
+
+/// Registers clobbered by a call, with the routine and the call site.
+type Clobbered = BTreeMap<&'static str, (String, Span)>;
+
+/// Warns when a register is read after a call to a routine whose
+/// `%clobbers` or `%preserves` contract says it doesn't survive the call.
+pub fn check_clobbers(cfg: &ControlFlowGraph) -> Result<()> {
+    let (contracts, _) = contracts(cfg.program)?;
+    let mut warnings = vec![];
+
+    if contracts.is_empty() {
+        return Ok(((), warnings));
+    }
+
+    let mut states: Vec<Clobbered> = vec![BTreeMap::new(); cfg.blocks.len()];
+    let mut worklist: Vec<usize> = (0..cfg.blocks.len()).rev().collect();
+    let mut reported = HashSet::new();
+
+    while let Some(block) = worklist.pop() {
+        let mut state = states[block].clone();
+
+        for i in cfg.blocks[block].nodes.clone() {
+            let node = &cfg.program[i];
+
+            if let NodeKind::Value { .. } = node.value {
+                break;
+            }
+
+            let (reads, writes) = register_effects(node);
+
+            for register in reads {
+                if let Some((routine, call)) = state.remove(register) {
+                    if reported.insert((i, register)) {
+                        warnings.push(
+                            Message::warning(format!("'{}' is read after a call to '{}', which clobbers it", register, routine))
+                                .with_id(Code::ClobberedRegister)
+                                .with_code(String::from("read of clobbered register"), node.span.clone())
+                                .with_code_context(format!("'{}' is clobbered by this call", register), call)
+                        );
+                    }
+                }
+            }
+
+            if let Some(register) = writes {
+                state.remove(register);
+            }
+        }
+
+        let successors = match cfg.call(block) {
+            Some((routine, return_block)) => {
+                // registers outside the contract may be outputs of the
+                // routine, so only the clobbered ones are tracked past it
+                let mut returned = Clobbered::new();
+                let contract = cfg.blocks[routine].labels.iter()
+                    .find_map(|label| contracts.get(label).map(|registers| (label, registers)));
+
+                if let Some((label, registers)) = contract {
+                    let call = cfg.last_node(block).expect("calls end with 'jmp'").span.clone();
+
+                    for register in registers {
+                        returned.insert(register, (label.clone(), call.clone()));
+                    }
+                }
+
+                vec![(return_block, returned)]
+            },
+            None => cfg.blocks[block].successors.iter()
+                .map(|(_, successor)| (*successor, state.clone()))
+                .collect()
+        };
+
+        for (successor, state) in successors {
+            let mut changed = false;
+
+            for (register, clobber) in state {
+                if let Entry::Vacant(entry) = states[successor].entry(register) {
+                    entry.insert(clobber);
+                    changed = true;
+                }
+            }
+
+            if changed && !worklist.contains(&successor) {
+                worklist.push(successor);
+            }
+        }
+    }
+
+    Ok(((), warnings))
+}
+
+enum Reachability<'a> {
+    /// execution can reach this point, last passing through this node
+    Live(Option<&'a Node>),
+    /// execution can't fall through to this point after the terminator
+    Dead { terminator: &'a Node, reported: bool }
+}
+
+/// Returns the return address pushed by the call `psh <return>`,
+/// `jmp <routine>`, `<return>:` starting at `program[index]`. Other labels
+/// pushed are data, not jump targets.
+fn return_label(program: &[Node], index: usize) -> Option<&String> {
+    let NodeKind::Instruction { name, arguments } = &program[index].value else {
+        return None;
+    };
+    if name.value != "psh" {
+        return None;
+    }
+
+    let label = arguments.first().and_then(label_argument)?;
+    let mut rest = program[index + 1..].iter()
+        .filter(|node| !matches!(node.value, NodeKind::Directive { .. } | NodeKind::Assert { .. } | NodeKind::Constant { .. }))
+        .map(|node| &node.value);
+
+    match (rest.next(), rest.next()) {
+        (Some(NodeKind::Instruction { name, .. }), Some(NodeKind::Label { name: next })) if name.value == "jmp" && &next.value == label => Some(label),
+        _ => None
+    }
+}
+
+/// Warns about instructions after an unconditional `jmp`, `ret` or `hlt`
+/// that have no label, and about execution falling into data.
+pub fn check_reachability(program: &[Node]) -> Vec<Message> {
+    let mut warnings = vec![];
+
+    let targets: HashSet<&String> = program.iter().enumerate()
+        .filter_map(|(i, node)| match &node.value {
+            NodeKind::Instruction { name, arguments } if matches!(name.value.as_str(), "jmp" | "jpz" | "jpn") => arguments.first()
+                .and_then(label_argument),
+            _ => return_label(program, i)
+        })
+        .collect();
+
+    let mut state = Reachability::Live(None);
+
+    for node in program {
+        match &node.value {
+            NodeKind::Label { name } => if targets.contains(&name.value) {
+                state = Reachability::Live(Some(node));
+            } else if let Reachability::Dead { reported, .. } = &mut state {
+                *reported = true;
+            },
+            // anonymous labels only exist to be jumped to
+            NodeKind::AnonymousLabel => state = Reachability::Live(Some(node)),
+            NodeKind::Instruction { name, .. } => {
+                if let Reachability::Dead { terminator, reported: false } = state {
+                    warnings.push(
+                        Message::warning(String::from("unreachable code"))
+                            .with_id(Code::UnreachableCode)
+                            .with_code(String::from("never executed"), node.span.clone())
+                            .with_code_context(String::from("any code following this is unreachable"), terminator.span.clone())
+                            .with_note(String::from("add a label if this code is the target of a computed jump"))
+                    );
+                }
+
+                state = match name.value.as_str() {
+                    "jmp" | "ret" | "hlt" => Reachability::Dead { terminator: node, reported: false },
+                    _ => match state {
+                        Reachability::Live(_) => Reachability::Live(Some(node)),
+                        Reachability::Dead { terminator, .. } => Reachability::Dead { terminator, reported: true }
+                    }
+                };
+            }
+            NodeKind::Value { .. } => if let Reachability::Live(previous) = state {
+                let mut message = Message::warning(String::from("execution falls into data"))
+                    .with_id(Code::FallIntoData)
+                    .with_code(String::from("executed as an instruction"), node.span.clone());
+
+                if let Some(previous) = previous {
+                    message = message.with_code_context(
+                        match previous.value {
+                            NodeKind::Label { .. } | NodeKind::AnonymousLabel => String::from("jumped to here"),
+                            _ => String::from("execution continues past this")
+                        },
+                        previous.span.clone()
+                    );
+                }
+
+                warnings.push(message.with_note(String::from("end the preceding code with 'jmp', 'ret' or 'hlt'")));
+
+                state = Reachability::Dead { terminator: node, reported: true };
+            },
+            NodeKind::Directive { .. } | NodeKind::Assert { .. } | NodeKind::Constant { .. } => ()
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{code::Code, compiler::{build, Options}, source::Source};
+
+    /// Assembles `text` and returns the codes of its warnings.
+    fn warnings(text: &str, options: &Options) -> Vec<Code> {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+
+        match build(source, options) {
+            Ok((_, warnings)) => warnings.into_iter().filter_map(|warning| warning.code).collect(),
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+    }
+
+    fn stack_warnings(text: &str) -> Vec<Code> {
+        warnings(text, &Options { check_stack: true, ..Options::default() })
+    }
+
+    const CALL: &str = "\
+ psh back
+ jmp routine
+back:
+ hlt
+routine:
+";
+
+    #[test]
+    fn balanced_routines_pass() {
+        assert!(stack_warnings(&format!("{CALL} psh rx\n pop rx\n ret\n")).is_empty());
+    }
+
+    #[test]
+    fn warns_about_unbalanced_returns() {
+        assert!(matches!(stack_warnings(&format!("{CALL} psh rx\n ret\n"))[..], [Code::UnbalancedReturn]));
+    }
+
+    #[test]
+    fn warns_about_underflow() {
+        assert!(matches!(stack_warnings(" pop rx\n hlt\n")[..], [Code::StackUnderflow]));
+        // popping the return address leaves nothing for the second pop and the ret
+        assert!(matches!(stack_warnings(&format!("{CALL} pop rx\n pop ry\n ret\n"))[..], [Code::StackUnderflow, Code::StackUnderflow]));
+    }
+
+    #[test]
+    fn warns_about_path_dependent_depth() {
+        assert!(matches!(stack_warnings(" jpz skip, rx\n psh rx\nskip:\n hlt\n")[..], [Code::InconsistentStackDepth]));
+    }
+
+    #[test]
+    fn stack_is_only_checked_when_asked() {
+        assert!(warnings(" pop rx\n hlt\n", &Options::default()).is_empty());
+    }
+
+    fn errors(text: &str) -> Vec<Code> {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+
+        match build(source, &Options::default()) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => errors.into_iter().filter_map(|error| error.code).collect()
+        }
+    }
+
+    /// Calls `routine`, which follows `contract`, then runs `after`.
+    fn call(contract: &str, after: &str) -> String {
+        format!(" psh back\n jmp routine\nback:\n{after}\n hlt\n{contract}\nroutine:\n put ry, 1\n put rz, 1\n ret\n")
+    }
+
+    #[test]
+    fn warns_about_reading_clobbered_registers() {
+        let options = Options::default();
+
+        assert!(matches!(warnings(&call("%clobbers ry", " add rx, ry"), &options)[..], [Code::ClobberedRegister]));
+        assert!(matches!(warnings(&call("%preserves rx", " add rz, rx"), &options)[..], [Code::ClobberedRegister]));
+    }
+
+    #[test]
+    fn allows_preserved_and_overwritten_registers() {
+        let options = Options::default();
+
+        assert!(warnings(&call("%clobbers ry", " add rz, rx"), &options).is_empty());
+        assert!(warnings(&call("%clobbers ry", " put ry, 2\n add rx, ry"), &options).is_empty());
+        assert!(warnings(&call("%preserves rx", " psh rx\n pop rx"), &options).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_contracts() {
+        assert!(matches!(errors("%clobbers rw\nroutine:\n ret\n")[..], [Code::InvalidContract]));
+        assert!(matches!(errors("%clobbers rx\n ret\n")[..], [Code::InvalidContract]));
+        assert!(matches!(errors(" ret\n%preserves rx\n")[..], [Code::InvalidContract]));
+    }
+
+    #[test]
+    fn warns_about_unreachable_code_once() {
+        let options = Options::default();
+
+        assert!(matches!(warnings(" hlt\n put rx, 1\n put rx, 2\n", &options)[..], [Code::UnreachableCode]));
+        assert!(matches!(warnings(" jmp end\n inc rx\nend:\n hlt\n", &options)[..], [Code::UnreachableCode]));
+        assert!(warnings(" put rx, 1\n jpz end, rx\n inc rx\nend:\n hlt\n", &options).is_empty());
+    }
+
+    #[test]
+    fn labels_end_unreachable_code() {
+        let options = Options::default();
+
+        assert!(warnings(" psh resume\n pop rx\n hlt\nresume:\n put rx, 1\n hlt\n", &options).is_empty());
+        assert!(warnings(" hlt\n:\n inc rx\n jmp :-\n", &options).is_empty());
+    }
+
+    #[test]
+    fn warns_about_falling_into_data() {
+        let options = Options::default();
+
+        assert!(matches!(warnings(" put rx, table\ntable:\n 5\n 6\n", &options)[..], [Code::FallIntoData]));
+        assert!(warnings(" put rx, table\n hlt\ntable:\n 5\n 6\n", &options).is_empty());
+    }
+
+    #[test]
+    fn only_return_labels_are_jump_targets() {
+        let options = Options::default();
+
+        // pushing the address of data doesn't make it reachable
+        assert!(warnings(" psh table\n pop rx\n hlt\ntable:\n 5\n", &options).is_empty());
+        // but the label a call returns to is
+        assert!(matches!(
+            warnings(" psh back\n jmp routine\nback:\n 5\nroutine:\n ret\n", &options)[..],
+            [Code::FallIntoData]
+        ));
+    }
+}
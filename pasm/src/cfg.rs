@@ -0,0 +1,365 @@
+use std::{collections::HashMap, ops::Range, io::{self, Write}};
+
+use crate::{parser::{Node, NodeKind}, lexer::{Token, TokenKind, Direction}, message::human_count};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Branch
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// control continues through the block's successors
+    Continue,
+    /// `jmp` through a register or to an address that isn't a label
+    Indirect,
+    Return,
+    Halt
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub labels: Vec<String>,
+    pub nodes: Range<usize>,
+    pub successors: Vec<(EdgeKind, usize)>,
+    pub exit: Exit
+}
+
+/// Basic blocks of a parsed program, split at labels and after every
+/// `jmp`, `jpz`, `jpn`, `ret` and `hlt`.
+#[derive(Debug)]
+pub struct ControlFlowGraph<'a> {
+    pub program: &'a [Node],
+    pub blocks: Vec<Block>,
+    pub labels: HashMap<String, usize>,
+    /// anonymous labels, as their node and the block they begin
+    pub anonymous: Vec<(usize, usize)>
+}
+
+pub fn is_register(word: &str) -> bool {
+    matches!(word, "rx" | "ry" | "rz")
+}
+
+/// Returns the label named by `token`, if it names one.
+pub fn label_argument(token: &Token) -> Option<&String> {
+    match &token.value {
+        TokenKind::Word(word) if !is_register(word) => Some(word),
+        _ => None
+    }
+}
+
+/// Returns whether `node` takes up space in the image.
+pub fn is_code(node: &Node) -> bool {
+    matches!(node.value, NodeKind::Instruction { .. } | NodeKind::Value { .. })
+}
+
+fn is_terminator(name: &str) -> bool {
+    matches!(name, "jmp" | "jpz" | "jpn" | "ret" | "hlt")
+}
+
+impl<'a> ControlFlowGraph<'a> {
+    pub fn new(program: &'a [Node]) -> Self {
+        let mut out = Self {
+            program,
+            blocks: vec![],
+            labels: HashMap::new(),
+            anonymous: vec![]
+        };
+
+        let mut begin = 0;
+        let mut labels = vec![];
+        let mut has_body = false;
+
+        for (i, node) in program.iter().enumerate() {
+            match &node.value {
+                NodeKind::Label { name } => {
+                    if has_body {
+                        out.push_block(std::mem::take(&mut labels), begin..i);
+                        begin = i;
+                        has_body = false;
+                    }
+
+                    out.labels.insert(name.value.clone(), out.blocks.len());
+                    labels.push(name.value.clone());
+                }
+                NodeKind::AnonymousLabel => {
+                    if has_body {
+                        out.push_block(std::mem::take(&mut labels), begin..i);
+                        begin = i;
+                        has_body = false;
+                    }
+
+                    out.anonymous.push((i, out.blocks.len()));
+                }
+                NodeKind::Instruction { name, .. } if is_terminator(&name.value) => {
+                    out.push_block(std::mem::take(&mut labels), begin..i + 1);
+                    begin = i + 1;
+                    has_body = false;
+                }
+                NodeKind::Directive { .. } | NodeKind::Assert { .. } | NodeKind::Constant { .. } => (),
+                _ => has_body = true
+            }
+        }
+
+        if has_body || !labels.is_empty() || out.anonymous.last().is_some_and(|(_, block)| *block == out.blocks.len()) {
+            out.push_block(labels, begin..program.len());
+        }
+
+        for i in 0..out.blocks.len() {
+            let (successors, exit) = out.successors(i);
+            out.blocks[i].successors = successors;
+            out.blocks[i].exit = exit;
+        }
+
+        out
+    }
+
+    fn push_block(&mut self, labels: Vec<String>, nodes: Range<usize>) {
+        self.blocks.push(Block {
+            labels,
+            nodes,
+            successors: vec![],
+            exit: Exit::Continue
+        });
+    }
+
+    /// Returns the last instruction or value of `block`.
+    pub fn last_node(&self, block: usize) -> Option<&'a Node> {
+        self.last_index(block).map(|i| &self.program[i])
+    }
+
+    fn last_index(&self, block: usize) -> Option<usize> {
+        self.blocks[block].nodes.clone()
+            .rev()
+            .find(|i| is_code(&self.program[*i]))
+    }
+
+    /// Returns the block `token`, an argument of the node at `index`, jumps to.
+    fn target(&self, index: usize, token: &Token) -> Option<usize> {
+        match &token.value {
+            TokenKind::AnonymousLabel(Direction::Backward) => self.anonymous.iter()
+                .rev()
+                .find(|(node, _)| *node < index)
+                .map(|(_, block)| *block),
+            TokenKind::AnonymousLabel(Direction::Forward) => self.anonymous.iter()
+                .find(|(node, _)| *node > index)
+                .map(|(_, block)| *block),
+            _ => label_argument(token)
+                .and_then(|label| self.labels.get(label))
+                .copied()
+        }
+    }
+
+    fn successors(&self, block: usize) -> (Vec<(EdgeKind, usize)>, Exit) {
+        let next = Some(block + 1).filter(|next| *next < self.blocks.len());
+        let fallthrough = next.map(|next| (EdgeKind::Fallthrough, next));
+        let index = self.last_index(block);
+        let target = |arguments: &Vec<Token>| arguments.first()
+            .and_then(|argument| self.target(index?, argument));
+
+        match self.last_node(block).map(|node| &node.value) {
+            Some(NodeKind::Instruction { name, arguments }) => match name.value.as_str() {
+                "jmp" => match target(arguments) {
+                    Some(target) => (vec![(EdgeKind::Jump, target)], Exit::Continue),
+                    None => (vec![], Exit::Indirect)
+                },
+                "jpz" | "jpn" => (
+                    target(arguments).map(|target| (EdgeKind::Branch, target)).into_iter()
+                        .chain(fallthrough)
+                        .collect(),
+                    Exit::Continue
+                ),
+                "ret" => (vec![], Exit::Return),
+                "hlt" => (vec![], Exit::Halt),
+                _ => (fallthrough.into_iter().collect(), Exit::Continue)
+            },
+            _ => (fallthrough.into_iter().collect(), Exit::Continue)
+        }
+    }
+
+    /// Recognizes the calling convention `psh <return>` followed by
+    /// `jmp <routine>` at the end of `block`, returning the blocks of the
+    /// routine and of the return address.
+    pub fn call(&self, block: usize) -> Option<(usize, usize)> {
+        let mut instructions = self.blocks[block].nodes.clone()
+            .rev()
+            .filter(|i| is_code(&self.program[*i]));
+
+        let resolve = |index: Option<usize>, instruction: &str| match &self.program[index?].value {
+            NodeKind::Instruction { name, arguments } if name.value == instruction => arguments.first()
+                .and_then(|argument| self.target(index?, argument)),
+            _ => None
+        };
+
+        let routine = resolve(instructions.next(), "jmp")?;
+        let return_block = resolve(instructions.next(), "psh")?;
+
+        Some((routine, return_block))
+    }
+}
+
+/// Renders an instruction argument as it would be written, so that code
+/// expanded from macros shows its expansion rather than the invocation.
+fn argument_text(token: &Token) -> String {
+    match &token.value {
+        TokenKind::Word(word) => word.clone(),
+        TokenKind::Number(number) => number.to_string(),
+        TokenKind::Character(byte) if byte.is_ascii_graphic() || *byte == b' ' => format!("'{}'", *byte as char),
+        TokenKind::Character(byte) => byte.to_string(),
+        TokenKind::String(string) => format!("{:?}", string),
+        TokenKind::Location(0) => String::from("$"),
+        TokenKind::Location(offset) => format!("${:+}", offset),
+        TokenKind::AnonymousLabel(Direction::Backward) => String::from(":-"),
+        TokenKind::AnonymousLabel(Direction::Forward) => String::from(":+"),
+        _ => token.span.get_text().to_string()
+    }
+}
+
+fn instruction_text(node: &Node) -> String {
+    match &node.value {
+        NodeKind::Instruction { name, arguments } if arguments.is_empty() => name.value.clone(),
+        NodeKind::Instruction { name, arguments } => format!(
+            "{} {}", name.value, arguments.iter().map(argument_text).collect::<Vec<_>>().join(", ")
+        ),
+        _ => node.span.get_text().to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl ControlFlowGraph<'_> {
+    fn block_name(&self, block: usize) -> String {
+        match self.blocks[block].labels.is_empty() {
+            false => self.blocks[block].labels.join(", "),
+            true => {
+                let span = &self.program[self.blocks[block].nodes.start].span;
+                format!("{}:{}", span.source.path.display(), span.row_num())
+            }
+        }
+    }
+
+    /// Writes the graph in the Graphviz DOT format, one node per block.
+    pub fn write_dot<T: Write>(&self, mut out: T) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "  node [shape=box, fontname=monospace];")?;
+
+        let mut exits = (false, false);
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut lines = vec![];
+            let mut data = 0;
+
+            for node in &self.program[block.nodes.clone()] {
+                match &node.value {
+                    NodeKind::Value { .. } => data += 1,
+                    NodeKind::Instruction { .. } => lines.push(escape(&instruction_text(node))),
+                    _ => ()
+                }
+            }
+            if data > 0 {
+                lines.push(human_count("byte", data) + " of data");
+            }
+
+            writeln!(out, "  b{} [label=\"{}:\\l{}\"{}];",
+                i, escape(&self.block_name(i)),
+                lines.iter().map(|line| format!("  {}\\l", line)).collect::<String>(),
+                if data > 0 { ", style=filled, fillcolor=lightgrey" } else { "" }
+            )?;
+
+            let call = self.call(i).map(|(routine, _)| routine);
+
+            for (kind, successor) in &block.successors {
+                let attributes = match (kind, self.last_node(i).map(|node| &node.value)) {
+                    (EdgeKind::Jump, _) if call == Some(*successor) => String::from("label=\"call\""),
+                    (EdgeKind::Jump, _) => String::from("label=\"jmp\""),
+                    (EdgeKind::Branch, Some(NodeKind::Instruction { name, arguments })) => format!(
+                        "label=\"{} {}\", color=darkgreen",
+                        &name.value, arguments.get(1).map(|register| escape(&argument_text(register))).unwrap_or_default()
+                    ),
+                    (EdgeKind::Branch, _) => String::from("color=darkgreen"),
+                    (EdgeKind::Fallthrough, _) => String::from("style=dashed")
+                };
+
+                writeln!(out, "  b{} -> b{} [{}];", i, successor, attributes)?;
+            }
+
+            match block.exit {
+                Exit::Indirect => {
+                    let jump = self.last_node(i).map(|node| escape(&instruction_text(node))).unwrap_or_default();
+                    writeln!(out, "  b{} -> indirect [label=\"{}\", style=dotted];", i, jump)?;
+                    exits.0 = true;
+                },
+                Exit::Return => {
+                    writeln!(out, "  b{} -> return [style=dotted];", i)?;
+                    exits.1 = true;
+                },
+                Exit::Halt | Exit::Continue => ()
+            }
+        }
+
+        if exits.0 {
+            writeln!(out, "  indirect [label=\"indirect jump\", shape=ellipse];")?;
+        }
+        if exits.1 {
+            writeln!(out, "  return [label=\"ret\", shape=ellipse];")?;
+        }
+
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{compiler::{preprocess, Options}, parser::Parser, source::Source};
+
+    fn dot(text: &str) -> String {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+        let (scope, _) = preprocess(source, &Options::default()).expect("source should preprocess");
+        let (program, _) = Parser::new(scope.tokens).parse().expect("source should parse");
+
+        let mut out = vec![];
+        ControlFlowGraph::new(&program).write_dot(&mut out).expect("writing to a vector shouldn't fail");
+
+        String::from_utf8(out).expect("DOT output should be UTF-8")
+    }
+
+    #[test]
+    fn writes_blocks_and_edges() {
+        let dot = dot("start:\n put rx, 3\nloop:\n dec rx\n jpz done, rx\n jmp loop\ndone:\n hlt\n");
+
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("  b0 [label=\"start:\\l  put rx, 3\\l\"];\n"));
+        assert!(dot.contains("  b1 [label=\"loop:\\l  dec rx\\l  jpz done, rx\\l\"];\n"));
+        assert!(dot.contains("  b0 -> b1 [style=dashed];\n"));
+        assert!(dot.contains("  b1 -> b3 [label=\"jpz rx\", color=darkgreen];\n"));
+        assert!(dot.contains("  b2 -> b1 [label=\"jmp\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn renders_macro_expansions() {
+        let dot = dot("%macro clear r\n put r, 0\n%end\n clear ry\n hlt\n");
+
+        assert!(dot.contains("\\l  put ry, 0\\l  hlt\\l\"];\n"), "{}", dot);
+        assert!(!dot.contains("clear"), "{}", dot);
+    }
+
+    #[test]
+    fn marks_data_calls_and_exits() {
+        let dot = dot(" psh back\n jmp routine\nback:\n jmp ry\nroutine:\n ret\ntable:\n 1\n 2\n");
+
+        assert!(dot.contains("label=\"call\""), "{}", dot);
+        assert!(dot.contains(" -> indirect [label=\"jmp ry\", style=dotted];\n"), "{}", dot);
+        assert!(dot.contains(" -> return [style=dotted];\n"), "{}", dot);
+        assert!(dot.contains("table:\\l  2 bytes of data\\l\", style=filled, fillcolor=lightgrey];\n"), "{}", dot);
+        assert!(dot.contains("  indirect [label=\"indirect jump\", shape=ellipse];\n"));
+        assert!(dot.contains("  return [label=\"ret\", shape=ellipse];\n"));
+    }
+}
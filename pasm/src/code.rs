@@ -0,0 +1,629 @@
+use std::fmt::Display;
+
+/// Stable identifiers of diagnostics, printed as `P0001` and explained by
+/// `pasm --explain`. New codes are only ever appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    IllegalCharacter = 1,
+    InvalidNumber,
+    NumberOutOfRange,
+    InvalidCharacterLiteral,
+    UnterminatedString,
+    UnknownDirective,
+    MalformedDirective,
+    FileNotFound,
+    InvalidImage,
+    RangeOutOfBounds,
+    InvalidSyntax,
+    UndeclaredLabel,
+    LabelRedefinition,
+    UnknownInstruction,
+    WrongArgumentCount,
+    WrongArgument,
+    UnsupportedValue,
+    ImageOverflow,
+    InvalidContract,
+    OutputError,
+    UnusedLabel,
+    UnreachableCode,
+    FallIntoData,
+    StackUnderflow,
+    UnbalancedReturn,
+    InconsistentStackDepth,
+    ClobberedRegister,
+    UnknownLint,
+    MacroRecursionLimit,
+    UserError,
+    UserWarning,
+    AssertionFailed,
+    DivisionByZero,
+    MacroArgumentCount,
+    InvalidPaste,
+    PrivateLabel,
+    CyclicConstant,
+    InvalidControlFlow,
+    InvalidEntryPoint,
+    InvalidEscape,
+    CommandFailed,
+    NothingToDocument
+}
+
+impl Code {
+    pub const ALL: &'static [Code] = &[
+        Code::IllegalCharacter,
+        Code::InvalidNumber,
+        Code::NumberOutOfRange,
+        Code::InvalidCharacterLiteral,
+        Code::UnterminatedString,
+        Code::UnknownDirective,
+        Code::MalformedDirective,
+        Code::FileNotFound,
+        Code::InvalidImage,
+        Code::RangeOutOfBounds,
+        Code::InvalidSyntax,
+        Code::UndeclaredLabel,
+        Code::LabelRedefinition,
+        Code::UnknownInstruction,
+        Code::WrongArgumentCount,
+        Code::WrongArgument,
+        Code::UnsupportedValue,
+        Code::ImageOverflow,
+        Code::InvalidContract,
+        Code::OutputError,
+        Code::UnusedLabel,
+        Code::UnreachableCode,
+        Code::FallIntoData,
+        Code::StackUnderflow,
+        Code::UnbalancedReturn,
+        Code::InconsistentStackDepth,
+        Code::ClobberedRegister,
+        Code::UnknownLint,
+        Code::MacroRecursionLimit,
+        Code::UserError,
+        Code::UserWarning,
+        Code::AssertionFailed,
+        Code::DivisionByZero,
+        Code::MacroArgumentCount,
+        Code::InvalidPaste,
+        Code::PrivateLabel,
+        Code::CyclicConstant,
+        Code::InvalidControlFlow,
+        Code::InvalidEntryPoint,
+        Code::InvalidEscape,
+        Code::CommandFailed,
+        Code::NothingToDocument
+    ];
+
+    /// Looks up a code by its printed form, e.g. `P0012`.
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::ALL.iter()
+            .find(|code| code.to_string().eq_ignore_ascii_case(id))
+            .copied()
+    }
+
+    /// Returns the name of the lint controlling this warning, if any.
+    pub fn lint(&self) -> Option<&'static str> {
+        match self {
+            Code::UnusedLabel => Some("unused_label"),
+            Code::UnreachableCode => Some("unreachable_code"),
+            Code::FallIntoData => Some("fall_into_data"),
+            Code::StackUnderflow => Some("stack_underflow"),
+            Code::UnbalancedReturn => Some("unbalanced_return"),
+            Code::InconsistentStackDepth => Some("inconsistent_stack_depth"),
+            Code::ClobberedRegister => Some("clobbered_register"),
+            _ => None
+        }
+    }
+
+    pub fn from_lint(name: &str) -> Option<Self> {
+        Self::ALL.iter()
+            .find(|code| code.lint() == Some(name))
+            .copied()
+    }
+
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            Code::IllegalCharacter => "\
+A character that can't begin any token was found in the source.
+
+Erroneous code example:
+
+    put rx,@5
+
+Only letters, digits, quotes and the punctuation of pasm's syntax are
+valid outside of comments, characters and strings. Comments start with ';'.",
+            Code::InvalidNumber => "\
+A number literal is malformed.
+
+Erroneous code example:
+
+    put rx,0b102
+    put ry,0z10
+
+Numbers may use the radixes '0b' (binary), '0o' or '0q' (octal), '0d'
+(decimal) and '0x' or '0h' (hexadecimal), and must only contain digits of
+that radix. '_' can be used as a separator.",
+            Code::NumberOutOfRange => "\
+A number literal doesn't fit in a byte.
+
+Erroneous code example:
+
+    put rx,256
+
+POC-8 is an 8-bit architecture, so every value must be between 0 and 255.
+This includes the offset and length given to '%incbin', so only the first
+510 bytes of a file can be sliced; include larger files whole.",
+            Code::InvalidCharacterLiteral => "\
+A character literal is malformed or doesn't fit in a byte.
+
+Erroneous code example:
+
+    put rx,'ř'
+
+Character literals must contain exactly one character whose code is below
+256, e.g. 'a' or '\\n'.",
+            Code::UnterminatedString => "\
+A string literal is missing its closing '\"'.
+
+Erroneous code example:
+
+    %include \"std/printu.pasm
+
+Close the string with '\"' before the end of the file.",
+            Code::UnknownDirective => "\
+A '%' is followed by a name that isn't a known directive.
+
+Erroneous code example:
+
+    %inlcude \"std/printu.pasm\"
+
+The available directives are '%include', '%incbin', '%incimage', '%define',
+'%ifndef', '%end', '%clobbers', '%preserves', '%allow', '%error', '%warning',
+'%assert', '%macro', '%module', '%export', '%struct' and '%enum'.",
+            Code::MalformedDirective => "\
+A directive is missing an argument, has an argument of the wrong kind, or
+isn't followed by a new line.
+
+Erroneous code example:
+
+    %define 5 STACK_CAP
+    %include std/printu.pasm
+
+'%define' and '%ifndef' take a symbol, '%include' takes a string, and every
+directive must end its line.",
+            Code::FileNotFound => "\
+A file named by '%include', '%incbin' or '%incimage' doesn't exist.
+
+Erroneous code example:
+
+    %include \"std/printx.pasm\"
+
+Paths are resolved relative to the directory of the file containing the
+directive.",
+            Code::InvalidImage => "\
+An image given to '%incimage' couldn't be decoded, or the conversion mode
+is unknown.
+
+Erroneous code example:
+
+    %incimage \"sprite.png\", invert
+
+The valid modes are 'threshold', which emits one byte per pixel (1 for
+light pixels, 0 for dark ones), and 'pack', which packs eight pixels into a
+byte, most significant bit first.",
+            Code::RangeOutOfBounds => "\
+The offset and length given to '%incbin' reach past the end of the file.
+
+Erroneous code example:
+
+    %incbin \"font.bin\", 250, 20 ; font.bin is only 256 bytes long
+
+Make sure that offset + length is at most the size of the file. The offset
+and length are bytes, so each is at most 255.",
+            Code::InvalidSyntax => "\
+A token appeared where a statement or an instruction argument was expected.
+
+Erroneous code example:
+
+    put rx,,5
+    , hlt
+
+A statement is a label ('name:'), an instruction with comma separated
+arguments, or a value.",
+            Code::UndeclaredLabel => "\
+A label was used but never declared.
+
+Erroneous code example:
+
+    loop:
+     inc rx
+     jmp lop
+
+Check the spelling of the label, or declare it with 'name:'. If the name
+is meant to be a constant, declare it with 'NAME = value', define it with
+'%define' or on the command line with '-D NAME=VALUE'. ':-' and ':+' refer
+to the nearest anonymous label, a ':' on its own, before or after them.
+'Name.member' and 'sizeof(Name)' are declared by '%struct' and '%enum'.",
+            Code::LabelRedefinition => "\
+The same label or constant was declared more than once.
+
+Erroneous code example:
+
+    end:
+     hlt
+    end = 0xff
+
+Labels name a single address and constants a single value, so each must be
+unique. Names of included files share the same namespace, unless they're
+declared in a '%module' block without being exported.",
+            Code::UnknownInstruction => "\
+An instruction mnemonic doesn't exist.
+
+Erroneous code example:
+
+    mov rx,ry
+
+Use one of the POC-8 instructions, e.g. 'put rx,ry' to copy a register.",
+            Code::WrongArgumentCount => "\
+An instruction was given the wrong number of arguments.
+
+Erroneous code example:
+
+    sub rx
+
+The note lists every valid signature of the instruction.",
+            Code::WrongArgument => "\
+An instruction doesn't accept this combination of registers and immediates.
+
+Erroneous code example:
+
+    put rx,rx
+
+Not every instruction is encoded for every register. The note lists every
+valid signature of the instruction.",
+            Code::UnsupportedValue => "\
+A string was used where a single byte is expected.
+
+Erroneous code example:
+
+    put rx,\"a\"
+
+Use a character literal ('a') for single characters.",
+            Code::ImageOverflow => "\
+The assembled program is larger than the image size given with
+'--image-size'.
+
+Shorten the program or use a larger image.",
+            Code::InvalidContract => "\
+A '%clobbers' or '%preserves' contract is malformed.
+
+Erroneous code example:
+
+    %clobbers rx,rq
+     ret
+
+Contracts take a comma separated list of registers and must be followed by
+the label of the routine they describe:
+
+    %clobbers rx,ry
+    printu:",
+            Code::OutputError => "\
+A file written by pasm couldn't be created.
+
+Check that the directory exists and that you may write to it.",
+            Code::UnusedLabel => "\
+A label is declared but never used.
+
+Example:
+
+    unused:
+     hlt
+
+Remove the label, or use it.",
+            Code::UnreachableCode => "\
+An instruction follows an unconditional 'jmp', 'ret' or 'hlt' and has no
+label, so no execution can reach it.
+
+Example:
+
+     jmp loop
+     inc rx    ; never executed
+
+Remove the code, or label it if it's the target of a computed jump.",
+            Code::FallIntoData => "\
+Execution can continue from instructions straight into data, which would
+be executed as instructions.
+
+Example:
+
+    end:
+    board:
+     0 0 1 0
+
+Make sure code before data ends with 'jmp', 'ret' or 'hlt':
+
+    end:
+     hlt
+    board:
+     0 0 1 0",
+            Code::StackUnderflow => "\
+A 'pop' or 'ret' can execute while the stack is empty, or while only a
+routine's return address is left on it ('--check-stack' only).
+
+Example:
+
+    routine:
+     pop rx    ; pops the return address
+     ret
+
+Make sure every 'pop' is matched by a 'psh' on every path to it.",
+            Code::UnbalancedReturn => "\
+A routine reaches 'ret' with values it pushed still on the stack, so it
+returns to one of them instead of its caller ('--check-stack' only).
+
+Example:
+
+    routine:
+     psh rx
+     ret
+
+Pop everything a routine pushes before returning.",
+            Code::InconsistentStackDepth => "\
+A label is reached with a different number of values on the stack depending
+on the path taken ('--check-stack' only).
+
+Example:
+
+     jpz skip,rx
+     psh ry
+    skip:
+     ret
+
+Balance the stack on every path into the label.",
+            Code::ClobberedRegister => "\
+A register is read after a call to a routine whose '%clobbers' or
+'%preserves' contract says it doesn't survive the call.
+
+Example:
+
+     put ry,3
+     psh ret_1
+     jmp printu ret_1:   ; printu clobbers rx, ry and rz
+     put rx,ry
+
+Save the register on the stack around the call, or write it again first.",
+            Code::UnknownLint => "\
+A lint name given to '--allow', '--warn', '--deny' or '%allow' doesn't
+exist.
+
+Erroneous code example:
+
+    %allow(unused_labels)
+
+The lints are 'unused_label', 'unreachable_code', 'fall_into_data',
+'stack_underflow', 'unbalanced_return', 'inconsistent_stack_depth' and
+'clobbered_register'. 'warnings' names all of them on the command line.",
+            Code::MacroRecursionLimit => "\
+A macro expanded within other macros more deeply than allowed, usually
+because it expands to itself.
+
+Erroneous code example:
+
+    %define A inc A
+     A
+
+Every expansion of 'A' produces another 'A'. The depth limit is 64 by
+default and can be changed with '--macro-depth'.",
+            Code::UserError => "\
+The source rejected its configuration with '%error'.
+
+Example:
+
+    %ifndef STACK_CAP
+    %error \"STACK_CAP must be defined\"
+    %end
+
+Read the message for what the source expects, e.g. a symbol defined with
+'-D'.",
+            Code::UserWarning => "\
+The source warns about its configuration with '%warning'.
+
+Example:
+
+    %ifndef STACK_CAP
+    %warning \"STACK_CAP isn't defined, defaulting to 5\"
+    %define STACK_CAP 5
+    %end",
+            Code::AssertionFailed => "\
+The expression of an '%assert' evaluated to 0.
+
+Erroneous code example:
+
+    table:
+     1 2 3 4
+    table_end:
+    %assert table_end - table == 5, \"table must have 5 entries\"
+
+Assertions are checked once every label has its address, so they can
+compare labels, numbers and symbols with the operators of C, e.g.
+'table >> 4 == table_end >> 4'.",
+            Code::DivisionByZero => "\
+An expression divides by zero.
+
+Erroneous code example:
+
+    %assert SIZE / (COUNT - 4), \"...\" ; COUNT is 4
+
+Make sure the right side of '/' can't be 0.",
+            Code::MacroArgumentCount => "\
+A macro was invoked with a different number of arguments than it has
+parameters.
+
+Erroneous code example:
+
+    %macro call routine
+     psh ret_##\\@
+     jmp routine ret_##\\@:
+    %end
+
+     call printu, udivmod
+
+Arguments are separated by commas, except for commas in parentheses.",
+            Code::InvalidPaste => "\
+'#', '##' or '\\@' was used in a way that doesn't produce a valid token.
+
+Erroneous code example:
+
+    %define BAD ret_ ## ,
+    %macro name_of value
+     #5
+    %end
+
+'a ## b' must join two tokens into a single valid token, e.g. 'ret_' and
+'\\@' into 'ret_3'. '#parameter' turns a macro argument into a string. '\\@'
+counts macro expansions; as a value it must fit in a byte.",
+            Code::PrivateLabel => "\
+A label declared in a '%module' block was used outside of the module
+without being exported.
+
+Erroneous code example:
+
+    %module printu
+    %export printu
+    printu:
+     jmp printu_end
+    printu_end:
+     ret
+    %end
+
+     jmp printu_end
+
+Labels of a module are private unless listed in '%export', so different
+modules can use the same names. Exported labels are used by their plain
+names or as 'module::label'; private ones only from inside their module.",
+            Code::CyclicConstant => "\
+A constant's value depends on itself.
+
+Erroneous code example:
+
+    WIDTH = HEIGHT * 2
+    HEIGHT = WIDTH / 2
+
+Constants may refer to labels and to constants declared later, but the
+references must not form a cycle.",
+            Code::InvalidControlFlow => "\
+Structured control flow was malformed or not properly nested.
+
+Erroneous code example:
+
+    .while rx != 0
+     dec rx
+    .else
+     hlt
+    .end
+
+'.if <condition>' and '.while <condition>' open blocks that are closed by
+'.end'. '.else' may follow the body of an '.if', and '.break' leaves the
+innermost '.while'. Conditions compare a register with 0 using '==', '!=',
+'<', '<=', '>' or '>='; '<', '<=', '>' and '>=' use 'jpn', which can only
+test 'rx' and 'ry'.",
+            Code::InvalidEntryPoint => "\
+A pcc program has no 'main' function, or its 'main' takes parameters.
+
+Erroneous code example:
+
+    fn main(n) {
+        return n;
+    }
+
+Execution starts at 'main', which is called without arguments. Declare it
+as 'fn main() { ... }'.",
+            Code::InvalidEscape => "\
+A character or string literal contains an unknown escape sequence.
+
+Erroneous code example:
+
+    put rx,'\\e'
+
+The escapes are '\\n', '\\t', '\\0', '\\\\', '\\'', '\\\"' and '\\xNN', where NN
+are two hexadecimal digits, e.g. '\\x1b'.",
+            Code::CommandFailed => "\
+The command given to '--then' could not be run, or exited with an error.
+
+Erroneous code example:
+
+    pasm game.pasm --watch --then \"pemu game.bin\"
+
+The command is run through the shell after every successful build, so it
+must be found on the PATH and exit with status 0. pasm keeps watching
+either way.",
+            Code::NothingToDocument => "\
+'pasm doc' found no exported routines in the given files, so the reference
+it writes is empty.
+
+Example:
+
+    sum:
+     add rx, ry
+     ret
+
+Only routines exported from a '%module' block are documented:
+
+    %module math
+    %export sum
+    sum:
+     add rx, ry
+     ret
+    %end"
+        }
+    }
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "P{:04}", *self as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_numbered_in_order() {
+        for (i, code) in Code::ALL.iter().enumerate() {
+            assert_eq!(*code as usize, i + 1, "{:?} is out of place", code);
+        }
+    }
+
+    #[test]
+    fn looks_up_printed_codes() {
+        assert_eq!(Code::IllegalCharacter.to_string(), "P0001");
+        assert_eq!(Code::UndeclaredLabel.to_string(), "P0012");
+
+        for code in Code::ALL {
+            assert_eq!(Code::from_id(&code.to_string()), Some(*code));
+        }
+        assert_eq!(Code::from_id("p0012"), Some(Code::UndeclaredLabel));
+        assert_eq!(Code::from_id("P0000"), None);
+        assert_eq!(Code::from_id("12"), None);
+    }
+
+    #[test]
+    fn every_code_is_explained() {
+        for code in Code::ALL {
+            assert!(!code.explanation().trim().is_empty(), "{} has no explanation", code);
+        }
+    }
+
+    #[test]
+    fn lints_map_to_their_codes() {
+        for code in Code::ALL {
+            if let Some(lint) = code.lint() {
+                assert_eq!(Code::from_lint(lint), Some(*code));
+            }
+        }
+        assert_eq!(Code::from_lint("unreachable_code"), Some(Code::UnreachableCode));
+        assert_eq!(Code::from_lint("undeclared_label"), None);
+    }
+}
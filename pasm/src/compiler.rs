@@ -1,9 +1,8 @@
-use std::{io::Write, collections::HashMap, ops::Deref, cell::RefCell, path::PathBuf, fs::File, rc::Rc};
+use std::{collections::{HashMap, HashSet}, ops::Deref, cell::RefCell, path::PathBuf, fs::{self, File}, rc::Rc};
 
-use byteorder::WriteBytesExt;
 use itertools::Itertools;
 
-use crate::{parser::{Node, Parser, NodeKind}, lexer::{Lexer, Token, TokenKind}, message::{Message, Result, human_count}, signature::{self, Argument, INSTRUCTIONS}, source::{WithSpan, IntoWithSpan, Source}, preprocessor::{Preprocessor, Scope}};
+use crate::{parser::{Node, Parser, NodeKind}, lexer::{Lexer, Token, TokenKind, Direction}, message::{Message, Result, human_count}, signature::{self, Argument, INSTRUCTIONS}, source::{WithSpan, IntoWithSpan, Source, Span}, preprocessor::{Preprocessor, Scope, DEFAULT_MAX_DEPTH}, cfg::ControlFlowGraph, analysis, module, control, suggestion::did_you_mean, code::Code, lint::Lints, expression::Expression};
 
 struct UsedMarker<T> {
     value: T,
@@ -31,43 +30,97 @@ impl<T> Deref for UsedMarker<T> {
     }
 }
 
+/// An assembled program, held until compilation has succeeded so a failed
+/// compilation never leaves a partial output file.
+pub struct Image {
+    pub bytes: Vec<u8>,
+    /// zeros appended to fill the image size
+    pub padding: usize
+}
+
+impl Image {
+    pub fn write(&self, options: &Options) -> Result<()> {
+        fs::write(&options.output_path, &self.bytes).map_err(|error| vec![
+            Message::error(format!("could not write output to '{}': {}", options.output_path.display(), error)).with_id(Code::OutputError)
+        ])?;
+
+        let padding = match self.padding {
+            0 => String::new(),
+            padding => format!(" (+ {} padding)", padding)
+        };
+        println!("wrote {}{} into '{}'", human_count("byte", self.bytes.len() - self.padding), padding, options.output_path.display());
+
+        Ok(((), vec![]))
+    }
+}
+
 struct Compiler<'a> {
     program: &'a Vec<Node>,
-    output_path: PathBuf,
-    output: &'a mut dyn Write,
+    output: Vec<u8>,
     cursor: usize,
     symbols: HashMap<String, UsedMarker<WithSpan<u8>>>,
+    /// names of the symbols declared as `NAME = value`
+    constants: HashSet<String>,
+    /// addresses of the anonymous labels referred to by each node
+    anonymous: HashMap<(usize, Direction), u8>,
+    /// index of the node being compiled
+    node: usize,
+    /// address of the node being compiled, the value of `$`
+    location: usize,
+    /// names defined by the preprocessor, for suggestions
+    macros: Vec<String>
 }
 
 impl<'a> Compiler<'a> {
-    fn new(program: &'a Vec<Node>, output: &'a mut dyn Write,output_path: PathBuf) -> Self {
+    fn new(program: &'a Vec<Node>, macros: Vec<String>) -> Self {
         Self {
             program,
-            output,
-            output_path,
+            output: vec![],
             cursor: 0,
             symbols: HashMap::new(),
+            constants: HashSet::new(),
+            anonymous: HashMap::new(),
+            node: 0,
+            location: 0,
+            macros
         }
     }
 
     fn write(&mut self, byte: u8) {
-        self.output.write_u8(byte).unwrap();
+        self.output.push(byte);
         self.cursor += 1;
     }
 
+    fn label(&self, word: &str, span: &Span) -> core::result::Result<u8, Message> {
+        self.symbols.get(word).map(|i| (**i).value).ok_or_else(|| {
+            let message = Message::error(format!("use of undeclared label: '{}'", word))
+                .with_id(Code::UndeclaredLabel)
+                .with_code(String::from("unknown label"), span.clone());
+            let candidates = self.symbols.keys().chain(&self.macros).map(String::as_str);
+
+            match did_you_mean(word, candidates) {
+                Some(note) => message.with_note(note),
+                None => message
+            }
+        })
+    }
+
     fn immediate(&self, token: &Token) -> core::result::Result<u8, Vec<Message>> {
         match &token.value {
             TokenKind::Number(byte) => Ok(*byte),
             TokenKind::Character(byte) => Ok(*byte),
-            TokenKind::Word(word) => {
-                self.symbols.get(word).map(|i| (**i).value).ok_or(vec![
-                    Message::error(format!("use of undeclared label: '{}'", word))
-                        .with_code(String::from("unknown label"), token.span.clone())
-                ])
-            },
+            TokenKind::Word(word) => self.label(word, &token.span).map_err(|error| vec![error]),
+            TokenKind::Location(offset) => u8::try_from(self.location as i64 + *offset as i64).map_err(|_| vec![
+                Message::error(format!("'{}' is {}, which isn't an address", token.span.get_text(), self.location as i64 + *offset as i64))
+                    .with_id(Code::NumberOutOfRange)
+                    .with_code(String::from("out of range"), token.span.clone())
+                    .with_note(format!("'$' is {} here", self.location))
+            ]),
+            TokenKind::AnonymousLabel(direction) => Ok(self.anonymous[&(self.node, *direction)]),
             TokenKind::String(_) => {
                 Err(vec![
                     Message::error(format!("usage of strings as immediate values is currently not supported"))
+                        .with_id(Code::UnsupportedValue)
                         .with_code(String::from("unsupported value"), token.span.clone())
                 ])
             }
@@ -80,11 +133,23 @@ impl<'a> Compiler<'a> {
             NodeKind::Instruction { name, arguments } => {
                 let mut valid_signatures = match INSTRUCTIONS.get(&name.value) {
                     Some(instruction) => instruction,
-                    None => return Err(vec![
-                        Message::error(format!("use of invalid instruction: '{}' does not exist", &name.value))
-                            .with_code(String::from("unknown instruction"), name.span.clone())
-                    ])
+                    None => {
+                        let message = Message::error(format!("use of invalid instruction: '{}' does not exist", &name.value))
+                            .with_id(Code::UnknownInstruction)
+                            .with_code(String::from("unknown instruction"), name.span.clone());
+
+                        return Err(vec![match did_you_mean(&name.value, INSTRUCTIONS.keys().map(String::as_str)) {
+                            Some(note) => message.with_note(note),
+                            None => message
+                        }])
+                    }
                 }.signatures.clone();
+                let signatures_note = format!("valid signatures of '{}' are: {}",
+                    &name.value,
+                    valid_signatures.iter()
+                        .map(|signature| format!("'{}'", signature::format_signature(&name.value, signature)))
+                        .join(", ")
+                );
                 let valid_num_arguments: Vec<usize> = valid_signatures.iter()
                     .map(|signature| signature.arguments.len())
                     .unique()
@@ -110,7 +175,9 @@ impl<'a> Compiler<'a> {
                             },
                             arguments_signature.len()
                         ))
+                            .with_id(Code::WrongArgumentCount)
                             .with_code(String::from("wrong number of arguments"), name.span.clone())
+                            .with_note(signatures_note)
                     ]);
                 }
 
@@ -140,7 +207,10 @@ impl<'a> Compiler<'a> {
                                     "expected argument {}, found {}",
                                     best_match.0.arguments[i],
                                     arguments_signature[i]
-                                )).with_code(String::from("wrong argument"), arguments[i].span.clone())
+                                ))
+                                    .with_id(Code::WrongArgument)
+                                    .with_code(String::from("wrong argument"), arguments[i].span.clone())
+                                    .with_note(signatures_note.clone())
                             )
                         }
                     }
@@ -161,18 +231,39 @@ impl<'a> Compiler<'a> {
                 self.write(self.immediate(&value)?);
                 Ok(((), vec![]))
             }
-            NodeKind::Label { .. } => Ok(((), vec![]))
+            NodeKind::Assert { condition, message } => {
+                let value = condition.evaluate(&|name, span| self.label(name, span).map(i64::from))
+                    .map_err(|error| vec![error])?;
+
+                match value {
+                    0 => Err(vec![
+                        Message::error(format!("assertion failed: {}", &message.value))
+                            .with_id(Code::AssertionFailed)
+                            .with_code(String::from("evaluates to 0"), condition.span.clone())
+                    ]),
+                    _ => Ok(((), vec![]))
+                }
+            }
+            | NodeKind::Label { .. }
+            | NodeKind::AnonymousLabel
+            | NodeKind::Directive { .. }
+            | NodeKind::Constant { .. } => Ok(((), vec![]))
         }
     }
 
-    fn compile(&mut self, image_size: Option<usize>) -> Result<()> {
+    /// Compiles the program into `output`, returning the padding added to
+    /// fill `image_size`.
+    fn compile(&mut self, image_size: Option<usize>) -> Result<usize> {
         let mut errors = vec![];
         let mut warnings = vec![];
 
-        let mut program = self.program.iter();
+        let mut program = self.program.iter().enumerate();
         self.cursor = 0;
 
-        while let Some(current) = program.next() {
+        while let Some((i, current)) = program.next() {
+            self.node = i;
+            self.location = self.cursor;
+
             match self.compile_instruction(current) {
                 Ok(((), mut w)) => warnings.append(&mut w),
                 Err(mut e) => errors.append(&mut e)
@@ -180,42 +271,107 @@ impl<'a> Compiler<'a> {
         }
 
         for (symbol, value) in &self.symbols {
-            if !*value.used.borrow() {
+            // labels generated by control-flow constructs, such as 'if@1.end',
+            // go unused when the code before them never falls through
+            if !*value.used.borrow() && !symbol.contains('@') {
+                let kind = match self.constants.contains(symbol) {
+                    true => "constant",
+                    false => "label"
+                };
+
                 warnings.push(
-                    Message::warning(format!("label '{}' is never used", symbol))
-                        .with_code(String::from("unused label"),(*value).span.clone())
+                    Message::warning(format!("{} '{}' is never used", kind, symbol))
+                        .with_id(Code::UnusedLabel)
+                        .with_code(format!("unused {}", kind),(*value).span.clone())
                 )
             }
         }
 
-        let mut padding_string = String::new();
+        let mut padding = 0;
         if let Some(image_size) = image_size {
             if self.cursor > image_size {
-                errors.push(Message::error(format!("program ({}) does not fit inside image ({})!", human_count("byte", self.cursor), human_count("byte", image_size))));
+                errors.push(Message::error(format!("program ({}) does not fit inside image ({})!", human_count("byte", self.cursor), human_count("byte", image_size))).with_id(Code::ImageOverflow));
                 return Err(errors);
             }
-            if self.cursor < image_size {
-                let padding = image_size - self.cursor;
-                self.output.write_all(&vec![0x00; padding][..]).unwrap();
-                padding_string = format!(" (+ {} padding)", padding);
-            }
+            padding = image_size - self.cursor;
+            self.output.resize(image_size, 0x00);
         }
-        println!("wrote {}{} into '{}'", human_count("byte", self.cursor), padding_string, self.output_path.display());
-        
+
         if errors.is_empty() {
-            Ok(((), warnings))
+            Ok((padding, warnings))
         } else {
             Err(errors)
         }
     }
 
+    /// Evaluates the constant `name`, along with the constants it refers to.
+    /// `visiting` holds the constants being evaluated, to detect cycles.
+    fn constant(
+        &self,
+        name: &str,
+        value: &Expression,
+        constants: &HashMap<&String, &Expression>,
+        values: &RefCell<HashMap<String, i64>>,
+        visiting: &mut Vec<String>
+    ) -> core::result::Result<i64, Message> {
+        if let Some(value) = values.borrow().get(name) {
+            return Ok(*value);
+        }
+
+        visiting.push(String::from(name));
+
+        let visiting = RefCell::new(visiting);
+        let result = value.evaluate(&|symbol, span| {
+            self.label(symbol, span)?;
+
+            let Some(value) = constants.get(&String::from(symbol)) else {
+                return Ok(self.symbols[symbol].value.value as i64);
+            };
+
+            if visiting.borrow().iter().any(|visited| visited == symbol) {
+                return Err(
+                    Message::error(format!("constant '{}' depends on itself", symbol))
+                        .with_id(Code::CyclicConstant)
+                        .with_code(String::from("cyclic reference"), span.clone())
+                        .with_note(format!("the cycle is: {} -> {}", visiting.borrow().join(" -> "), symbol))
+                );
+            }
+
+            self.constant(symbol, value, constants, values, &mut visiting.borrow_mut())
+        });
+        let visiting = visiting.into_inner();
+
+        visiting.pop();
+
+        // failed constants aren't reported again by the ones using them
+        let number = result.inspect_err(|_| {
+            values.borrow_mut().insert(String::from(name), 0);
+        })?;
+
+        if !(-128..=255).contains(&number) {
+            return Err(
+                Message::error(format!("constant '{}' is {}, which doesn't fit in a byte", name, number))
+                    .with_id(Code::NumberOutOfRange)
+                    .with_code(String::from("out of range"), value.span.clone())
+            );
+        }
+
+        values.borrow_mut().insert(String::from(name), number);
+
+        Ok(number)
+    }
+
     fn do_declaration_pass(&mut self) -> Result<()> {
         let mut errors = vec![];
+        let mut constants: HashMap<&String, &Expression> = HashMap::new();
+        let mut order = vec![];
+
+        let mut anonymous = vec![];
 
-        let mut program = self.program.iter();
+        let mut program = self.program.iter().enumerate();
         let mut cursor: usize = 0;
 
-        while let Some(current) = program.next() {
+        while let Some((i, current)) = program.next() {
             cursor += match &current.value {
                 NodeKind::Instruction { arguments, .. } => {
                     let arguments_signature = signature::parse_arguments(arguments);
@@ -223,17 +379,34 @@ impl<'a> Compiler<'a> {
                     1 + arguments_signature.iter().filter(|argument| argument == &&Argument::Im).count() as u8
                 },
                 NodeKind::Value { .. } => 1,
-                NodeKind::Label { name } => {
+                NodeKind::Directive { .. } | NodeKind::Assert { .. } => 0,
+                NodeKind::AnonymousLabel => {
+                    anonymous.push((i, cursor as u8));
+
+                    0
+                }
+                NodeKind::Label { name } | NodeKind::Constant { name, .. } => {
                     let name_str = &name.value;
+                    let kind = match current.value {
+                        NodeKind::Constant { .. } => "constant",
+                        _ => "label"
+                    };
 
                     if let Some(previous) = self.symbols.get(name_str) {
                         errors.push(
-                            Message::error(format!("redefinition of label '{}'", name_str))
+                            Message::error(format!("redefinition of {} '{}'", kind, name_str))
+                                .with_id(Code::LabelRedefinition)
                                 .with_code(String::from("already defined"), name.span.clone())
                                 .with_code_context(String::from("previously defined here"), previous.span.clone())
                         )
                     }
 
+                    // constants are given their values once every label is known
+                    if let NodeKind::Constant { value, .. } = &current.value {
+                        constants.insert(name_str, value);
+                        order.push(name_str);
+                    }
+
                     self.symbols.insert(name_str.clone(), (cursor as u8).with_span(name.span.clone()).into());
 
                     0
@@ -241,6 +414,57 @@ impl<'a> Compiler<'a> {
             } as usize
         }
 
+        let values = RefCell::new(HashMap::new());
+
+        for (name, value) in order.iter().map(|name| (name, constants[name])) {
+            match self.constant(name, value, &constants, &values, &mut vec![]) {
+                Ok(value) => self.symbols.get_mut(*name).unwrap().value.value = value as u8,
+                Err(error) => errors.push(error)
+            }
+        }
+
+        for name in constants.keys() {
+            self.constants.insert((*name).clone());
+        }
+
+        // references to anonymous labels go to the nearest one in their direction
+        for (i, node) in self.program.iter().enumerate() {
+            let arguments = match &node.value {
+                NodeKind::Instruction { arguments, .. } => arguments.as_slice(),
+                NodeKind::Value { value } => std::slice::from_ref(value),
+                _ => continue
+            };
+
+            for argument in arguments {
+                let TokenKind::AnonymousLabel(direction) = argument.value else {
+                    continue
+                };
+
+                let label = match direction {
+                    Direction::Backward => anonymous.iter().rev().find(|(label, _)| *label < i),
+                    Direction::Forward => anonymous.iter().find(|(label, _)| *label > i)
+                };
+
+                match label {
+                    Some((_, address)) => {
+                        self.anonymous.insert((i, direction), *address);
+                    },
+                    None => errors.push(
+                        Message::error(format!("no anonymous label {} '{}'",
+                            match direction {
+                                Direction::Backward => "before",
+                                Direction::Forward => "after"
+                            },
+                            argument.span.get_text()
+                        ))
+                            .with_id(Code::UndeclaredLabel)
+                            .with_code(String::from("unknown label"), argument.span.clone())
+                            .with_note(String::from("declare an anonymous label with ':' on its own"))
+                    )
+                }
+            }
+        }
+
         if errors.is_empty() {
             Ok(((), vec![]))
         } else {
@@ -249,10 +473,82 @@ impl<'a> Compiler<'a> {
     }
 }
 
-pub fn compile(source: Source, output_path: PathBuf, image_size: Option<usize>, verbose: bool) -> Result<()> {
+/// A symbol defined or undefined on the command line.
+pub enum Define {
+    /// `-D NAME[=VALUE]`, defining `NAME` as the tokens of `VALUE`
+    Set { name: String, value: Option<String> },
+    /// `-U NAME`
+    Unset(String)
+}
+
+/// Symbols defined before anything else, as `%define`s of `<built-in>`.
+fn predefined() -> String {
+    format!(
+        "%define __PASM_VERSION \"{}\"\n\
+         %define __PASM_VERSION_MAJOR {}\n\
+         %define __PASM_VERSION_MINOR {}\n\
+         %define __PASM_VERSION_PATCH {}\n\
+         %define __TARGET \"poc-8\"\n\
+         %define __TARGET_POC8\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH")
+    )
+}
+
+/// Preprocesses the `%define`s in `text` into `scope`.
+fn define(text: String, path: &str, scope: &mut Scope, options: &Options) -> Result<()> {
+    let source = Rc::new(Source::new(text, PathBuf::from(path)));
+
+    let (tokens, mut warnings) = Lexer::new(&source).lex()?;
+    let ((), mut w) = Preprocessor::from(tokens)
+        .with_max_depth(options.macro_depth)
+        .preprocess(scope)?;
+    warnings.append(&mut w);
+
+    Ok(((), warnings))
+}
+
+pub struct Options {
+    pub output_path: PathBuf,
+    pub image_size: Option<usize>,
+    pub verbose: bool,
+    /// Run the stack-balance analysis over the parsed program.
+    pub check_stack: bool,
+    /// Write the control-flow graph to this file in the DOT format.
+    pub cfg_path: Option<PathBuf>,
+    /// Levels of the lints, applied after `%allow` directives.
+    pub lints: Lints,
+    /// How deeply macros may expand within each other.
+    pub macro_depth: usize,
+    /// Symbols to define or undefine before preprocessing, in order.
+    pub defines: Vec<Define>,
+    /// The files opened by the preprocessor, recorded for `--watch`.
+    pub opened: Rc<RefCell<Vec<PathBuf>>>
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+            image_size: None,
+            verbose: false,
+            check_stack: false,
+            cfg_path: None,
+            lints: Lints::default(),
+            macro_depth: DEFAULT_MAX_DEPTH,
+            defines: vec![],
+            opened: Rc::default()
+        }
+    }
+}
+
+/// Lexes and preprocesses `source`, returning the root scope.
+pub fn preprocess(source: Source, options: &Options) -> Result<Scope<'static>> {
     let mut warnings = vec![];
 
-    if verbose {
+    if options.verbose {
         println!("lexing tokens...")
     }
 
@@ -260,10 +556,39 @@ pub fn compile(source: Source, output_path: PathBuf, image_size: Option<usize>,
     warnings.append(&mut w);
 
     let mut scope = Scope::new(None);
-    let ((), mut w) = Preprocessor::from(tokens).preprocess(&mut scope)?;
-    let tokens = scope.tokens;
+
+    let ((), mut w) = define(predefined(), "<built-in>", &mut scope, options)?;
+    warnings.append(&mut w);
+
+    for symbol in &options.defines {
+        match symbol {
+            Define::Set { name, value } => {
+                let text = format!("%define {} {}\n", name, value.as_deref().unwrap_or_default());
+                let ((), mut w) = define(text, "<command line>", &mut scope, options)?;
+                warnings.append(&mut w);
+            },
+            Define::Unset(name) => scope.undefine(name)
+        }
+    }
+
+    let ((), mut w) = Preprocessor::from(tokens)
+        .with_max_depth(options.macro_depth)
+        .with_opened(Rc::clone(&options.opened))
+        .preprocess(&mut scope)?;
     warnings.append(&mut w);
 
+    Ok((scope, warnings))
+}
+
+/// Assembles `source` into an image without writing it.
+pub fn build(source: Source, options: &Options) -> Result<Image> {
+    let verbose = options.verbose;
+
+    let (scope, mut warnings) = preprocess(source, options)?;
+    let macros = scope.symbol_names().cloned().collect();
+    let tokens = scope.tokens;
+    let allows = scope.allows;
+
     if verbose {
         println!("parsing nodes...")
     }
@@ -271,18 +596,235 @@ pub fn compile(source: Source, output_path: PathBuf, image_size: Option<usize>,
     let (nodes, mut w) = Parser::new(tokens).parse()?;
     warnings.append(&mut w);
 
+    let (image, mut w) = assemble(nodes, macros, options)?;
+    warnings.append(&mut w);
+
+    let (warnings, errors) = options.lints.apply(warnings, &allows);
+
+    if errors.is_empty() {
+        Ok((image, warnings))
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn compile(source: Source, options: &Options) -> Result<()> {
+    let (image, warnings) = build(source, options)?;
+
+    image.write(options)?;
+    Ok(((), warnings))
+}
+
+/// Turns lines holding only the name of a label or constant into data
+/// values. They parse as instructions without arguments, which take the
+/// same single byte.
+fn symbol_values(nodes: Vec<Node>) -> Vec<Node> {
+    let symbols: HashSet<String> = nodes.iter()
+        .filter_map(|node| match &node.value {
+            NodeKind::Label { name } | NodeKind::Constant { name, .. } => Some(name.value.clone()),
+            _ => None
+        })
+        .collect();
+
+    nodes.into_iter()
+        .map(|node| match node.value {
+            NodeKind::Instruction { name, arguments } if arguments.is_empty() && !INSTRUCTIONS.contains_key(&name.value) && symbols.contains(&name.value) =>
+                NodeKind::Value { value: TokenKind::Word(name.value).with_span(name.span) }.with_span(node.span),
+            value => value.with_span(node.span)
+        })
+        .collect()
+}
+
+/// Assembles parsed nodes into an image, which the caller writes out. `macros`
+/// are the names defined by the preprocessor, suggested for undeclared
+/// labels. Lints are left to the caller, to apply once to all its warnings.
+pub fn assemble(nodes: Vec<Node>, macros: Vec<String>, options: &Options) -> Result<Image> {
+    let verbose = options.verbose;
+    let mut warnings = vec![];
+
+    let (nodes, mut w) = control::lower(nodes)?;
+    warnings.append(&mut w);
+
+    let (nodes, mut w) = module::resolve_modules(symbol_values(nodes))?;
+    warnings.append(&mut w);
+
+    let cfg = ControlFlowGraph::new(&nodes);
+
+    if options.check_stack {
+        if verbose {
+            println!("checking stack balance...")
+        }
+
+        warnings.append(&mut analysis::check_stack(&cfg));
+    }
+
+    warnings.append(&mut analysis::check_reachability(&nodes));
+
+    let ((), mut w) = analysis::check_clobbers(&cfg)?;
+    warnings.append(&mut w);
+
     if verbose {
         println!("compiling...")
     }
 
-    let mut output = File::create(&output_path).unwrap();
-
-    let mut compiler = Compiler::new(&nodes, &mut output, output_path);
+    let mut compiler = Compiler::new(&nodes, macros);
     let ((), mut w) = compiler.do_declaration_pass()?;
     warnings.append(&mut w);
 
-    let ((), mut w) = compiler.compile(image_size)?;
+    let (padding, mut w) = compiler.compile(options.image_size)?;
     warnings.append(&mut w);
 
-    Ok(((), warnings))
-}
\ No newline at end of file
+    // the graph is only written once the program is known to assemble
+    if let Some(cfg_path) = &options.cfg_path {
+        File::create(cfg_path)
+            .and_then(|file| cfg.write_dot(file))
+            .map_err(|error| vec![
+                Message::error(format!("could not write control-flow graph to '{}': {}", cfg_path.display(), error)).with_id(Code::OutputError)
+            ])?;
+    }
+
+    Ok((Image { bytes: compiler.output, padding }, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(text: &str) -> Source {
+        Source::new(text.to_string(), PathBuf::from("test.pasm"))
+    }
+
+    fn bytes(text: &str) -> Vec<u8> {
+        match build(source(text), &Options::default()) {
+            Ok((image, _)) => image.bytes,
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        match build(source(text), &Options::default()) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => errors.into_iter().filter_map(|error| error.code).collect()
+        }
+    }
+
+    #[test]
+    fn anonymous_labels_resolve_to_the_nearest() {
+        assert_eq!(
+            bytes(":\n jmp :+\n jmp :-\n:\n jmp :-\n"),
+            bytes("a:\n jmp b\n jmp a\nb:\n jmp b\n")
+        );
+        assert_eq!(
+            bytes(" jmp :+\n jmp :+\n:\n hlt\n:\n"),
+            bytes(" jmp a\n jmp a\na:\n hlt\nb:\n")
+        );
+    }
+
+    #[test]
+    fn anonymous_labels_resolve_in_values() {
+        assert_eq!(bytes(":\n hlt\n:-\n:+\n:\n"), bytes("a:\n hlt\na\nb\nb:\n"));
+    }
+
+    #[test]
+    fn rejects_missing_anonymous_labels() {
+        assert!(matches!(codes(" jmp :-\n:\n")[..], [Code::UndeclaredLabel]));
+        assert!(matches!(codes(":\n jmp :+\n")[..], [Code::UndeclaredLabel]));
+    }
+
+    #[test]
+    fn constants_are_evaluated_in_any_order() {
+        assert_eq!(bytes("WIDTH = HEIGHT * 2\nHEIGHT = 3\n put rx, WIDTH\n put ry, HEIGHT\n"), bytes(" put rx, 6\n put ry, 3\n"));
+        assert_eq!(bytes("OFFSET = end - start\nstart:\n put rx, OFFSET\nend:\n"), bytes(" put rx, 2\n"));
+        assert_eq!(bytes("LOW = -1\n put rx, LOW\n"), bytes(" put rx, 255\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_constants() {
+        assert!(matches!(codes("A = B + 1\nB = A\n put rx, A\n")[..], [Code::CyclicConstant]));
+        assert!(matches!(codes("A = A\n put rx, A\n")[..], [Code::CyclicConstant]));
+        assert!(matches!(codes("A = 200 + 100\n put rx, A\n")[..], [Code::NumberOutOfRange]));
+        assert!(matches!(codes("A = missing\n put rx, A\n")[..], [Code::UndeclaredLabel]));
+        assert!(matches!(codes("A = 1\nA:\n put rx, A\n")[..], [Code::LabelRedefinition]));
+        assert!(matches!(codes("A = 1\nA = 2\n put rx, A\n")[..], [Code::LabelRedefinition]));
+    }
+
+    fn bytes_with(text: &str, defines: Vec<Define>) -> Vec<u8> {
+        match build(source(text), &Options { defines, ..Options::default() }) {
+            Ok((image, _)) => image.bytes,
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+    }
+
+    fn set(name: &str, value: Option<&str>) -> Define {
+        Define::Set { name: name.to_string(), value: value.map(str::to_string) }
+    }
+
+    #[test]
+    fn command_line_defines_seed_the_scope() {
+        let text = "%ifndef VALUE\n%define VALUE 1\n%end\n put rx, VALUE\n%ifndef FLAG\n hlt\n%end\n";
+
+        assert_eq!(bytes_with(text, vec![]), bytes(" put rx, 1\n hlt\n"));
+        assert_eq!(bytes_with(text, vec![set("VALUE", Some("5"))]), bytes(" put rx, 5\n hlt\n"));
+        assert_eq!(bytes_with(text, vec![set("FLAG", None)]), bytes(" put rx, 1\n"));
+    }
+
+    #[test]
+    fn command_line_defines_apply_in_order() {
+        let text = "%ifndef FLAG\n hlt\n%end\n";
+
+        assert_eq!(bytes_with(text, vec![set("FLAG", None), Define::Unset(String::from("FLAG"))]), [3]);
+        assert!(bytes_with(text, vec![Define::Unset(String::from("FLAG")), set("FLAG", None)]).is_empty());
+    }
+
+    #[test]
+    fn predefines_the_version_and_target() {
+        assert_eq!(bytes(" put rx, __PASM_VERSION_MAJOR\n"), bytes(&format!(" put rx, {}\n", env!("CARGO_PKG_VERSION_MAJOR"))));
+        assert!(bytes("%ifndef __TARGET_POC8\n hlt\n%end\n").is_empty());
+        assert_eq!(bytes_with("%ifndef __TARGET_POC8\n hlt\n%end\n", vec![Define::Unset(String::from("__TARGET_POC8"))]), [3]);
+    }
+
+    #[test]
+    fn writes_the_graph_only_on_success() {
+        let path = std::env::temp_dir().join(format!("pasm-cfg-{}.dot", std::process::id()));
+        let options = Options { cfg_path: Some(path.clone()), ..Options::default() };
+        let _ = fs::remove_file(&path);
+
+        assert!(build(source(" jmp nowhere\n"), &options).is_err());
+        assert!(!path.exists());
+
+        assert!(build(source("start:\n jmp start\n"), &options).is_ok());
+        assert!(fs::read_to_string(&path).unwrap().starts_with("digraph cfg {"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_unterminated_characters() {
+        assert!(matches!(codes(" put rx, 'a\n")[..], [Code::InvalidCharacterLiteral]));
+        assert!(matches!(codes(" put rx, '")[..], [Code::InvalidCharacterLiteral]));
+    }
+
+    #[test]
+    fn reports_output_errors_without_writing() {
+        let dir = std::env::temp_dir().join(format!("pasm-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.bin");
+
+        let options = Options { output_path: path.clone(), ..Options::default() };
+        assert!(compile(source(" jmp nowhere\n"), &options).is_err());
+        assert!(!path.exists());
+
+        let missing = Options { output_path: dir.join("missing/out.bin"), ..Options::default() };
+        match compile(source(" hlt\n"), &missing) {
+            Ok(_) => panic!("the output directory doesn't exist"),
+            Err(errors) => assert!(matches!(errors[..], [Message { code: Some(Code::OutputError), .. }]))
+        }
+
+        let small = Options { output_path: path.clone(), image_size: Some(1), ..Options::default() };
+        match compile(source(" hlt\n hlt\n"), &small) {
+            Ok(_) => panic!("the program doesn't fit in the image"),
+            Err(errors) => assert!(matches!(errors[..], [Message { code: Some(Code::ImageOverflow), .. }]))
+        }
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{parser::{Node, NodeKind}, lexer::{Token, TokenKind}, expression::Operator, message::{Message, Result}, source::{Span, WithSpan, IntoWithSpan}, code::Code, cfg::is_register};
+
+#[derive(Debug)]
+enum Construct {
+    If { id: usize, span: Span, has_else: bool },
+    While { id: usize, span: Span }
+}
+
+/// Where the jumps testing a condition go when they're taken.
+enum Target {
+    /// into the body, jumping past it otherwise
+    Body,
+    /// past the body
+    Skip
+}
+
+/// The jumps testing `register` against 0, taken when the condition holds
+/// for `Target::Body` or when it doesn't for `Target::Skip`.
+struct Condition<'a> {
+    register: &'a str,
+    jumps: &'static [&'static str],
+    target: Target
+}
+
+fn invalid(message: String, description: &str, span: &Span) -> Message {
+    Message::error(message)
+        .with_id(Code::InvalidControlFlow)
+        .with_code(String::from(description), span.clone())
+}
+
+fn condition<'a>(keyword: &str, arguments: &'a [Token], span: &Span) -> core::result::Result<Condition<'a>, Message> {
+    let usage = || format!("usage: {} <register> <operator> 0", keyword);
+
+    let (register, operator) = match arguments {
+        [
+            WithSpan { value: TokenKind::Word(register), .. },
+            WithSpan { value: TokenKind::Operator(operator), .. },
+            WithSpan { value: TokenKind::Number(0), .. }
+        ] if is_register(register) => (register, operator),
+        _ => return Err(
+            invalid(format!("'{}' must be supplied with a comparison of a register with 0", keyword), "invalid condition", span)
+                .with_note(usage())
+        )
+    };
+
+    let (jumps, target): (&[&str], _) = match operator {
+        Operator::Equal => (&["jpz"], Target::Body),
+        Operator::NotEqual => (&["jpz"], Target::Skip),
+        Operator::Less => (&["jpn"], Target::Body),
+        Operator::GreaterEqual => (&["jpn"], Target::Skip),
+        Operator::LessEqual => (&["jpz", "jpn"], Target::Body),
+        Operator::Greater => (&["jpz", "jpn"], Target::Skip),
+        _ => return Err(
+            invalid(format!("'{}' is not a comparison", operator.as_str()), "invalid condition", span)
+                .with_note(String::from("valid comparisons are: '==', '!=', '<', '<=', '>' and '>='"))
+        )
+    };
+
+    if jumps.contains(&"jpn") && register == "rz" {
+        return Err(
+            invalid(format!("'{}' can't test the sign of 'rz'", keyword), "compares 'rz' with 0", span)
+                .with_note(String::from("'jpn' can only test 'rx' and 'ry'"))
+        );
+    }
+
+    Ok(Condition { register, jumps, target })
+}
+
+/// Emits nodes that all point back at the construct they were lowered from.
+struct Emitter<'a> {
+    out: &'a mut Vec<Node>,
+    span: Span
+}
+
+impl Emitter<'_> {
+    fn word(&self, word: &str) -> Token {
+        TokenKind::Word(String::from(word)).with_span(self.span.clone())
+    }
+
+    fn instruction(&mut self, name: &str, arguments: &[&str]) {
+        self.out.push(NodeKind::Instruction {
+            name: String::from(name).with_span(self.span.clone()),
+            arguments: arguments.iter().map(|argument| self.word(argument)).collect()
+        }.with_span(self.span.clone()));
+    }
+
+    fn label(&mut self, name: &str) {
+        self.out.push(NodeKind::Label {
+            name: String::from(name).with_span(self.span.clone())
+        }.with_span(self.span.clone()));
+    }
+
+    /// Returns whether the last node emitted so far never falls through, in
+    /// which case a jump after it would be unreachable.
+    fn terminated(&self) -> bool {
+        matches!(
+            self.out.last().map(|node| &node.value),
+            Some(NodeKind::Instruction { name, .. }) if matches!(name.value.as_str(), "jmp" | "ret" | "hlt")
+        )
+    }
+
+    /// Jumps to `skip` unless `condition` holds.
+    fn test(&mut self, condition: &Condition, body: &str, skip: &str) {
+        let target = match condition.target {
+            Target::Body => body,
+            Target::Skip => skip
+        };
+
+        for jump in condition.jumps {
+            self.instruction(jump, &[target, condition.register]);
+        }
+
+        if let Target::Body = condition.target {
+            self.instruction("jmp", &[skip]);
+            self.label(body);
+        }
+    }
+}
+
+/// Lowers `.if`, `.else`, `.while`, `.break` and `.end` to `jpz`, `jpn` and
+/// `jmp` over generated labels, named `if@<n>` or `while@<n>` so they can't
+/// collide with declared ones.
+pub fn lower(program: Vec<Node>) -> Result<Vec<Node>> {
+    let mut errors = vec![];
+    let mut out = vec![];
+    let mut stack: Vec<Construct> = vec![];
+    let mut count = 0;
+
+    for node in program {
+        let NodeKind::Directive { name, arguments } = &node.value else {
+            out.push(node);
+            continue
+        };
+
+        if !name.value.starts_with('.') {
+            out.push(node);
+            continue
+        }
+
+        let mut emit = Emitter { out: &mut out, span: node.span.clone() };
+
+        match (name.value.as_str(), arguments.as_slice()) {
+            // blocks with invalid conditions are still opened, so their
+            // '.end' isn't reported as well
+            (".if", _) => {
+                count += 1;
+                stack.push(Construct::If { id: count, span: node.span.clone(), has_else: false });
+
+                match condition(&name.value, arguments, &node.span) {
+                    Ok(condition) => emit.test(&condition, &format!("if@{}", count), &format!("if@{}.else", count)),
+                    Err(error) => errors.push(error)
+                }
+            },
+            (".while", _) => {
+                count += 1;
+                stack.push(Construct::While { id: count, span: node.span.clone() });
+
+                match condition(&name.value, arguments, &node.span) {
+                    Ok(condition) => {
+                        emit.label(&format!("while@{}", count));
+                        emit.test(&condition, &format!("while@{}.body", count), &format!("while@{}.end", count));
+                    },
+                    Err(error) => errors.push(error)
+                }
+            },
+            (".else", []) => match stack.last_mut() {
+                Some(Construct::If { has_else: has_else @ false, id, .. }) => {
+                    *has_else = true;
+                    if !emit.terminated() {
+                        emit.instruction("jmp", &[&format!("if@{}.end", id)]);
+                    }
+                    emit.label(&format!("if@{}.else", id));
+                },
+                Some(Construct::If { span, .. }) => errors.push(
+                    invalid(String::from("'.if' can only have one '.else'"), "second '.else'", &node.span)
+                        .with_code_context(String::from("of this '.if'"), span.clone())
+                ),
+                _ => errors.push(invalid(String::from("'.else' must be inside of an '.if' block"), "outside of an '.if'", &node.span))
+            },
+            (".break", []) => match stack.iter().rev().find_map(|construct| match construct {
+                Construct::While { id, .. } => Some(id),
+                _ => None
+            }) {
+                Some(id) => emit.instruction("jmp", &[&format!("while@{}.end", id)]),
+                None => errors.push(invalid(String::from("'.break' must be inside of a '.while' block"), "outside of a '.while'", &node.span))
+            },
+            (".end", []) => match stack.pop() {
+                Some(Construct::If { id, has_else, .. }) => emit.label(&match has_else {
+                    true => format!("if@{}.end", id),
+                    false => format!("if@{}.else", id)
+                }),
+                Some(Construct::While { id, .. }) => {
+                    if !emit.terminated() {
+                        emit.instruction("jmp", &[&format!("while@{}", id)]);
+                    }
+                    emit.label(&format!("while@{}.end", id));
+                },
+                None => errors.push(invalid(String::from("'.end' doesn't close any block"), "unmatched '.end'", &node.span))
+            },
+            (".else" | ".break" | ".end", [first, ..]) => errors.push(
+                invalid(format!("'{}' takes no arguments", &name.value), "unexpected argument", &first.span)
+            ),
+            _ => errors.push(
+                invalid(format!("unknown construct '{}'", &name.value), "unknown construct", &name.span)
+                    .with_note(String::from("valid constructs are: '.if', '.else', '.while', '.break' and '.end'"))
+            )
+        }
+    }
+
+    for construct in stack {
+        let (keyword, span) = match construct {
+            Construct::If { span, .. } => (".if", span),
+            Construct::While { span, .. } => (".while", span)
+        };
+
+        errors.push(invalid(format!("'{}' must be closed with '.end'", keyword), "unclosed block", &span));
+    }
+
+    if errors.is_empty() {
+        Ok((out, vec![]))
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{compiler::{build, preprocess, Options}, parser::Parser, source::Source};
+
+    fn parse(text: &str) -> Vec<Node> {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+        let (scope, _) = preprocess(source, &Options::default()).expect("source should preprocess");
+        let (program, _) = Parser::new(scope.tokens).parse().expect("source should parse");
+
+        program
+    }
+
+    /// Lowers `text` and renders each node as `name arguments` or `label:`.
+    fn lowered(text: &str) -> Vec<String> {
+        let (program, _) = lower(parse(text)).expect("control flow should lower");
+
+        program.iter().map(|node| match &node.value {
+            NodeKind::Instruction { name, arguments } => {
+                let arguments: Vec<_> = arguments.iter().map(|argument| match &argument.value {
+                    TokenKind::Word(word) => word.as_str(),
+                    other => panic!("unexpected argument {:?}", other)
+                }).collect();
+                format!("{} {}", name.value, arguments.join(", ")).trim_end().to_string()
+            },
+            NodeKind::Label { name } => format!("{}:", name.value),
+            other => panic!("unexpected node {:?}", other)
+        }).collect()
+    }
+
+    fn errors(text: &str) -> Vec<Message> {
+        lower(parse(text)).expect_err("control flow shouldn't lower")
+    }
+
+    #[test]
+    fn lowers_each_comparison() {
+        assert_eq!(lowered(".if rx == 0\n hlt\n.end"), [
+            "jpz if@1, rx", "jmp if@1.else", "if@1:", "hlt", "if@1.else:"
+        ]);
+        assert_eq!(lowered(".if rx != 0\n hlt\n.end"), [
+            "jpz if@1.else, rx", "hlt", "if@1.else:"
+        ]);
+        assert_eq!(lowered(".if ry < 0\n hlt\n.end"), [
+            "jpn if@1, ry", "jmp if@1.else", "if@1:", "hlt", "if@1.else:"
+        ]);
+        assert_eq!(lowered(".if ry >= 0\n hlt\n.end"), [
+            "jpn if@1.else, ry", "hlt", "if@1.else:"
+        ]);
+        assert_eq!(lowered(".if rx <= 0\n hlt\n.end"), [
+            "jpz if@1, rx", "jpn if@1, rx", "jmp if@1.else", "if@1:", "hlt", "if@1.else:"
+        ]);
+        assert_eq!(lowered(".if rx > 0\n hlt\n.end"), [
+            "jpz if@1.else, rx", "jpn if@1.else, rx", "hlt", "if@1.else:"
+        ]);
+    }
+
+    #[test]
+    fn lowers_else() {
+        assert_eq!(lowered(".if rz != 0\n inc rx\n.else\n dec rx\n.end"), [
+            "jpz if@1.else, rz", "inc rx", "jmp if@1.end", "if@1.else:", "dec rx", "if@1.end:"
+        ]);
+    }
+
+    #[test]
+    fn lowers_while_and_break() {
+        assert_eq!(lowered(".while rx != 0\n .if ry == 0\n  .break\n .end\n dec rx\n.end"), [
+            "while@1:", "jpz while@1.end, rx",
+            "jpz if@2, ry", "jmp if@2.else", "if@2:", "jmp while@1.end", "if@2.else:",
+            "dec rx", "jmp while@1", "while@1.end:"
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_control_flow() {
+        for text in [
+            ".if rx + 0\n.end",
+            ".if rx == 1\n.end",
+            ".if rz < 0\n.end",
+            ".if rx == 0",
+            ".end",
+            ".else",
+            ".break",
+            ".if rx == 0\n.else\n.else\n.end",
+            ".loop"
+        ] {
+            let errors = errors(text);
+
+            assert_eq!(errors.len(), 1, "{:?}", text);
+            assert!(matches!(errors[0].code, Some(Code::InvalidControlFlow)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn skips_jumps_after_terminators() {
+        assert_eq!(lowered(".if rx == 0\n ret\n.else\n dec rx\n.end"), [
+            "jpz if@1, rx", "jmp if@1.else", "if@1:", "ret", "if@1.else:", "dec rx", "if@1.end:"
+        ]);
+        assert_eq!(lowered(".while ry > 0\n dec ry\n .break\n.end"), [
+            "while@1:", "jpz while@1.end, ry", "jpn while@1.end, ry", "dec ry", "jmp while@1.end", "while@1.end:"
+        ]);
+    }
+
+    #[test]
+    fn terminated_blocks_have_no_unreachable_code() {
+        for text in [
+            " put rx, 1\n.if rx == 0\n ret\n.else\n dec rx\n.end\n hlt\n",
+            " put ry, 1\n.while ry > 0\n dec ry\n .break\n.end\n hlt\n"
+        ] {
+            let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+
+            match build(source, &Options::default()) {
+                Ok((_, warnings)) => assert!(warnings.is_empty(), "{:?}: {:?}", text, warnings),
+                Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+            }
+        }
+    }
+}
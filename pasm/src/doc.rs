@@ -0,0 +1,345 @@
+use std::{collections::{HashMap, HashSet}, fmt::Write, path::PathBuf};
+
+use crate::{analysis, code::Code, compiler::{preprocess, Options}, lexer::{Doc, Lexer, TokenKind}, message::{Message, Result}, parser::{Parser, NodeKind}, source::{Source, Span, WithSpan}};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Markdown,
+    Html
+}
+
+/// An exported routine, described by the comment block before its label.
+struct Routine {
+    name: String,
+    span: Span,
+    description: Vec<String>,
+    inputs: Vec<(String, String)>,
+    outputs: Vec<(String, String)>,
+    /// the registers given by its `%clobbers` or `%preserves` contract
+    clobbers: Option<Vec<&'static str>>
+}
+
+enum Section {
+    Description,
+    Input,
+    Output
+}
+
+impl Routine {
+    /// Reads a std style banner, where `input` and `output` head lists of
+    /// `register - description` lines. Other comments are the description.
+    fn read_doc(&mut self, doc: &Doc) {
+        let mut section = Section::Description;
+
+        for line in &doc.lines {
+            // the `---- name ----` rules above and below the banner
+            if line.trim_start().starts_with("--") {
+                continue;
+            }
+
+            let text = line.trim().trim_end_matches(';').trim();
+
+            match (text.to_lowercase().as_str(), text.split_once(" - "), &section) {
+                ("", ..) => (),
+                ("input" | "inputs", ..) => section = Section::Input,
+                ("output" | "outputs", ..) => section = Section::Output,
+                (_, Some((register, description)), Section::Input) => self.inputs.push((register.trim().to_string(), description.trim().to_string())),
+                (_, Some((register, description)), Section::Output) => self.outputs.push((register.trim().to_string(), description.trim().to_string())),
+                _ => self.description.push(text.to_string())
+            }
+        }
+    }
+
+    fn link(&self) -> (String, usize) {
+        (self.span.source.path.display().to_string(), self.span.row_num())
+    }
+}
+
+/// Finds the routines exported from `%module` blocks of the program.
+fn routines(source: Source, options: &Options) -> Result<Vec<Routine>> {
+    let (scope, mut warnings) = preprocess(source, options)?;
+    let (nodes, mut w) = Parser::new(scope.tokens).parse()?;
+    warnings.append(&mut w);
+
+    let (contracts, mut w) = analysis::contracts(&nodes)?;
+    warnings.append(&mut w);
+
+    let mut exports = HashSet::new();
+    // the module of every node
+    let mut modules: Vec<Option<String>> = vec![];
+    let mut stack: Vec<String> = vec![];
+
+    for node in &nodes {
+        match &node.value {
+            NodeKind::Directive { name, arguments } if name.value == "module" => if let [WithSpan { value: TokenKind::Word(name), .. }] = arguments.as_slice() {
+                stack.push(match stack.last() {
+                    Some(parent) => format!("{}::{}", parent, name),
+                    None => name.clone()
+                });
+            },
+            NodeKind::Directive { name, .. } if name.value == "end" => {
+                stack.pop();
+            },
+            NodeKind::Directive { name, arguments } if name.value == "export" => if let Some(module) = stack.last() {
+                for argument in arguments {
+                    if let TokenKind::Word(label) = &argument.value {
+                        exports.insert((module.clone(), label.clone()));
+                    }
+                }
+            },
+            _ => ()
+        }
+
+        modules.push(stack.last().cloned());
+    }
+
+    // labels may be exported before or after they're declared
+    let routines = nodes.iter().zip(modules)
+        .filter_map(|(node, module)| match &node.value {
+            NodeKind::Label { name } if exports.contains(&(module?, name.value.clone())) => Some(Routine {
+                name: name.value.clone(),
+                span: name.span.clone(),
+                description: vec![],
+                inputs: vec![],
+                outputs: vec![],
+                clobbers: contracts.get(&name.value).cloned()
+            }),
+            _ => None
+        })
+        .collect();
+
+    Ok((routines, warnings))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn markdown(routines: &[Routine]) -> String {
+    let mut out = String::from("# Reference\n\n");
+
+    for routine in routines {
+        writeln!(out, "- [`{}`](#{})", routine.name, routine.name.to_lowercase()).unwrap();
+    }
+
+    for routine in routines {
+        write!(out, "\n## {}\n\n", routine.name).unwrap();
+
+        if !routine.description.is_empty() {
+            write!(out, "{}\n\n", escape(&routine.description.join(" "))).unwrap();
+        }
+
+        for (title, registers) in [("Inputs", &routine.inputs), ("Outputs", &routine.outputs)] {
+            if registers.is_empty() {
+                continue;
+            }
+
+            write!(out, "**{}**\n\n| Register | Description |\n| --- | --- |\n", title).unwrap();
+            for (register, description) in registers {
+                writeln!(out, "| `{}` | {} |", register, escape(description).replace('|', "\\|")).unwrap();
+            }
+            out.push('\n');
+        }
+
+        let clobbers = match &routine.clobbers {
+            Some(registers) if registers.is_empty() => String::from("none"),
+            Some(registers) => registers.iter().map(|register| format!("`{}`", register)).collect::<Vec<String>>().join(", "),
+            None => String::from("not declared")
+        };
+        write!(out, "**Clobbers:** {}\n\n", clobbers).unwrap();
+
+        let (path, row) = routine.link();
+        writeln!(out, "Defined in [{}:{}]({}#L{}).", path, row, path, row).unwrap();
+    }
+
+    out
+}
+
+fn html(routines: &[Routine]) -> String {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Reference</title>\n</head>\n<body>\n<h1>Reference</h1>\n<ul>\n");
+
+    for routine in routines {
+        writeln!(out, "<li><a href=\"#{0}\"><code>{0}</code></a></li>", escape(&routine.name)).unwrap();
+    }
+    out.push_str("</ul>\n");
+
+    for routine in routines {
+        writeln!(out, "<section id=\"{0}\">\n<h2>{0}</h2>", escape(&routine.name)).unwrap();
+
+        if !routine.description.is_empty() {
+            writeln!(out, "<p>{}</p>", escape(&routine.description.join(" "))).unwrap();
+        }
+
+        for (title, registers) in [("Inputs", &routine.inputs), ("Outputs", &routine.outputs)] {
+            if registers.is_empty() {
+                continue;
+            }
+
+            writeln!(out, "<h3>{}</h3>\n<table>\n<tr><th>Register</th><th>Description</th></tr>", title).unwrap();
+            for (register, description) in registers {
+                writeln!(out, "<tr><td><code>{}</code></td><td>{}</td></tr>", escape(register), escape(description)).unwrap();
+            }
+            out.push_str("</table>\n");
+        }
+
+        let clobbers = match &routine.clobbers {
+            Some(registers) if registers.is_empty() => String::from("none"),
+            Some(registers) => registers.iter().map(|register| format!("<code>{}</code>", register)).collect::<Vec<String>>().join(", "),
+            None => String::from("not declared")
+        };
+        writeln!(out, "<p><strong>Clobbers:</strong> {}</p>", clobbers).unwrap();
+
+        let (path, row) = routine.link();
+        writeln!(out, "<p>Defined in <a href=\"{0}#L{1}\">{0}:{1}</a>.</p>\n</section>", escape(&path), row).unwrap();
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Renders a reference of the routines exported by `sources` and the files
+/// they include, from the comment block before each routine's label.
+pub fn document(sources: Vec<Source>, options: &Options, format: Format) -> Result<String> {
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    let mut routines: Vec<Routine> = vec![];
+
+    for source in sources {
+        match self::routines(source, options) {
+            Ok((found, mut w)) => {
+                warnings.append(&mut w);
+
+                // files included from several inputs are documented once
+                for routine in found {
+                    if !routines.iter().any(|other| other.span.source.path == routine.span.source.path && other.span.begin == routine.span.begin) {
+                        routines.push(routine);
+                    }
+                }
+            },
+            Err(mut e) => errors.append(&mut e)
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if routines.is_empty() {
+        warnings.push(
+            Message::warning(String::from("no exported routines to document"))
+                .with_id(Code::NothingToDocument)
+                .with_note(String::from("routines are exported from a '%module' block with '%export'"))
+        );
+    }
+
+    let mut docs: HashMap<PathBuf, Vec<Doc>> = HashMap::new();
+
+    for routine in &mut routines {
+        let source = &routine.span.source;
+        let docs = docs.entry(source.path.clone()).or_insert_with(|| {
+            let mut lexer = Lexer::new(source);
+            let _ = lexer.lex();
+            lexer.docs().to_vec()
+        });
+
+        if let Some(doc) = docs.iter().find(|doc| doc.label.span.begin == routine.span.begin) {
+            routine.read_doc(doc);
+        }
+    }
+
+    routines.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let out = match format {
+        Format::Markdown => markdown(&routines),
+        Format::Html => html(&routines)
+    };
+
+    Ok((out, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATH: &str = "\
+;------- sum -------;
+; Adds two numbers. ;
+;       input       ;
+;  rx - a           ;
+;  ry - b <= 10     ;
+;       output      ;
+;  rx - a + b       ;
+;-------------------;
+
+%module math
+%export sum
+%preserves ry
+sum:
+ add rx, ry
+ ret
+hidden:
+ ret
+%end
+";
+
+    fn document_text(text: &str, format: Format) -> Result<String> {
+        document(vec![Source::new(text.to_string(), PathBuf::from("math.pasm"))], &Options::default(), format)
+    }
+
+    #[test]
+    fn renders_markdown() {
+        let (out, warnings) = document_text(MATH, Format::Markdown).expect("source should document");
+
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(out, "\
+# Reference
+
+- [`sum`](#sum)
+
+## sum
+
+Adds two numbers.
+
+**Inputs**
+
+| Register | Description |
+| --- | --- |
+| `rx` | a |
+| `ry` | b &lt;= 10 |
+
+**Outputs**
+
+| Register | Description |
+| --- | --- |
+| `rx` | a + b |
+
+**Clobbers:** `rx`, `rz`
+
+Defined in [math.pasm:13](math.pasm#L13).
+");
+    }
+
+    #[test]
+    fn renders_html() {
+        let (out, _) = document_text(MATH, Format::Html).expect("source should document");
+
+        assert!(out.starts_with("<!DOCTYPE html>\n"));
+        assert!(out.contains("<li><a href=\"#sum\"><code>sum</code></a></li>\n"));
+        assert!(out.contains("<tr><td><code>ry</code></td><td>b &lt;= 10</td></tr>\n"));
+        assert!(out.contains("<p><strong>Clobbers:</strong> <code>rx</code>, <code>rz</code></p>\n"));
+        assert!(out.contains("<a href=\"math.pasm#L13\">math.pasm:13</a>"));
+        assert!(!out.contains("hidden"));
+        assert!(out.ends_with("</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn warns_when_nothing_is_exported() {
+        let (out, warnings) = document_text("sum:\n add rx, ry\n ret\n", Format::Markdown).expect("source should document");
+
+        assert_eq!(out, "# Reference\n\n");
+        assert!(matches!(warnings[..], [Message { code: Some(Code::NothingToDocument), .. }]));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{rc::Rc, iter::Peekable, slice::Iter};
+
+use crate::{lexer::{Token, TokenKind}, source::{Span, WithSpan}, message::Message, code::Code};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    Bang,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or
+}
+
+impl Operator {
+    /// Every operator, longer ones before their prefixes.
+    pub const ALL: &'static [Operator] = &[
+        Operator::ShiftLeft,
+        Operator::ShiftRight,
+        Operator::Equal,
+        Operator::NotEqual,
+        Operator::LessEqual,
+        Operator::GreaterEqual,
+        Operator::And,
+        Operator::Or,
+        Operator::Plus,
+        Operator::Minus,
+        Operator::Star,
+        Operator::Slash,
+        Operator::Ampersand,
+        Operator::Pipe,
+        Operator::Caret,
+        Operator::Tilde,
+        Operator::Bang,
+        Operator::Less,
+        Operator::Greater
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Star => "*",
+            Operator::Slash => "/",
+            Operator::Ampersand => "&",
+            Operator::Pipe => "|",
+            Operator::Caret => "^",
+            Operator::Tilde => "~",
+            Operator::Bang => "!",
+            Operator::ShiftLeft => "<<",
+            Operator::ShiftRight => ">>",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::And => "&&",
+            Operator::Or => "||"
+        }
+    }
+
+    /// Binding strength of binary operators, loosest first as in C.
+    fn precedence(&self) -> Option<u8> {
+        Some(match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Pipe => 3,
+            Operator::Caret => 4,
+            Operator::Ampersand => 5,
+            Operator::Equal | Operator::NotEqual => 6,
+            Operator::Less | Operator::LessEqual | Operator::Greater | Operator::GreaterEqual => 7,
+            Operator::ShiftLeft | Operator::ShiftRight => 8,
+            Operator::Plus | Operator::Minus => 9,
+            Operator::Star | Operator::Slash => 10,
+            Operator::Tilde | Operator::Bang => return None
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
+    Number(i64),
+    Symbol(String),
+    Unary(Operator, Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>)
+}
+
+/// An integer expression over numbers and symbols, such as labels.
+pub type Expression = WithSpan<ExpressionKind>;
+
+fn join(begin: &Span, end: &Span) -> Span {
+    Span {
+        end: end.end,
+        ..begin.clone()
+    }
+}
+
+struct ExpressionParser<'a> {
+    tokens: Peekable<Iter<'a, Token>>,
+    /// where the expression ends, for errors about missing operands
+    end: Span
+}
+
+impl ExpressionParser<'_> {
+    fn expected(&mut self, what: &str) -> Message {
+        let span = self.tokens.peek()
+            .map(|token| token.span.clone())
+            .unwrap_or_else(|| Span::new(self.end.end, self.end.end + 1, Rc::clone(&self.end.source)));
+
+        Message::error(format!("expected {}, found '{}'", what, span.get_text()))
+            .with_id(Code::InvalidSyntax)
+            .with_code(format!("expected {}", what), span)
+    }
+
+    fn primary(&mut self) -> Result<Expression, Message> {
+        let Some(token) = self.tokens.next() else {
+            return Err(self.expected("operand"));
+        };
+
+        let kind = match &token.value {
+            TokenKind::Number(number) => ExpressionKind::Number(*number as i64),
+            TokenKind::Character(character) => ExpressionKind::Number(*character as i64),
+            TokenKind::Word(word) => ExpressionKind::Symbol(word.clone()),
+            TokenKind::Operator(operator @ (Operator::Minus | Operator::Tilde | Operator::Bang)) => {
+                let operand = self.primary()?;
+                let span = join(&token.span, &operand.span);
+
+                return Ok(WithSpan { value: ExpressionKind::Unary(*operator, Box::new(operand)), span })
+            }
+            TokenKind::LeftParen => {
+                let inner = self.binary(0)?;
+
+                return match self.tokens.next() {
+                    Some(WithSpan { value: TokenKind::RightParen, span }) => Ok(WithSpan {
+                        value: inner.value,
+                        span: join(&token.span, span)
+                    }),
+                    _ => Err(
+                        Message::error(String::from("unclosed parenthesis"))
+                            .with_id(Code::InvalidSyntax)
+                            .with_code(String::from("expected ')' to close this"), token.span.clone())
+                    )
+                }
+            }
+            _ => return Err(
+                Message::error(format!("expected operand, found '{}'", token.span.get_text()))
+                    .with_id(Code::InvalidSyntax)
+                    .with_code(String::from("expected operand"), token.span.clone())
+            )
+        };
+
+        Ok(WithSpan { value: kind, span: token.span.clone() })
+    }
+
+    /// Parses operators binding tighter than `precedence` by precedence climbing.
+    fn binary(&mut self, precedence: u8) -> Result<Expression, Message> {
+        let mut left = self.primary()?;
+
+        while let Some(WithSpan { value: TokenKind::Operator(operator), .. }) = self.tokens.peek() {
+            let operator = *operator;
+
+            match operator.precedence() {
+                Some(next) if next > precedence => {
+                    self.tokens.next();
+
+                    let right = self.binary(next)?;
+                    let span = join(&left.span, &right.span);
+
+                    left = WithSpan { value: ExpressionKind::Binary(operator, Box::new(left), Box::new(right)), span };
+                }
+                _ => break
+            }
+        }
+
+        Ok(left)
+    }
+}
+
+/// Parses `tokens` as a single expression. `tokens` mustn't be empty.
+pub fn parse(tokens: &[Token]) -> Result<Expression, Message> {
+    let mut parser = ExpressionParser {
+        tokens: tokens.iter().peekable(),
+        end: tokens.last().expect("expression should have tokens").span.clone()
+    };
+
+    let expression = parser.binary(0)?;
+
+    match parser.tokens.peek() {
+        None => Ok(expression),
+        Some(_) => Err(parser.expected("operator"))
+    }
+}
+
+impl Expression {
+    /// Evaluates the expression, looking symbols up with `resolve`.
+    pub fn evaluate(&self, resolve: &dyn Fn(&str, &Span) -> Result<i64, Message>) -> Result<i64, Message> {
+        Ok(match &self.value {
+            ExpressionKind::Number(number) => *number,
+            ExpressionKind::Symbol(symbol) => resolve(symbol, &self.span)?,
+            ExpressionKind::Unary(operator, operand) => {
+                let operand = operand.evaluate(resolve)?;
+
+                match operator {
+                    Operator::Minus => operand.wrapping_neg(),
+                    Operator::Tilde => !operand,
+                    _ => (operand == 0) as i64
+                }
+            }
+            ExpressionKind::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(resolve)?, right.evaluate(resolve)?);
+
+                match operator {
+                    Operator::Plus => left.wrapping_add(right),
+                    Operator::Minus => left.wrapping_sub(right),
+                    Operator::Star => left.wrapping_mul(right),
+                    Operator::Slash => left.checked_div(right).ok_or_else(||
+                        Message::error(String::from("division by zero"))
+                            .with_id(Code::DivisionByZero)
+                            .with_code(String::from("divides by zero"), self.span.clone())
+                    )?,
+                    Operator::Ampersand => left & right,
+                    Operator::Pipe => left | right,
+                    Operator::Caret => left ^ right,
+                    Operator::ShiftLeft => left.wrapping_shl(right as u32),
+                    Operator::ShiftRight => left.wrapping_shr(right as u32),
+                    Operator::Equal => (left == right) as i64,
+                    Operator::NotEqual => (left != right) as i64,
+                    Operator::Less => (left < right) as i64,
+                    Operator::LessEqual => (left <= right) as i64,
+                    Operator::Greater => (left > right) as i64,
+                    Operator::GreaterEqual => (left >= right) as i64,
+                    Operator::And => (left != 0 && right != 0) as i64,
+                    Operator::Or => (left != 0 || right != 0) as i64,
+                    Operator::Tilde | Operator::Bang => unreachable!("unary operators aren't parsed as binary")
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{lexer::Lexer, source::Source};
+
+    fn evaluate(text: &str) -> Result<i64, Message> {
+        let source = Rc::new(Source::new(text.to_string(), PathBuf::from("test.pasm")));
+        let (tokens, _) = Lexer::new(&source).lex().expect("expression should lex");
+        let tokens: Vec<Token> = tokens.into_iter()
+            .filter(|token| !matches!(token.value, TokenKind::NewLine))
+            .collect();
+
+        parse(&tokens)?.evaluate(&|symbol, span| match symbol {
+            "WIDTH" => Ok(16),
+            _ => Err(Message::error(format!("use of undeclared label: '{symbol}'"))
+                .with_id(Code::UndeclaredLabel)
+                .with_code(String::from("unknown label"), span.clone()))
+        })
+    }
+
+    #[test]
+    fn binds_tighter_operators_first() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(evaluate("1 << 2 + 1").unwrap(), 8);
+        assert_eq!(evaluate("1 | 2 & 3").unwrap(), 3);
+        assert_eq!(evaluate("1 + 1 == 2").unwrap(), 1);
+        assert_eq!(evaluate("0 || 1 && 1").unwrap(), 1);
+        assert_eq!(evaluate("6 ^ 3 & 1").unwrap(), 7);
+    }
+
+    #[test]
+    fn groups_left_to_right() {
+        assert_eq!(evaluate("10 - 4 - 3").unwrap(), 3);
+        assert_eq!(evaluate("64 / 4 / 2").unwrap(), 8);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(evaluate("10 - (4 - 3)").unwrap(), 9);
+    }
+
+    #[test]
+    fn applies_unary_operators() {
+        assert_eq!(evaluate("-2 * 3").unwrap(), -6);
+        assert_eq!(evaluate("~0").unwrap(), -1);
+        assert_eq!(evaluate("!0 + !5").unwrap(), 1);
+    }
+
+    #[test]
+    fn resolves_symbols() {
+        assert_eq!(evaluate("WIDTH * 2 - 1").unwrap(), 31);
+        assert!(matches!(evaluate("HEIGHT").unwrap_err().code, Some(Code::UndeclaredLabel)));
+    }
+
+    #[test]
+    fn rejects_division_by_zero() {
+        assert!(matches!(evaluate("1 / (2 - 2)").unwrap_err().code, Some(Code::DivisionByZero)));
+    }
+
+    #[test]
+    fn rejects_trailing_tokens() {
+        assert!(evaluate("1 2").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+    }
+}
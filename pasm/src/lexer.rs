@@ -1,6 +1,6 @@
 use std::{str::Chars, rc::Rc, num::IntErrorKind};
 
-use crate::{source::{Span, WithSpan, IntoWithSpan, Source}, message::{Message, Result}};
+use crate::{source::{Span, WithSpan, IntoWithSpan, Source}, message::{Message, Result}, code::Code, expression::Operator};
 
 trait IsValidWord {
     fn is_valid_word(&self) -> bool;
@@ -27,16 +27,55 @@ pub enum TokenKind {
     Colon,
     Percent,
     NewLine,
-    Backslash
+    Backslash,
+    LeftParen,
+    RightParen,
+    Operator(Operator),
+    /// `#`, stringifying a macro argument
+    Hash,
+    /// `##`, pasting two tokens in a macro body
+    HashHash,
+    /// `\@`, the number of the current macro expansion
+    Counter,
+    /// `=`, declaring a constant
+    Equals,
+    /// `$`, the address of the current statement, offset by `$+n` or `$-n`
+    Location(i16),
+    /// `:-` or `:+`, the nearest anonymous label before or after
+    AnonymousLabel(Direction)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Backward,
+    Forward
 }
 
 pub type Token = WithSpan<TokenKind>;
 
+/// A block of whole-line comments, such as the banner of a std routine,
+/// and the label declared after it.
+#[derive(Debug, Clone)]
+pub struct Doc {
+    pub label: WithSpan<String>,
+    /// the text of every comment line, without the `;`
+    pub lines: Vec<String>
+}
+
 pub struct Lexer<'a> {
     text: Chars<'a>,
     index: usize,
     current: Option<char>,
-    source: Rc<Source>
+    source: Rc<Source>,
+    /// whether no token has been made on the current line yet
+    line_start: bool,
+    /// whether the current line is a whole-line comment
+    commented: bool,
+    /// whether the previous line was a whole-line comment
+    after_comment: bool,
+    /// the latest comment block, waiting for a label
+    comment: Vec<String>,
+    docs: Vec<Doc>
 }
 
 impl<'a> Lexer<'a> {
@@ -45,32 +84,72 @@ impl<'a> Lexer<'a> {
             text: source.text.chars(),
             index: 0,
             current: None,
-            source: Rc::clone(&source)
+            source: Rc::clone(&source),
+            line_start: true,
+            commented: false,
+            after_comment: false,
+            comment: vec![],
+            docs: vec![]
         };
         out.advance();
         out
     }
 
+    /// Moves to the next character. `index` is a byte offset into the source.
     fn advance(&mut self) -> Option<char> {
-        if let Some(_) = self.current {
-            self.index += 1;
+        if let Some(current) = self.current {
+            self.index += current.len_utf8();
         }
         self.current = self.text.next();
         self.current
     }
 
+    /// Returns the span of the current character, or of the end of the file.
+    fn current_span(&self) -> Span {
+        let len = self.current.map(char::len_utf8).unwrap_or(1);
+
+        Span::new(self.index, self.index + len, Rc::clone(&self.source))
+    }
+
     fn make_word(&mut self) -> Result<Token> {
         let begin = self.index;
         let mut text = String::new();
 
-        while let Some(current) = self.current {
-            if !current.is_valid_word() {
-                break
-            }
+        // structured control flow such as `.if`
+        if self.current == Some('.') {
+            text.push('.');
+            self.advance();
+        }
 
-            text.push(current);
+        loop {
+            match self.current {
+                Some(current) if current.is_valid_word() => {
+                    text.push(current);
 
-            self.advance();
+                    self.advance();
+                },
+                // members of layouts such as `Snake.head`
+                Some('.') if text.chars().any(|current| current.is_valid_word()) && self.text.clone().next().is_some_and(|next| next.is_valid_word_begin()) => {
+                    text.push('.');
+
+                    self.advance();
+                },
+                // qualified names such as `printu::loop`
+                Some(':') => {
+                    let mut rest = self.text.clone();
+
+                    match (rest.next(), rest.next()) {
+                        (Some(':'), Some(next)) if next.is_valid_word_begin() => {
+                            text.push_str("::");
+
+                            self.advance();
+                            self.advance();
+                        },
+                        _ => break
+                    }
+                },
+                _ => break
+            }
         }
 
         let end = self.index;
@@ -100,9 +179,10 @@ impl<'a> Lexer<'a> {
                     'x' | 'h' => 16,
                     _   => return Err(vec![
                         Message::error(format!("'{}' is not a valid radix", current))
+                            .with_id(Code::InvalidNumber)
                             .with_code(
                                 String::from("invalid radix"),
-                                Span::new(self.index, self.index + 1, Rc::clone(&self.source))
+                                self.current_span()
                             )
                             .with_note(String::from("valid radixes are: 'b' and 'x'"))
                     ])
@@ -124,15 +204,19 @@ impl<'a> Lexer<'a> {
 
         let number = u8::from_str_radix(text.as_str(), base).map_err(|error| match error.kind() {
             IntErrorKind::Empty => vec![Message::error(format!("numbers must not be empty"))
+                .with_id(Code::InvalidNumber)
                 .with_code(String::from("empty number"), span.clone())
             ],
             IntErrorKind::InvalidDigit => vec![Message::error(format!("numbers must contain valid digits"))
+                .with_id(Code::InvalidNumber)
                 .with_code(String::from("includes invalid digits"), span.clone())
             ],
             IntErrorKind::PosOverflow => vec![Message::error(format!("integer overflow: {} can't fit in a byte", &text))
+                .with_id(Code::NumberOutOfRange)
                 .with_code(String::from("too big"), span.clone())
             ],
             IntErrorKind::NegOverflow => vec![Message::error(format!("negative integers are not suppored yet"))
+                .with_id(Code::NumberOutOfRange)
                 .with_code(String::from("unsupported sign"), span.clone())
             ],
             _ => unreachable!()
@@ -155,34 +239,104 @@ impl<'a> Lexer<'a> {
         ))
     }
 
-    fn escaped_char(&mut self) -> Option<char> {
-        match self.current {
-            Some('\\') => {
-                Some(match self.advance()? {
-                    'n' => '\n',
-                    character => character
-                })
-            },
-            _ => self.current
+    fn make_pair(&mut self, kind: TokenKind) -> Result<Token> {
+        let begin = self.index;
+        self.advance();
+        self.advance();
+        let end = self.index;
+
+        Ok((
+            kind.with_span(Span::new(begin, end, Rc::clone(&self.source))),
+            vec![]
+        ))
+    }
+
+    fn make_operator(&mut self) -> Option<Token> {
+        let begin = self.index;
+        let rest = self.text.clone();
+        let current = self.current?;
+
+        let operator = *Operator::ALL.iter().find(|operator| {
+            let mut text = operator.as_str().chars();
+            text.next() == Some(current) && text.eq(rest.clone().take(operator.as_str().len() - 1))
+        })?;
+
+        for _ in 0..operator.as_str().len() {
+            self.advance();
         }
+
+        Some(TokenKind::Operator(operator).with_span(Span::new(begin, self.index, Rc::clone(&self.source))))
+    }
+
+    /// Reads the character at `current`, unescaping `\n`, `\t`, `\0`, `\\`,
+    /// `\'`, `\"` and `\xNN`. `current` is left on the last character read.
+    fn escaped_char(&mut self) -> core::result::Result<Option<char>, Message> {
+        if self.current != Some('\\') {
+            return Ok(self.current);
+        }
+
+        let begin = self.index;
+
+        Ok(Some(match self.advance() {
+            None => return Ok(None),
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(character @ ('\\' | '\'' | '"')) => character,
+            Some('x') => {
+                let digits: String = self.text.clone().take(2).collect();
+                let byte = Some(&digits)
+                    .filter(|digits| digits.len() == 2 && digits.chars().all(|digit| digit.is_ascii_hexdigit()))
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+                let Some(byte) = byte else {
+                    return Err(Message::error(String::from("'\\x' must be followed by two hexadecimal digits"))
+                        .with_id(Code::InvalidEscape)
+                        .with_code(String::from("expected e.g. '\\x1b'"), Span::new(begin, self.index + 1, Rc::clone(&self.source))));
+                };
+
+                self.advance();
+                self.advance();
+
+                byte as char
+            },
+            Some(character) => return Err(Message::error(format!("unknown escape sequence: '\\{}'", character))
+                .with_id(Code::InvalidEscape)
+                .with_code(String::from("unknown escape"), Span::new(begin, self.index + character.len_utf8(), Rc::clone(&self.source)))
+                .with_note(String::from("valid escapes are '\\n', '\\t', '\\0', '\\\\', '\\'', '\\\"' and '\\xNN'")))
+        }))
     }
 
     fn make_character<'b>(&'b mut self) -> Result<Token> {
         let begin = self.index;
 
         self.advance();
-        let character = self.escaped_char().ok_or(vec![
+        let character = match self.escaped_char() {
+            Ok(character) => character,
+            Err(error) => {
+                // skip the rest of the literal
+                while !matches!(self.advance(), Some('\'' | '\n') | None) {}
+                if self.current == Some('\'') {
+                    self.advance();
+                }
+
+                return Err(vec![error]);
+            }
+        };
+        let character = character.ok_or(vec![
             Message::error(String::from("expected character, found end of file"))
+                .with_id(Code::InvalidCharacterLiteral)
                 .with_code(
                     String::from("expected character"),
-                    Span::new(self.index, self.index + 1, Rc::clone(&self.source))
+                    self.current_span()
                 )
         ])?;
 
         let character: u8 = character.try_into().map_err(|_| vec![Message::error(format!("characters must fit in a byte"))
+            .with_id(Code::InvalidCharacterLiteral)
             .with_code(
                 String::from("does not fit in a byte"),
-                Span::new(self.index, self.index + 1, Rc::clone(&self.source))
+                self.current_span()
             )
         ])?;
 
@@ -192,34 +346,50 @@ impl<'a> Lexer<'a> {
                 let end = self.index;
 
                 Ok((
-                    TokenKind::Character(character.try_into().unwrap()).with_span(Span::new(begin, end, Rc::clone(&self.source))),
+                    TokenKind::Character(character).with_span(Span::new(begin, end, Rc::clone(&self.source))),
                     vec![]
                 ))
             }
-            _ => todo!()
+            _ => Err(vec![
+                Message::error(String::from("character literals must be closed"))
+                    .with_id(Code::InvalidCharacterLiteral)
+                    .with_code(
+                        String::from("expected '''"),
+                        self.current_span()
+                    )
+                    .with_note(String::from("a character literal holds a single character, use \"...\" for strings"))
+            ])
         }
     }
 
     fn make_string(&mut self) -> Result<Token> {
         let begin = self.index;
         let mut text = String::new();
+        let mut errors = vec![];
 
         self.advance();
 
         while self.current != Some('"') {
             text.push(match self.escaped_char() {
-                Some(character) => {
+                Ok(Some(character)) => {
                     self.advance();
                     character
                 },
-                None => {
+                Err(error) => {
+                    self.advance();
+                    errors.push(error);
+
+                    continue;
+                },
+                Ok(None) => {
                     self.advance();
 
                     return Err(vec![
                         Message::error(String::from("strings must be closed"))
+                            .with_id(Code::UnterminatedString)
                             .with_code(
                                 String::from("expected '\"'"),
-                                Span::new(self.index, self.index + 1, Rc::clone(&self.source))
+                                self.current_span()
                             )
                     ])
                 }
@@ -229,11 +399,15 @@ impl<'a> Lexer<'a> {
         self.advance();
         let end = self.index;
 
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
         Ok((TokenKind::String(text).with_span(Span::new(begin, end, Rc::clone(&self.source))), vec![]))
     }
 
     fn chop_whitespace(&mut self) {
-        while let Some(' ') = self.current {
+        while let Some(' ' | '\t' | '\r') = self.current {
             self.advance();
         }
     }
@@ -242,16 +416,60 @@ impl<'a> Lexer<'a> {
         self.chop_whitespace();
 
         if let Some(';') = self.current {
+            let mut text = String::new();
+
             loop {
                 match self.advance() {
                     Some('\n') | None => break,
-                    _ => ()
+                    Some('\r') => (),
+                    Some(current) => text.push(current)
                 }
             }
             self.chop_whitespace();
+
+            if self.line_start {
+                if !self.after_comment {
+                    self.comment.clear();
+                }
+
+                self.comment.push(text);
+                self.commented = true;
+            }
         }
     }
 
+    /// Attaches the pending comment block to `token` if it declares a label.
+    /// Directives between the two are skipped over, anything else drops it.
+    fn attach_doc(&mut self, token: &Token) {
+        match &token.value {
+            TokenKind::NewLine => {
+                self.after_comment = self.commented;
+                self.commented = false;
+                self.line_start = true;
+
+                return;
+            },
+            _ if !self.line_start => return,
+            TokenKind::Percent => (),
+            TokenKind::Word(word) if self.current == Some(':') && !matches!(self.text.clone().next(), Some('-' | '+')) => {
+                if !self.comment.is_empty() {
+                    self.docs.push(Doc {
+                        label: word.clone().with_span(token.span.clone()),
+                        lines: std::mem::take(&mut self.comment)
+                    });
+                }
+            },
+            _ => self.comment.clear()
+        }
+
+        self.line_start = false;
+    }
+
+    /// Returns the comment blocks attached to labels lexed so far.
+    pub fn docs(&self) -> &[Doc] {
+        &self.docs
+    }
+
     pub fn lex(&mut self) -> Result<Vec<Token>> {
         let mut errors = vec![];
         let mut warnings = vec![];
@@ -282,7 +500,7 @@ impl<'a> Iterator for Lexer<'a> {
         self.chop_whitespace_and_comments();
 
         let current = self.current?;
-        Some({
+        let token = {
             if current.is_valid_word_begin() {
                 self.make_word()
             } else if current.is_numeric() {
@@ -292,21 +510,99 @@ impl<'a> Iterator for Lexer<'a> {
                     '"'  => self.make_string(),
                     '%'  => self.make_singleton(TokenKind::Percent),
                     ','  => self.make_singleton(TokenKind::Comma),
+                    ':'  if self.text.clone().next() == Some('-') => self.make_pair(TokenKind::AnonymousLabel(Direction::Backward)),
+                    ':'  if self.text.clone().next() == Some('+') => self.make_pair(TokenKind::AnonymousLabel(Direction::Forward)),
                     ':'  => self.make_singleton(TokenKind::Colon),
+                    '$'  => self.make_singleton(TokenKind::Location(0)),
+                    '.'  if self.text.clone().next().is_some_and(|next| next.is_valid_word_begin()) => self.make_word(),
                     '\n' => self.make_singleton(TokenKind::NewLine),
+                    '\\' if self.text.clone().next() == Some('@') => self.make_pair(TokenKind::Counter),
                     '\\' => self.make_singleton(TokenKind::Backslash),
+                    '#'  if self.text.clone().next() == Some('#') => self.make_pair(TokenKind::HashHash),
+                    '#'  => self.make_singleton(TokenKind::Hash),
+                    '='  if self.text.clone().next() != Some('=') => self.make_singleton(TokenKind::Equals),
+                    '('  => self.make_singleton(TokenKind::LeftParen),
+                    ')'  => self.make_singleton(TokenKind::RightParen),
                     '\'' => self.make_character(),
-                    _    => {
-                        let span = Span::new(self.index, self.index + 1, Rc::clone(&self.source));
-                        self.advance();
-
-                        Err(vec![
-                            Message::error(format!("'{}' is not a valid character", current))
-                                .with_code(String::from("illegal character"), span)
-                        ])
+                    _    => match self.make_operator() {
+                        Some(operator) => Ok((operator, vec![])),
+                        None => {
+                            let span = self.current_span();
+                            self.advance();
+
+                            Err(vec![
+                                Message::error(format!("'{}' is not a valid character", current))
+                                    .with_id(Code::IllegalCharacter)
+                                    .with_code(String::from("illegal character"), span)
+                            ])
+                        }
                     }
                 }
             }
-        })
+        };
+
+        if let Ok((token, _)) = &token {
+            self.attach_doc(token);
+        }
+
+        Some(token)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn lex(text: &str) -> Result<Vec<Token>> {
+        Lexer::new(&Rc::new(Source::new(text.to_string(), PathBuf::from("test.pasm")))).lex()
+    }
+
+    fn kinds(text: &str) -> Vec<TokenKind> {
+        match lex(text) {
+            Ok((tokens, _)) => tokens.into_iter().map(|token| token.value).collect(),
+            Err(errors) => panic!("source doesn't lex: {:?}", errors)
+        }
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        match lex(text) {
+            Ok(_) => panic!("source shouldn't lex"),
+            Err(errors) => errors.into_iter().filter_map(|error| error.code).collect()
+        }
+    }
+
+    fn word(word: &str) -> TokenKind {
+        TokenKind::Word(String::from(word))
+    }
+
+    #[test]
+    fn treats_tabs_and_carriage_returns_as_whitespace() {
+        assert_eq!(kinds("\tput rx,\t1\r\n hlt ; done\r\n"), [
+            word("put"), word("rx"), TokenKind::Comma, TokenKind::Number(1), TokenKind::NewLine,
+            word("hlt"), TokenKind::NewLine
+        ]);
+    }
+
+    #[test]
+    fn unescapes_characters() {
+        assert_eq!(kinds(r#"'\n' '\t' '\0' '\\' '\'' '\"' '\x1b' '"'"#), [
+            TokenKind::Character(b'\n'), TokenKind::Character(b'\t'), TokenKind::Character(0),
+            TokenKind::Character(b'\\'), TokenKind::Character(b'\''), TokenKind::Character(b'"'),
+            TokenKind::Character(0x1b), TokenKind::Character(b'"')
+        ]);
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        assert_eq!(kinds(r#""a\tb\x41\"\\'""#), [TokenKind::String(String::from("a\tbA\"\\'"))]);
+    }
+
+    #[test]
+    fn rejects_invalid_escapes() {
+        assert!(matches!(codes(r"'\e'")[..], [Code::InvalidEscape]));
+        assert!(matches!(codes(r"'\x1'")[..], [Code::InvalidEscape]));
+        assert!(matches!(codes(r#""\xzz""#)[..], [Code::InvalidEscape]));
+        assert!(matches!(codes("`")[..], [Code::IllegalCharacter]));
+    }
+}
@@ -1,10 +1,18 @@
 pub mod source;
-mod next_n;
+mod suggestion;
 mod signature;
 pub mod lexer;
+pub mod expression;
 pub mod preprocessor;
 pub mod parser;
 pub mod compiler;
+mod cfg;
+mod analysis;
+mod module;
+mod control;
+pub mod doc;
 pub mod message;
+pub mod code;
+pub mod lint;
 
 pub use signature::format_instruction_code;
\ No newline at end of file
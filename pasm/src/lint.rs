@@ -0,0 +1,217 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{code::Code, message::{Message, MessageKind}, source::Span, suggestion::did_you_mean};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny
+}
+
+impl Level {
+    fn flag(&self) -> &'static str {
+        match self {
+            Level::Allow => "--allow",
+            Level::Warn => "--warn",
+            Level::Deny => "--deny"
+        }
+    }
+}
+
+/// An `%allow(...)` directive, in effect from `begin` to `end` in the file
+/// at `path`. Directives in `%ifndef` blocks end with the block.
+#[derive(Debug, Clone)]
+pub struct Allow {
+    pub lint: Code,
+    pub path: PathBuf,
+    pub begin: usize,
+    pub end: usize
+}
+
+impl Allow {
+    fn covers(&self, span: &Span) -> bool {
+        span.source.path == self.path && (self.begin..self.end).contains(&span.begin)
+    }
+}
+
+/// Looks up a lint by name, reporting unknown names at `span` if given.
+pub fn lookup(name: &str, span: Option<Span>) -> Result<Code, Message> {
+    Code::from_lint(name).ok_or_else(|| {
+        let mut message = Message::error(format!("unknown lint: '{}'", name))
+            .with_id(Code::UnknownLint);
+
+        if let Some(span) = span {
+            message = message.with_code(String::from("unknown lint"), span);
+        }
+        if let Some(note) = did_you_mean(name, Code::ALL.iter().filter_map(Code::lint)) {
+            message = message.with_note(note);
+        }
+
+        message
+    })
+}
+
+/// Levels of the lints, as configured on the command line.
+#[derive(Debug)]
+pub struct Lints {
+    levels: HashMap<Code, Level>,
+    default: Level,
+    pub deny_warnings: bool
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Self {
+            levels: HashMap::new(),
+            default: Level::Warn,
+            deny_warnings: false
+        }
+    }
+}
+
+impl Lints {
+    /// Sets the level of the lint `name`, or of every lint for `warnings`.
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), Message> {
+        if name == "warnings" {
+            self.levels.clear();
+            self.default = level;
+        } else {
+            self.levels.insert(lookup(name, None)?, level);
+        }
+
+        Ok(())
+    }
+
+    fn level(&self, code: Code) -> Level {
+        self.levels.get(&code).copied().unwrap_or(self.default)
+    }
+
+    /// Drops allowed warnings and turns denied ones into errors, returning
+    /// the remaining warnings and the errors. `%allow` in the source takes
+    /// precedence over the command line.
+    pub fn apply(&self, warnings: Vec<Message>, allows: &[Allow]) -> (Vec<Message>, Vec<Message>) {
+        let mut remaining = vec![];
+        let mut errors = vec![];
+
+        for mut warning in warnings {
+            let Some(lint) = warning.code.and_then(|code| code.lint().map(|lint| (code, lint))) else {
+                match self.deny_warnings {
+                    true => {
+                        warning.kind = MessageKind::Error;
+                        errors.push(warning.with_note(String::from("warnings are denied by '--deny-warnings'")))
+                    },
+                    false => remaining.push(warning)
+                }
+
+                continue
+            };
+
+            let allowed = warning.span()
+                .map(|span| allows.iter().any(|allow| allow.lint == lint.0 && allow.covers(span)))
+                .unwrap_or(false);
+
+            match (self.level(lint.0), allowed) {
+                (Level::Allow, _) | (_, true) => (),
+                (Level::Warn, false) if !self.deny_warnings => remaining.push(warning),
+                (level, false) => {
+                    let note = match (level, self.levels.contains_key(&lint.0)) {
+                        (Level::Deny, true) => format!("'{}' is denied by '{} {}'", lint.1, level.flag(), lint.1),
+                        (Level::Deny, false) => format!("'{}' is denied by '{} warnings'", lint.1, level.flag()),
+                        _ => format!("'{}' is denied by '--deny-warnings'", lint.1)
+                    };
+
+                    warning.kind = MessageKind::Error;
+                    errors.push(warning.with_note(note));
+                }
+            }
+        }
+
+        (remaining, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::source::Source;
+
+    fn span(begin: usize) -> Span {
+        Span::new(begin, begin + 1, Rc::new(Source::new(String::from("abcdefgh\n"), PathBuf::from("test.pasm"))))
+    }
+
+    fn warning(code: Code, begin: usize) -> Message {
+        Message::warning(String::from("test")).with_id(code).with_code(String::from("here"), span(begin))
+    }
+
+    fn codes(messages: &[Message]) -> Vec<Option<Code>> {
+        messages.iter().map(|message| message.code).collect()
+    }
+
+    #[test]
+    fn warnings_are_kept_by_default() {
+        let (warnings, errors) = Lints::default().apply(vec![warning(Code::UnusedLabel, 0), warning(Code::UnreachableCode, 1)], &[]);
+
+        assert_eq!(codes(&warnings), [Some(Code::UnusedLabel), Some(Code::UnreachableCode)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn allowed_lints_are_dropped_and_denied_ones_are_errors() {
+        let mut lints = Lints::default();
+        lints.set("unused_label", Level::Allow).unwrap();
+        lints.set("unreachable_code", Level::Deny).unwrap();
+
+        let (warnings, errors) = lints.apply(vec![warning(Code::UnusedLabel, 0), warning(Code::UnreachableCode, 1), warning(Code::FallIntoData, 2)], &[]);
+
+        assert_eq!(codes(&warnings), [Some(Code::FallIntoData)]);
+        assert_eq!(codes(&errors), [Some(Code::UnreachableCode)]);
+        assert!(matches!(errors[0].kind, MessageKind::Error));
+    }
+
+    #[test]
+    fn later_levels_override_earlier_ones() {
+        let mut lints = Lints::default();
+        lints.set("unused_label", Level::Deny).unwrap();
+        lints.set("warnings", Level::Allow).unwrap();
+        lints.set("fall_into_data", Level::Warn).unwrap();
+
+        let (warnings, errors) = lints.apply(vec![warning(Code::UnusedLabel, 0), warning(Code::FallIntoData, 1)], &[]);
+
+        assert_eq!(codes(&warnings), [Some(Code::FallIntoData)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn deny_warnings_denies_every_warning() {
+        let lints = Lints { deny_warnings: true, ..Lints::default() };
+        let uncoded = Message::warning(String::from("test"));
+
+        let (warnings, errors) = lints.apply(vec![warning(Code::UnusedLabel, 0), uncoded], &[]);
+
+        assert!(warnings.is_empty());
+        assert_eq!(codes(&errors), [Some(Code::UnusedLabel), None]);
+    }
+
+    #[test]
+    fn allow_directives_cover_their_range() {
+        let mut lints = Lints::default();
+        lints.set("unused_label", Level::Deny).unwrap();
+        let allows = [Allow { lint: Code::UnusedLabel, path: PathBuf::from("test.pasm"), begin: 2, end: 4 }];
+
+        let (warnings, errors) = lints.apply(vec![warning(Code::UnusedLabel, 1), warning(Code::UnusedLabel, 2), warning(Code::UnusedLabel, 4)], &allows);
+
+        assert!(warnings.is_empty());
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors.iter().filter_map(|error| error.span()).map(|span| span.begin).collect::<Vec<_>>(), [1, 4]);
+    }
+
+    #[test]
+    fn unknown_lints_are_rejected() {
+        let error = Lints::default().set("unused_labels", Level::Allow).unwrap_err();
+
+        assert_eq!(error.code, Some(Code::UnknownLint));
+    }
+}
@@ -1,22 +1,309 @@
-use std::{fs, path::PathBuf, io};
+use std::{fs::{self, File}, path::{PathBuf, Path}, io::{self, Write, IsTerminal, stdout}, process::{self, ExitCode}, thread, time::{Duration, SystemTime}};
 
-use clap::{command, arg, crate_version};
-use pasm::{compiler::compile, source::Source, message::{Message, human_count}};
+use clap::{command, arg, crate_version, value_parser, ArgAction, ArgMatches, Command};
+use itertools::Itertools;
+use pasm::{compiler::{compile, preprocess, Options, Define}, doc::{document, Format}, source::Source, message::{Message, ColorChoice, human_count}, code::Code, lint::{Lints, Level}, preprocessor::{DEFAULT_MAX_DEPTH, write_source}};
 
-fn main() {
+/// Reads the lint flags, later ones overriding earlier ones.
+fn lints(matches: &ArgMatches) -> Result<Lints, Message> {
+    let mut lints = Lints::default();
+    lints.deny_warnings = matches.get_flag("deny-warnings");
+
+    let flags = [("allow", Level::Allow), ("warn", Level::Warn), ("deny", Level::Deny)].into_iter()
+        .flat_map(|(id, level)| matches.indices_of(id).into_iter().flatten()
+            .zip(matches.get_many::<String>(id).into_iter().flatten())
+            .map(move |(index, name)| (index, name, level))
+        )
+        .sorted_by_key(|(index, ..)| *index);
+
+    for (_, name, level) in flags {
+        lints.set(name, level)?;
+    }
+
+    Ok(lints)
+}
+
+/// Reads the `-D` and `-U` flags in the order they were given.
+fn defines(matches: &ArgMatches) -> Vec<Define> {
+    ["define", "undefine"].into_iter()
+        .flat_map(|id| matches.indices_of(id).into_iter().flatten()
+            .zip(matches.get_many::<String>(id).into_iter().flatten())
+            .map(move |(index, argument)| (index, match id {
+                "define" => match argument.split_once('=') {
+                    Some((name, value)) => Define::Set { name: name.to_string(), value: Some(value.to_string()) },
+                    None => Define::Set { name: argument.clone(), value: None }
+                },
+                _ => Define::Unset(argument.clone())
+            }))
+        )
+        .sorted_by_key(|(index, _)| *index)
+        .map(|(_, define)| define)
+        .collect()
+}
+
+fn report<T: Write>(errors: &[Message], input_path: &Path, mut out: T) {
+    for error in errors {
+        error.format(&mut out)
+    }
+    Message::error(format!("could not compile '{}' due to previous {}",
+        input_path.display(), human_count("error", errors.len())
+    )).format(&mut out);
+
+    let codes: Vec<String> = errors.iter()
+        .filter_map(|error| error.code)
+        .unique()
+        .map(|code| code.to_string())
+        .collect();
+    match codes.as_slice() {
+        [] => (),
+        [code] => writeln!(out, "For more information about this error, try `pasm --explain {}`.", code).unwrap(),
+        codes => writeln!(out, "Some errors have detailed explanations: {}.\nFor more information about an error, try `pasm --explain {}`.",
+            codes.join(", "), codes[0]
+        ).unwrap()
+    }
+}
+
+/// How often `--watch` checks the opened files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Reads the input file, reporting to `out` if it can't be read.
+fn read<T: Write>(input_path: &Path, out: T) -> Option<Source> {
+    match fs::read_to_string(input_path) {
+        Ok(text) => Some(Source::new(text, input_path.to_path_buf())),
+        Err(error) => {
+            Message::error(format!("could not read '{}': {}", input_path.display(), error))
+                .with_id(Code::FileNotFound)
+                .format(out);
+            None
+        }
+    }
+}
+
+/// Reads and assembles `input_path`, printing the diagnostics to `out`.
+/// Returns whether the image was written.
+fn build<T: Write>(input_path: &Path, options: &Options, mut out: T) -> bool {
+    let Some(source) = read(input_path, &mut out) else {
+        return false;
+    };
+
+    match compile(source, options) {
+        Ok(((), warnings)) => {
+            for warning in warnings {
+                warning.format(&mut out)
+            }
+
+            true
+        },
+        Err(errors) => {
+            report(&errors, input_path, &mut out);
+            false
+        }
+    }
+}
+
+/// Runs `command` through the shell, as given to `--then`.
+fn run(command: &str) -> Result<(), Message> {
+    let status = match cfg!(windows) {
+        true => process::Command::new("cmd").arg("/C").arg(command).status(),
+        false => process::Command::new("sh").arg("-c").arg(command).status()
+    };
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Message::error(format!("'{}' failed: {}", command, status)).with_id(Code::CommandFailed)),
+        Err(error) => Err(Message::error(format!("could not run '{}': {}", command, error)).with_id(Code::CommandFailed))
+    }
+}
+
+/// Assembles `input_path` whenever it or a file the preprocessor opened for
+/// it changes, running `then` after every successful build. Never returns
+/// unless interrupted.
+fn watch(input_path: &Path, options: &Options, then: Option<&String>) -> ExitCode {
+    let mut stdout = io::stdout();
+
+    loop {
+        // every build starts on a clean screen
+        if stdout.is_terminal() {
+            print!("\x1b[2J\x1b[H");
+        }
+
+        options.opened.borrow_mut().clear();
+
+        if build(input_path, options, &mut stdout) {
+            if let Some(Err(error)) = then.map(|command| run(command)) {
+                error.format(&mut stdout);
+            }
+        }
+
+        let files: Vec<(PathBuf, Option<SystemTime>)> = std::iter::once(input_path.to_path_buf())
+            .chain(options.opened.borrow().iter().cloned())
+            .unique()
+            .map(|path| {
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+                (path, modified)
+            })
+            .collect();
+
+        println!("watching {} for changes...", human_count("file", files.len()));
+
+        // files that are missing are watched for their creation
+        while files.iter().all(|(path, modified)| fs::metadata(path).and_then(|metadata| metadata.modified()).ok() == *modified) {
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+}
+
+/// Runs `pasm doc`, writing a reference of the exported routines.
+fn doc(matches: &ArgMatches) -> ExitCode {
+    let mut stderr = io::stderr();
+
+    let output = matches.get_one::<String>("output");
+    let format = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("html") => Format::Html,
+        Some(_) => Format::Markdown,
+        None => match output.map(|output| Path::new(output).extension().is_some_and(|extension| extension == "html" || extension == "htm")) {
+            Some(true) => Format::Html,
+            _ => Format::Markdown
+        }
+    };
+
+    let mut sources = vec![];
+    for input in matches.get_many::<String>("input").into_iter().flatten() {
+        match fs::read_to_string(input) {
+            Ok(text) => sources.push(Source::new(text, PathBuf::from(input))),
+            Err(error) => {
+                Message::error(format!("could not read '{}': {}", input, error))
+                    .with_id(Code::FileNotFound)
+                    .format(&mut stderr);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let options = Options {
+        output_path: PathBuf::new(),
+        image_size: None,
+        verbose: false,
+        check_stack: false,
+        cfg_path: None,
+        lints: Lints::default(),
+        macro_depth: DEFAULT_MAX_DEPTH,
+        defines: defines(matches),
+        opened: Default::default()
+    };
+
+    match document(sources, &options, format) {
+        Ok((text, warnings)) => {
+            for warning in warnings {
+                warning.format(&mut stderr)
+            }
+
+            let written = match output {
+                Some(output) => fs::write(output, text),
+                None => stdout().lock().write_all(text.as_bytes())
+            };
+
+            match written {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(error) => {
+                    Message::error(format!("could not write documentation: {}", error))
+                        .with_id(Code::OutputError)
+                        .format(&mut stderr);
+                    ExitCode::FAILURE
+                }
+            }
+        },
+        Err(errors) => {
+            for error in errors {
+                error.format(&mut stderr)
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let mut stdout = io::stdout();
 
     let matches = command!()
         .about("Assembler for the POC-8 computer architecture")
         .version(crate_version!())
 
-        .arg(arg!(                     <input>                             "Input file"))
+        .arg(arg!(                     [input]                             "Input file")
+            .required_unless_present("explain"))
         .arg(arg!(-o        --output    <FILE>                            "Output file"))
         .arg(arg!(-s  --"image-size"    <SIZE>  "The size of the output image in bytes"))
         .arg(arg!(-v        --verbose                        "Toggle verbose reporting"))
-        
+        .arg(arg!(         --"check-stack"   "Warn about unbalanced 'psh'/'pop' and 'ret'"))
+        .arg(arg!(         --cfg      <FILE>    "Write the control-flow graph as Graphviz DOT"))
+        .arg(arg!(         --explain  <CODE>    "Explain a diagnostic code, e.g. P0012"))
+        .arg(arg!(-A        --allow     <LINT>             "Silence a lint, or all warnings")
+            .action(ArgAction::Append))
+        .arg(arg!(-W        --warn      <LINT>                "Report a lint as a warning")
+            .action(ArgAction::Append))
+        .arg(arg!(          --deny      <LINT>               "Report a lint as an error (no -D, which defines symbols)")
+            .action(ArgAction::Append))
+        .arg(arg!(         --"deny-warnings"        "Report every warning as an error"))
+        .arg(arg!(         --"macro-depth" <DEPTH>   "How deeply macros may expand [default: 64]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(-E        --preprocess    "Only preprocess, printing the result as source"))
+        .arg(arg!(-D        --define   <SYMBOL>    "Define a symbol, as NAME or NAME=VALUE")
+            .action(ArgAction::Append))
+        .arg(arg!(-U        --undefine   <NAME>                  "Undefine a symbol")
+            .action(ArgAction::Append))
+        .arg(arg!(-w        --watch       "Re-assemble whenever an opened file changes")
+            .conflicts_with_all(["preprocess", "explain"]))
+        .arg(arg!(          --then     <COMMAND>    "Run a command after each successful build")
+            .requires("watch"))
+        .arg(arg!(          --color     <WHEN>      "Colour diagnostics: auto, always or never")
+            .value_parser(["auto", "always", "never"])
+            .default_value("auto")
+            .global(true))
+
+        .subcommand(Command::new("doc")
+            .about("Render a reference of the routines exported by pasm libraries")
+            .arg(arg!(                 <input>...                     "Input files"))
+            .arg(arg!(-o        --output    <FILE>   "Output file [default: stdout]"))
+            .arg(arg!(          --format  <FORMAT>   "Output format [default: from the output file]")
+                .value_parser(["markdown", "html"]))
+            .arg(arg!(-D        --define   <SYMBOL>    "Define a symbol, as NAME or NAME=VALUE")
+                .action(ArgAction::Append))
+            .arg(arg!(-U        --undefine   <NAME>                  "Undefine a symbol")
+                .action(ArgAction::Append)))
+        .args_conflicts_with_subcommands(true)
+
         .get_matches();
 
+    matches.get_one::<String>("color")
+        .and_then(|color| ColorChoice::from_name(color))
+        .unwrap_or(ColorChoice::Auto)
+        .apply();
+
+    if let Some(("doc", matches)) = matches.subcommand() {
+        return doc(matches);
+    }
+
+    if let Some(code) = matches.get_one::<String>("explain") {
+        return match Code::from_id(code) {
+            Some(code) => {
+                println!("{}", code.explanation());
+                ExitCode::SUCCESS
+            },
+            None => {
+                Message::error(format!("'{}' is not a valid diagnostic code", code)).format(&mut stdout);
+                ExitCode::FAILURE
+            }
+        }
+    }
+
+    let lints = match lints(&matches) {
+        Ok(lints) => lints,
+        Err(error) => {
+            error.format(&mut stdout);
+            return ExitCode::FAILURE;
+        }
+    };
+
     let input_path = PathBuf::from(matches.get_one::<String>("input")
         .expect("Input should be present"));
     let output_path = match matches.get_one::<String>("output") {
@@ -27,26 +314,94 @@ fn main() {
             output
         }
     };
-    let image_size = matches.get_one::<String>("image-size")
-        .map(|image_size| image_size.parse::<usize>().unwrap());
+    let image_size = match matches.get_one::<String>("image-size").map(|image_size| image_size.parse::<usize>().map_err(|_| image_size)) {
+        Some(Ok(image_size)) => Some(image_size),
+        Some(Err(image_size)) => {
+            Message::error(format!("invalid image size: '{}' is not a number of bytes", image_size))
+                .with_id(Code::InvalidNumber)
+                .format(&mut stdout);
+            return ExitCode::FAILURE;
+        },
+        None => None
+    };
     let verbose = matches.get_flag("verbose");
 
-    let source = Source {
-        text: fs::read_to_string(&input_path).unwrap(),
-        path: input_path.clone()
+    let options = Options {
+        output_path,
+        image_size,
+        verbose,
+        check_stack: matches.get_flag("check-stack"),
+        cfg_path: matches.get_one::<String>("cfg").map(PathBuf::from),
+        lints,
+        macro_depth: matches.get_one::<usize>("macro-depth").copied().unwrap_or(DEFAULT_MAX_DEPTH),
+        defines: defines(&matches),
+        opened: Default::default()
     };
 
-    match compile(source, output_path, image_size, verbose) {
-        Ok(((), warnings)) => for warning in warnings {
-            warning.format(&mut stdout)
-        },
-        Err(errors) => {
-            for error in &errors {
-                error.format(&mut stdout)
+    if matches.get_flag("preprocess") {
+        // diagnostics go to stderr so they don't mix with the source
+        let mut stderr = io::stderr();
+
+        let Some(source) = read(&input_path, &mut stderr) else {
+            return ExitCode::FAILURE;
+        };
+
+        return match preprocess(source, &options) {
+            Ok((scope, warnings)) => {
+                for warning in warnings {
+                    warning.format(&mut stderr)
+                }
+
+                let written = match matches.get_one::<String>("output") {
+                    Some(output) => File::create(output).and_then(|file| write_source(&scope.tokens, file)),
+                    None => write_source(&scope.tokens, stdout.lock())
+                };
+
+                match written {
+                    Ok(()) => ExitCode::SUCCESS,
+                    Err(error) => {
+                        Message::error(format!("could not write preprocessed source: {}", error))
+                            .with_id(Code::OutputError)
+                            .format(&mut stderr);
+                        ExitCode::FAILURE
+                    }
+                }
+            },
+            Err(errors) => {
+                report(&errors, &input_path, &mut stderr);
+                ExitCode::FAILURE
             }
-            Message::error(format!("could not compile '{}' due to previous {}",
-                input_path.display(), human_count("error", errors.len())
-            )).format(&mut stdout);
         }
     }
-}
\ No newline at end of file
+
+    if matches.get_flag("watch") {
+        return watch(&input_path, &options, matches.get_one::<String>("then"));
+    }
+
+    match build(&input_path, &options, &mut stdout) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_failed_commands() {
+        assert!(run("exit 0").is_ok());
+        assert!(matches!(run("exit 3"), Err(Message { code: Some(Code::CommandFailed), .. })));
+    }
+
+    #[test]
+    fn reports_unreadable_inputs() {
+        let mut out = vec![];
+        let path = std::env::temp_dir().join(format!("pasm-missing-{}.pasm", process::id()));
+
+        ColorChoice::Never.apply();
+        assert!(!build(&path, &Options::default(), &mut out));
+        assert!(String::from_utf8(out).unwrap().contains("[P0008]: could not read"));
+    }
+}
+
@@ -1,7 +1,60 @@
-use std::io::Write;
+use std::{io::Write, rc::Rc};
 
-use crate::{source::Span, next_n::NextN};
+use itertools::Itertools;
+
+use crate::{source::Span, code::Code};
 use colored::*;
+use unicode_width::UnicodeWidthChar;
+
+/// The columns a tab in a source line is drawn as.
+const TAB_WIDTH: usize = 4;
+
+/// The most rows of a multi-line span drawn before the middle ones are elided.
+const MAX_ROWS: usize = 4;
+
+/// Returns the columns `text` takes up in a terminal, counting wide
+/// characters twice and expanding tabs.
+fn width(text: &str) -> usize {
+    text.chars()
+        .map(|ch| match ch {
+            '\t' => TAB_WIDTH,
+            _ => ch.width().unwrap_or(0)
+        })
+        .sum()
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+/// Whether diagnostics are coloured, as given by `--color`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// colour when writing to a terminal, unless `NO_COLOR` is set
+    Auto,
+    Always,
+    Never
+}
+
+impl ColorChoice {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None
+        }
+    }
+
+    /// Applies the choice to every message formatted after.
+    pub fn apply(&self) {
+        match self {
+            Self::Auto => colored::control::unset_override(),
+            Self::Always => colored::control::set_override(true),
+            Self::Never => colored::control::set_override(false)
+        }
+    }
+}
 
 #[derive(Debug)]
 struct CodeSnippet {
@@ -11,30 +64,105 @@ struct CodeSnippet {
 }
 
 impl CodeSnippet {
+    /// Returns the number, beginning and text of every row the span covers,
+    /// without their line endings.
+    fn rows(&self) -> Vec<(usize, usize, &str)> {
+        let source = &self.span.source;
+        let first = source.line_index(self.span.begin);
+        let last = source.line_index(self.span.end.max(self.span.begin + 1) - 1);
+
+        (first..=last)
+            .map(|line| (line + 1, source.line_begin(line), source.line(line)))
+            .collect()
+    }
+
     fn line_num_len(&self) -> usize {
-        self.span.row_num().to_string().len()
+        self.rows().last()
+            .map(|(row, ..)| row.to_string().len())
+            .unwrap_or(1)
+    }
+
+    /// Splits `line`, which begins at the byte offset `begin`, into the text
+    /// before, inside and after the span.
+    fn split<'a>(&self, line: &'a str, begin: usize) -> (&'a str, &'a str, &'a str) {
+        let start = self.span.begin.saturating_sub(begin).min(line.len());
+        let end = self.span.end.saturating_sub(begin).clamp(start, line.len());
+
+        (&line[..start], &line[start..end], &line[end..])
     }
 
     fn format<T: Write>(&self, mut out: T, pad_line_num: usize) {
-        let row = self.span.row_num().to_string();
-        let line_num_len = row.len();
+        let rows = self.rows();
+        let row = self.span.row_num();
         let col = self.span.col_num();
         let row_len = pad_line_num + 2;
-        let mut line = self.span.row();
-        let before = line.next_n::<String>(col - 1);
-        let error = line.next_n::<String>(self.span.end - self.span.begin);
-        let after = line.collect::<String>();
-
-        writeln!(out, "{} {}{}{}\n{}{}",
-            format!("{}╮┄┄ {}:{}:{}\n {}{} │",
-                "─".repeat(row_len),
-                self.span.source.path.display(), &row, col,
-                " ".repeat(pad_line_num - line_num_len), &row
-            ).bright_black(),
-            before, (&error).color(self.color), after,
-            format!("{}╯ {}", "─".repeat(row_len), " ".repeat(before.len())).bright_black(),
-            format!("╰{} {}", "─".repeat(error.len() + 1), &self.description).color(self.color),
-        ).unwrap();
+        let gutter = |row: Option<usize>| {
+            let row = row.map(|row| row.to_string()).unwrap_or_default();
+            format!(" {}{} │", " ".repeat(pad_line_num - row.len()), row).bright_black()
+        };
+
+        writeln!(out, "{}", format!("{}╮┄┄ {}:{}:{}",
+            "─".repeat(row_len),
+            self.span.source.path.display(), row, col
+        ).bright_black()).unwrap();
+
+        match rows.as_slice() {
+            [(row, begin, line)] => {
+                let (before, error, after) = self.split(line, *begin);
+
+                writeln!(out, "{} {}{}{}\n{}{}",
+                    gutter(Some(*row)),
+                    expand_tabs(before), expand_tabs(error).color(self.color), expand_tabs(after),
+                    format!("{}╯ {}", "─".repeat(row_len), " ".repeat(width(before))).bright_black(),
+                    format!("╰{} {}", "─".repeat(width(error) + 1), &self.description).color(self.color),
+                ).unwrap();
+            },
+            _ => {
+                let mut last = "";
+
+                for (i, (row, begin, line)) in rows.iter().enumerate() {
+                    let (before, error, after) = self.split(line, *begin);
+                    last = error;
+
+                    if rows.len() > MAX_ROWS && (2..rows.len() - 2).contains(&i) {
+                        if i == 2 {
+                            writeln!(out, "{} {}", gutter(None), "┆".color(self.color)).unwrap();
+                        }
+
+                        continue;
+                    }
+
+                    writeln!(out, "{} {} {}{}{}",
+                        gutter(Some(*row)),
+                        if i == 0 { "╭" } else { "│" }.color(self.color),
+                        expand_tabs(before), expand_tabs(error).color(self.color), expand_tabs(after)
+                    ).unwrap();
+                }
+
+                writeln!(out, "{} {}",
+                    format!("{}╯", "─".repeat(row_len)).bright_black(),
+                    format!("╰─{} {}", "─".repeat(width(last)), &self.description).color(self.color)
+                ).unwrap();
+            }
+        }
+
+        let Some(expansion) = &self.span.expansion else {
+            return
+        };
+
+        // recursive macros repeat the same expansion, so runs are collapsed
+        for (count, expansion) in expansion.chain().dedup_by_with_count(|a, b| Rc::ptr_eq(&a.definition.source, &b.definition.source)
+            && a.definition.begin == b.definition.begin
+        ) {
+            writeln!(out, "{}",
+                format!("{} ╰ expanded from macro '{}' defined at {}:{}{}",
+                    " ".repeat(row_len),
+                    &expansion.name,
+                    expansion.definition.source.path.display(), expansion.definition.row_num(),
+                    if count > 1 { format!(" ({} times)", count) } else { String::new() }
+                ).bright_black()
+            ).unwrap();
+        }
     }
 }
 
@@ -62,30 +190,38 @@ impl MessageKind {
 #[derive(Debug)]
 pub struct Message {
     pub kind: MessageKind,
+    pub code: Option<Code>,
     message: String,
     code_snippets: Vec<CodeSnippet>,
-    note: Option<String>
+    notes: Vec<String>
 }
 
 impl Message {
     pub fn error(message: String) -> Self {
         Self {
             kind: MessageKind::Error,
+            code: None,
             message,
             code_snippets: vec![],
-            note: None
+            notes: vec![]
         }
     }
     
     pub fn warning(message: String) -> Self {
         Self {
             kind: MessageKind::Warning,
+            code: None,
             message,
             code_snippets: vec![],
-            note: None
+            notes: vec![]
         }
     }
 
+    pub fn with_id(mut self, code: Code) -> Self {
+        self.code = Some(code);
+        self
+    }
+
     pub fn with_code(mut self, description: String, span: Span) -> Self {
         self.code_snippets.push(CodeSnippet {
             description, span,
@@ -94,6 +230,11 @@ impl Message {
         self
     }
 
+    /// Returns the span of the first code snippet.
+    pub fn span(&self) -> Option<&Span> {
+        self.code_snippets.first().map(|snippet| &snippet.span)
+    }
+
     pub fn with_code_context(mut self, description: String, span: Span) -> Self {
         self.code_snippets.push(CodeSnippet {
             description, span,
@@ -103,15 +244,20 @@ impl Message {
     }
 
     pub fn with_note(mut self, note: String) -> Self {
-        self.note = Some(note);
+        self.notes.push(note);
         self
     }
 
     pub fn format<T: Write>(&self, mut out: T) {
         let color = self.kind.as_color();
 
+        let kind = match self.code {
+            Some(code) => format!("{}[{}]", self.kind.as_str(), code),
+            None => String::from(self.kind.as_str())
+        };
+
         writeln!(out, "{}",
-            format!("{}: {}", self.kind.as_str().color(color), &self.message).bold()
+            format!("{}: {}", kind.color(color), &self.message).bold()
         ).unwrap();
 
         if !self.code_snippets.is_empty() {
@@ -124,7 +270,7 @@ impl Message {
             }
         }
 
-        if let Some(note) = &self.note {
+        for note in &self.notes {
             writeln!(out, "{}",
                 format!("{}: {}", "note".cyan(), note).bold()
             ).unwrap();
@@ -144,4 +290,85 @@ pub fn human_count(of: &str, count: usize) -> String {
     )
 }
 
-pub type Result<T> = core::result::Result<(T, Vec<Message>), Vec<Message>>;
\ No newline at end of file
+pub type Result<T> = core::result::Result<(T, Vec<Message>), Vec<Message>>;
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::source::Source;
+
+    /// Formats an error pointing at the first occurrence of `text` in
+    /// `source`, without colour.
+    fn render(source: &str, text: &str) -> String {
+        let source = Rc::new(Source::new(source.to_string(), PathBuf::from("test.pasm")));
+        let begin = source.text.find(text).expect("text should be in the source");
+        let span = Span::new(begin, begin + text.len(), Rc::clone(&source));
+
+        let mut out = vec![];
+        ColorChoice::Never.apply();
+        Message::error(String::from("oops")).with_code(String::from("here"), span).format(&mut out);
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn underlines_spans_on_one_row() {
+        assert_eq!(render("start:\n jmp nowhere\n", "nowhere"), "\
+error: oops
+───╮┄┄ test.pasm:2:6
+ 2 │  jmp nowhere
+───╯      ╰──────── here
+");
+    }
+
+    #[test]
+    fn expands_tabs_and_counts_wide_characters() {
+        assert_eq!(render("\tjmp nowhere\n", "nowhere"), "\
+error: oops
+───╮┄┄ test.pasm:1:6
+ 1 │     jmp nowhere
+───╯         ╰──────── here
+");
+        assert_eq!(render(" %error \"日本\" x\n", "x"), "\
+error: oops
+───╮┄┄ test.pasm:1:14
+ 1 │  %error \"日本\" x
+───╯                ╰── here
+");
+    }
+
+    #[test]
+    fn draws_spans_across_rows() {
+        assert_eq!(render("a:\n jmp b\n hlt\n", "jmp b\n hlt"), "\
+error: oops
+───╮┄┄ test.pasm:2:2
+ 2 │ ╭  jmp b
+ 3 │ │  hlt
+───╯ ╰───── here
+");
+    }
+
+    #[test]
+    fn elides_the_middle_of_long_spans() {
+        let source = (1..=12).map(|row| format!("row{}\n", row)).collect::<String>();
+
+        assert_eq!(render(&source, "row5\nrow6\nrow7\nrow8\nrow9\nrow10"), "\
+error: oops
+────╮┄┄ test.pasm:5:1
+  5 │ ╭ row5
+  6 │ │ row6
+    │ ┆
+  9 │ │ row9
+ 10 │ │ row10
+────╯ ╰────── here
+");
+    }
+
+    #[test]
+    fn parses_color_choices() {
+        assert_eq!(ColorChoice::from_name("auto"), Some(ColorChoice::Auto));
+        assert_eq!(ColorChoice::from_name("never"), Some(ColorChoice::Never));
+        assert_eq!(ColorChoice::from_name("sometimes"), None);
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use crate::{parser::{Node, NodeKind}, lexer::{Token, TokenKind}, expression::{Expression, ExpressionKind}, message::{Message, Result}, source::{Span, WithSpan}, code::Code};
+
+/// Labels and constants declared in a `%module` block, by their
+/// unqualified names.
+#[derive(Debug)]
+struct Module {
+    path: String,
+    labels: HashMap<String, Span>,
+    exports: HashMap<String, Span>
+}
+
+impl Module {
+    /// Returns the name the label `name` of this module has after mangling.
+    fn mangle(&self, name: &str) -> String {
+        match self.exports.contains_key(name) {
+            true => String::from(name),
+            false => format!("{}::{}", self.path, name)
+        }
+    }
+}
+
+struct Resolver {
+    modules: Vec<Module>,
+    /// labels declared outside of modules or exported
+    globals: HashMap<String, Span>
+}
+
+impl Resolver {
+    fn module(&self, path: &str) -> Option<&Module> {
+        self.modules.iter().find(|module| module.path == path)
+    }
+
+    fn private(&self, module: &Module, name: &str, span: &Span) -> Message {
+        Message::error(format!("label '{}' is private to module '{}'", name, &module.path))
+            .with_id(Code::PrivateLabel)
+            .with_code(String::from("private label"), span.clone())
+            .with_code_context(String::from("declared here"), module.labels[name].clone())
+            .with_note(format!("labels are only visible outside of their module after '%export {}'", name))
+    }
+
+    /// Resolves a reference to `name` from the module `current` to the
+    /// mangled name of the label, if it names one of a module.
+    fn resolve(&self, name: &str, span: &Span, current: Option<&Module>) -> core::result::Result<Option<String>, Message> {
+        if let Some((path, label)) = name.rsplit_once("::") {
+            let Some(module) = self.module(path).filter(|module| module.labels.contains_key(label)) else {
+                return Ok(None)
+            };
+
+            return match module.exports.contains_key(label) || current.map(|current| current.path == path).unwrap_or(false) {
+                true => Ok(Some(module.mangle(label))),
+                false => Err(self.private(module, label, span))
+            };
+        }
+
+        // labels of enclosing modules are visible in nested ones
+        let mut path = current.map(|current| current.path.as_str());
+        while let Some(current) = path {
+            if let Some(module) = self.module(current).filter(|module| module.labels.contains_key(name)) {
+                return Ok(Some(module.mangle(name)));
+            }
+
+            path = current.rsplit_once("::").map(|(parent, _)| parent);
+        }
+
+        if self.globals.contains_key(name) {
+            return Ok(None);
+        }
+
+        match self.modules.iter().find(|module| module.labels.contains_key(name)) {
+            Some(module) => Err(self.private(module, name, span)),
+            None => Ok(None)
+        }
+    }
+
+    fn resolve_token(&self, token: &mut Token, current: Option<&Module>) -> core::result::Result<(), Message> {
+        if let TokenKind::Word(word) = &token.value {
+            if let Some(resolved) = self.resolve(word, &token.span, current)? {
+                token.value = TokenKind::Word(resolved);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resolve_expression(&self, expression: &mut Expression, current: Option<&Module>) -> core::result::Result<(), Message> {
+        match &mut expression.value {
+            ExpressionKind::Number(_) => (),
+            ExpressionKind::Symbol(symbol) => if let Some(resolved) = self.resolve(symbol, &expression.span, current)? {
+                *symbol = resolved;
+            },
+            ExpressionKind::Unary(_, operand) => self.resolve_expression(operand, current)?,
+            ExpressionKind::Binary(_, left, right) => {
+                self.resolve_expression(left, current)?;
+                self.resolve_expression(right, current)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mangles the labels declared in `%module` blocks that aren't exported
+/// with `%export`, and resolves every reference to them, including ones
+/// qualified as `module::label`. The `%module`, `%export` and `%end`
+/// directives are removed.
+pub fn resolve_modules(program: Vec<Node>) -> Result<Vec<Node>> {
+    let mut errors = vec![];
+    let mut resolver = Resolver {
+        modules: vec![],
+        globals: HashMap::new()
+    };
+
+    // the module of every node, as an index into `resolver.modules`
+    let mut modules: Vec<Option<usize>> = vec![];
+    let mut stack: Vec<usize> = vec![];
+
+    for node in &program {
+        match &node.value {
+            NodeKind::Directive { name, arguments } if name.value == "module" => {
+                let name = match arguments.as_slice() {
+                    [WithSpan { value: TokenKind::Word(name), .. }] => name,
+                    _ => unreachable!("should be handled by the preprocessor")
+                };
+                let path = match stack.last() {
+                    Some(parent) => format!("{}::{}", &resolver.modules[*parent].path, name),
+                    None => name.clone()
+                };
+
+                match resolver.modules.iter().position(|module| module.path == path) {
+                    Some(i) => stack.push(i),
+                    None => {
+                        stack.push(resolver.modules.len());
+                        resolver.modules.push(Module { path, labels: HashMap::new(), exports: HashMap::new() });
+                    }
+                }
+            },
+            NodeKind::Directive { name, .. } if name.value == "end" => {
+                stack.pop();
+            },
+            NodeKind::Directive { name, arguments } if name.value == "export" => match stack.last() {
+                Some(module) => for argument in arguments {
+                    match &argument.value {
+                        TokenKind::Word(label) => {
+                            resolver.modules[*module].exports.insert(label.clone(), argument.span.clone());
+                        },
+                        _ => errors.push(
+                            Message::error(format!("expected label, found '{}'", argument.span.get_text()))
+                                .with_id(Code::MalformedDirective)
+                                .with_code(String::from("expected label"), argument.span.clone())
+                        )
+                    }
+                },
+                None => errors.push(
+                    Message::error(String::from("'%export' must be inside of a '%module' block"))
+                        .with_id(Code::MalformedDirective)
+                        .with_code(String::from("outside of a module"), node.span.clone())
+                )
+            },
+            NodeKind::Label { name } | NodeKind::Constant { name, .. } => match stack.last() {
+                Some(module) => {
+                    resolver.modules[*module].labels.insert(name.value.clone(), name.span.clone());
+                },
+                None => {
+                    resolver.globals.insert(name.value.clone(), name.span.clone());
+                }
+            },
+            _ => ()
+        }
+
+        modules.push(stack.last().copied());
+    }
+
+    for module in &resolver.modules {
+        for (label, span) in &module.exports {
+            match module.labels.contains_key(label) {
+                true => {
+                    resolver.globals.insert(label.clone(), span.clone());
+                },
+                false => errors.push(
+                    Message::error(format!("module '{}' exports undeclared label '{}'", &module.path, label))
+                        .with_id(Code::UndeclaredLabel)
+                        .with_code(String::from("unknown label"), span.clone())
+                )
+            }
+        }
+    }
+
+    let mut out = vec![];
+
+    for (mut node, module) in program.into_iter().zip(modules) {
+        let current = module.map(|module| &resolver.modules[module]);
+
+        let result = match &mut node.value {
+            NodeKind::Directive { name, .. } if matches!(name.value.as_str(), "module" | "end" | "export") => continue,
+            NodeKind::Label { name } => {
+                if let Some(current) = current {
+                    name.value = current.mangle(&name.value);
+                }
+
+                Ok(())
+            },
+            NodeKind::Constant { name, value } => {
+                if let Some(current) = current {
+                    name.value = current.mangle(&name.value);
+                }
+
+                resolver.resolve_expression(value, current)
+            },
+            NodeKind::Instruction { arguments, .. } => arguments.iter_mut()
+                .try_for_each(|argument| resolver.resolve_token(argument, current)),
+            NodeKind::Value { value } => resolver.resolve_token(value, current),
+            NodeKind::Assert { condition, .. } => resolver.resolve_expression(condition, current),
+            NodeKind::Directive { .. } | NodeKind::AnonymousLabel => Ok(())
+        };
+
+        match result {
+            Ok(()) => out.push(node),
+            Err(error) => errors.push(error)
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((out, vec![]))
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{code::Code, compiler::{build, Options}, source::Source};
+
+    fn bytes(text: &str) -> Vec<u8> {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+
+        match build(source, &Options::default()) {
+            Ok((image, _)) => image.bytes,
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        let source = Source::new(text.to_string(), PathBuf::from("test.pasm"));
+
+        match build(source, &Options::default()) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => errors.into_iter().filter_map(|error| error.code).collect()
+        }
+    }
+
+    const MODULE: &str = "\
+%module a
+%export f
+f:
+ jmp g
+g:
+ hlt
+%end
+";
+
+    #[test]
+    fn exported_labels_are_global() {
+        let expected = bytes("jmp f\nf:\n jmp g\ng:\n hlt\n");
+
+        assert_eq!(bytes(&format!("jmp f\n{}", MODULE)), expected);
+        assert_eq!(bytes(&format!("jmp a::f\n{}", MODULE)), expected);
+    }
+
+    #[test]
+    fn private_labels_are_only_visible_in_their_module() {
+        assert!(matches!(codes(&format!("jmp a::g\n{}", MODULE))[..], [Code::PrivateLabel]));
+        assert!(matches!(codes(&format!("jmp g\n{}", MODULE))[..], [Code::PrivateLabel]));
+    }
+
+    #[test]
+    fn private_labels_dont_collide() {
+        assert_eq!(
+            bytes(&format!("g:\n jmp g\n{}", MODULE)),
+            bytes("g:\n jmp g\nf:\n jmp h\nh:\n hlt\n")
+        );
+    }
+
+    #[test]
+    fn nested_modules_see_enclosing_labels() {
+        assert_eq!(
+            bytes("%module a\n%module b\n%export f\nf:\n jmp g\n%end\ng:\n hlt\n%end\n"),
+            bytes("f:\n jmp g\ng:\n hlt\n")
+        );
+        assert!(matches!(codes("jmp a::b::f\n%module a\n%module b\nf:\n hlt\n%end\n%end\n")[..], [Code::PrivateLabel]));
+    }
+
+    #[test]
+    fn rejects_invalid_exports() {
+        assert!(matches!(codes("%export f\nf:\n hlt\n")[..], [Code::MalformedDirective]));
+        assert!(matches!(codes("%module a\n%export f\n hlt\n%end\n")[..], [Code::UndeclaredLabel]));
+    }
+}
@@ -1,12 +1,22 @@
-use std::{vec::IntoIter, rc::Rc};
+use std::vec::IntoIter;
 
-use crate::{lexer::{Token, TokenKind}, source::{Span, WithSpan, IntoWithSpan}, message::{Result, Message}};
+use crate::{lexer::{Token, TokenKind}, source::{Span, WithSpan, IntoWithSpan}, message::{Result, Message}, code::Code, expression::{self, Expression, Operator}};
 
 #[derive(Debug)]
 pub enum NodeKind {
     Instruction { name: WithSpan<String>, arguments: Vec<Token> },
     Value { value: Token },
-    Label { name: WithSpan<String> }
+    Label { name: WithSpan<String> },
+    /// An assembler directive passed through by the preprocessor, such as
+    /// `%clobbers`
+    Directive { name: WithSpan<String>, arguments: Vec<Token> },
+    /// `%assert <condition>, "message"`, checked once labels are resolved
+    Assert { condition: Expression, message: WithSpan<String> },
+    /// `name = value`, a constant that may refer to labels and constants
+    /// declared anywhere
+    Constant { name: WithSpan<String>, value: Expression },
+    /// `:` on its own, referred to as `:-` or `:+`
+    AnonymousLabel
 }
 
 pub type Node = WithSpan<NodeKind>;
@@ -32,11 +42,27 @@ impl Parser {
         &self.current
     }
 
-    fn make_instruction(&mut self, name: WithSpan<String>) -> Result<Node> {
-        let source = Rc::clone(&name.span.source);
-        let begin = name.span.begin;
-        let mut end = name.span.end;
+    /// Folds `+ n` or `- n` following `$` into its offset.
+    fn make_location(&mut self, dollar: Token) -> Token {
+        let (offset, end) = match self.tokens.as_slice() {
+            [
+                WithSpan { value: TokenKind::Operator(operator @ (Operator::Plus | Operator::Minus)), .. },
+                WithSpan { value: TokenKind::Number(number), span },
+                ..
+            ] => match operator {
+                Operator::Plus => (*number as i16, span.end),
+                _ => (-(*number as i16), span.end)
+            },
+            _ => return dollar
+        };
+
+        self.advance();
+        self.advance();
 
+        TokenKind::Location(offset).with_span(Span { end, ..dollar.span })
+    }
+
+    fn make_arguments(&mut self, mut end: usize) -> core::result::Result<(Vec<Token>, usize), Vec<Message>> {
         let mut arguments = Vec::new();
 
         while let Some(current) = self.current.clone() {
@@ -44,23 +70,37 @@ impl Parser {
                 | TokenKind::Word(..)
                 | TokenKind::Character(..)
                 | TokenKind::String(..)
-                | TokenKind::Number(..) => {
+                | TokenKind::Number(..)
+                | TokenKind::AnonymousLabel(..) => {
                     arguments.push(current.clone());
                     end = current.span.end;
                 }
-                TokenKind::NewLine => break,
+                TokenKind::Location(..) => {
+                    let location = self.make_location(current);
+                    end = location.span.end;
+                    arguments.push(location);
+                }
+                | TokenKind::NewLine
+                | TokenKind::Percent => break,
                 | TokenKind::Comma
                 | TokenKind::Colon
-                | TokenKind::Backslash => {
+                | TokenKind::Backslash
+                | TokenKind::LeftParen
+                | TokenKind::RightParen
+                | TokenKind::Operator(..)
+                | TokenKind::Hash
+                | TokenKind::HashHash
+                | TokenKind::Counter
+                | TokenKind::Equals => {
                     let span = current.span.clone();
                     self.advance();
 
                     return Err(
                         vec![Message::error(format!("expected argument, found '{}'", current.span.get_text()))
+                            .with_id(Code::InvalidSyntax)
                             .with_code(String::from("invalid syntax"), span)
                     ])
                 }
-                | TokenKind::Percent { .. } => unreachable!()
             }
 
             self.advance();
@@ -71,27 +111,149 @@ impl Parser {
             }
         }
 
+        Ok((arguments, end))
+    }
+
+    fn make_instruction(&mut self, name: WithSpan<String>) -> Result<Node> {
+        let (arguments, end) = self.make_arguments(name.span.end)?;
+        let span = Span { end, ..name.span.clone() };
+
+        Ok((
+            NodeKind::Instruction { name, arguments }.with_span(span),
+            vec![]
+        ))
+    }
+
+    fn make_directive(&mut self, percent: Span) -> Result<Node> {
+        let name = match self.advance().clone() {
+            Some(WithSpan { value: TokenKind::Word(name), span }) => WithSpan { value: name, span },
+            _ => unreachable!("should be handled by the preprocessor")
+        };
+        self.advance();
+
+        if name.value == "assert" {
+            return self.make_assert(percent);
+        }
+
+        let (arguments, end) = self.make_arguments(name.span.end)?;
+
+        Ok((
+            NodeKind::Directive { name, arguments }.with_span(Span::new(percent.begin, end, percent.source)),
+            vec![]
+        ))
+    }
+
+    fn make_assert(&mut self, percent: Span) -> Result<Node> {
+        let mut tokens = vec![];
+
+        while let Some(token) = self.current.clone() {
+            if token.value == TokenKind::NewLine {
+                break;
+            }
+
+            tokens.push(token);
+            self.advance();
+        }
+
+        let usage = || String::from("usage: %assert <condition>, \"message\"");
+
+        let (condition, message) = match tokens.as_slice() {
+            [condition @ .., WithSpan { value: TokenKind::Comma, .. }, WithSpan { value: TokenKind::String(message), span }]
+                if !condition.is_empty() => (condition, WithSpan { value: message.clone(), span: span.clone() }),
+            [.., last] => return Err(vec![
+                Message::error(String::from("'%assert' must end with a message"))
+                    .with_id(Code::MalformedDirective)
+                    .with_code(String::from("expected ', \"message\"' after this"), last.span.clone())
+                    .with_note(usage())
+            ]),
+            [] => return Err(vec![
+                Message::error(String::from("'%assert' must be supplied with a condition"))
+                    .with_id(Code::MalformedDirective)
+                    .with_code(String::from("expected condition"), percent)
+                    .with_note(usage())
+            ])
+        };
+
+        let condition = expression::parse(condition).map_err(|error| vec![error])?;
+        let span = Span { end: message.span.end, ..percent };
+
+        Ok((
+            NodeKind::Assert { condition, message }.with_span(span),
+            vec![]
+        ))
+    }
+
+    fn make_constant(&mut self, name: WithSpan<String>, equals: Span) -> Result<Node> {
+        let mut tokens = vec![];
+
+        while let Some(token) = self.advance().clone() {
+            if token.value == TokenKind::NewLine {
+                break;
+            }
+
+            tokens.push(token);
+        }
+
+        if tokens.is_empty() {
+            return Err(vec![
+                Message::error(format!("constant '{}' must be given a value", &name.value))
+                    .with_id(Code::InvalidSyntax)
+                    .with_code(String::from("expected value after '='"), equals)
+            ]);
+        }
+
+        let value = expression::parse(&tokens).map_err(|error| vec![error])?;
+        let span = Span { end: value.span.end, ..name.span.clone() };
+
         Ok((
-            NodeKind::Instruction { name, arguments }.with_span(Span::new(begin, end, source)),
+            NodeKind::Constant { name, value }.with_span(span),
+            vec![]
+        ))
+    }
+
+    /// Parses structured control flow such as `.if rx == 0` as a directive
+    /// named with its dot, lowered by `control::lower`.
+    fn make_control(&mut self, name: WithSpan<String>) -> Result<Node> {
+        let mut arguments = vec![];
+        let mut end = name.span.end;
+
+        while let Some(token) = self.advance().clone() {
+            if token.value == TokenKind::NewLine {
+                break;
+            }
+
+            end = token.span.end;
+            arguments.push(token);
+        }
+
+        let span = Span { end, ..name.span.clone() };
+
+        Ok((
+            NodeKind::Directive { name, arguments }.with_span(span),
             vec![]
         ))
     }
 
     fn make_instruction_or_label(&mut self, name: WithSpan<String>) -> Result<Node> {
-        let source = Rc::clone(&name.span.source);
-        let begin = name.span.begin;
+        if name.value.starts_with('.') {
+            return self.make_control(name);
+        }
 
         match self.advance() {
             Some(WithSpan { value: TokenKind::Colon, span }) => {
-                let end = span.end;
+                let span = Span { end: span.end, ..name.span.clone() };
 
                 self.advance();
 
                 Ok((
-                    NodeKind::Label { name }.with_span(Span::new(begin, end, source)),
+                    NodeKind::Label { name }.with_span(span),
                     vec![]
                 ))
             },
+            Some(WithSpan { value: TokenKind::Equals, span }) => {
+                let equals = span.clone();
+                self.make_constant(name, equals)
+            },
             _ => self.make_instruction(name)
         }
     }
@@ -134,13 +296,28 @@ impl Iterator for Parser {
         let current = self.current.clone()?;
         Some(match current.value {
             TokenKind::Word(name) => self.make_instruction_or_label(WithSpan { value: name, span: current.span }),
+            TokenKind::Percent => self.make_directive(current.span),
+            TokenKind::Colon => {
+                self.advance();
+
+                Ok((
+                    NodeKind::AnonymousLabel.with_span(current.span),
+                    vec![]
+                ))
+            }
             | TokenKind::Number(..)
             | TokenKind::String(..)
-            | TokenKind::Character(..) => {
+            | TokenKind::Character(..)
+            | TokenKind::Location(..)
+            | TokenKind::AnonymousLabel(..) => {
+                let value = match current.value {
+                    TokenKind::Location(..) => self.make_location(current),
+                    _ => current
+                };
                 self.advance();
 
                 Ok((
-                    NodeKind::Value { value: current.clone() }.with_span(current.span.clone()),
+                    NodeKind::Value { value: value.clone() }.with_span(value.span.clone()),
                     vec![]
                 ))
             }
@@ -150,6 +327,7 @@ impl Iterator for Parser {
 
                 Err(vec![
                     Message::error(format!("expected statement, found '{}'", current.span.get_text()))
+                        .with_id(Code::InvalidSyntax)
                         .with_code(String::from("invalid syntax"), span)
                 ])
             }
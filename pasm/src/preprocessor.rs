@@ -1,14 +1,379 @@
-use std::{fs, vec::IntoIter, rc::Rc, collections::HashMap};
+use std::{fs, vec::IntoIter, rc::Rc, cell::{Cell, RefCell}, collections::HashMap, path::PathBuf, io::{self, Write}};
 
-use crate::{lexer::{Token, TokenKind, Lexer}, message::{Result, Message}, source::{WithSpan, Span, Source}};
+use image::GenericImageView;
+use itertools::Itertools;
+
+use crate::{lexer::{Token, TokenKind, Lexer, Direction}, expression::Operator, message::{Result, Message, human_count}, source::{WithSpan, Span, Source, IntoWithSpan}, suggestion::did_you_mean, code::Code, lint::{self, Allow}};
+
+/// Directives whose blocks are closed by '%end'.
+const BLOCKS: &[&str] = &["ifndef", "macro", "module", "struct", "enum"];
+
+/// Names that may follow a '%'.
+const DIRECTIVES: &[&str] = &[
+    "include", "incbin", "incimage", "define", "ifndef", "end", "clobbers", "preserves", "allow",
+    "error", "warning", "assert", "macro", "module", "export", "struct", "enum"
+];
 
 type Tokens = Vec<Token>;
-type Symbols = HashMap<String, Vec<TokenKind>>;
+type Symbols = HashMap<String, Macro>;
+type Allows = Vec<Allow>;
+
+/// Resolves `path` relative to the directory of the file containing `span`.
+fn resolve_path(span: &Span, path: &str) -> PathBuf {
+    let mut resolved = span.source.path.clone();
+    resolved.pop();
+    resolved.push(path);
+    resolved
+}
+
+fn string_argument(token: &Token, description: &str) -> core::result::Result<WithSpan<String>, Message> {
+    match &token.value {
+        TokenKind::String(string) => Ok(string.clone().with_span(token.span.clone())),
+        _ => Err(
+            Message::error(format!("expected {}, found '{}'", description, token.span.get_text()))
+                .with_id(Code::MalformedDirective)
+                .with_code(String::from("expected string"), token.span.clone())
+        )
+    }
+}
+
+fn number_argument(token: &Token, description: &str) -> core::result::Result<u8, Message> {
+    match &token.value {
+        TokenKind::Number(number) => Ok(*number),
+        _ => Err(
+            Message::error(format!("expected {}, found '{}'", description, token.span.get_text()))
+                .with_id(Code::MalformedDirective)
+                .with_code(String::from("expected number"), token.span.clone())
+        )
+    }
+}
+
+fn read_binary(path: &WithSpan<String>, opened: &RefCell<Vec<PathBuf>>) -> core::result::Result<Vec<u8>, Message> {
+    let include_path = resolve_path(&path.span, &path.value);
+    opened.borrow_mut().push(include_path.clone());
+
+    fs::read(&include_path).map_err(|_|
+        Message::error(format!("no such file: {}", include_path.display()))
+            .with_id(Code::FileNotFound)
+            .with_code(String::from("invalid include path"), path.span.clone())
+    )
+}
+
+/// Converts an image into bytes, one pixel per byte (`threshold`) or
+/// eight pixels per byte, most significant bit first (`pack`).
+fn convert_image(bytes: &[u8], path: &WithSpan<String>, mode: &Token) -> core::result::Result<Vec<u8>, Message> {
+    let pack = match &mode.value {
+        TokenKind::Word(word) if word == "threshold" => false,
+        TokenKind::Word(word) if word == "pack" => true,
+        _ => return Err(
+            Message::error(format!("'{}' is not a valid image mode", mode.span.get_text()))
+                .with_id(Code::InvalidImage)
+                .with_code(String::from("invalid mode"), mode.span.clone())
+                .with_note(String::from("valid modes are: 'threshold' and 'pack'"))
+        )
+    };
+
+    let image = image::load_from_memory(bytes).map_err(|error|
+        Message::error(format!("could not decode image '{}': {}", &path.value, error))
+            .with_id(Code::InvalidImage)
+            .with_code(String::from("invalid image"), path.span.clone())
+    )?;
+    let (width, height) = image.dimensions();
+    let image = image.to_luma_alpha8();
+
+    let mut out = vec![];
+
+    for y in 0..height {
+        let row = (0..width).map(|x| {
+            let [luma, alpha] = image.get_pixel(x, y).0;
+            luma >= 0x80 && alpha >= 0x80
+        });
+
+        if pack {
+            for chunk in &row.chunks(8) {
+                out.push(chunk.enumerate().fold(0, |byte, (i, set)| byte | ((set as u8) << (7 - i))));
+            }
+        } else {
+            out.extend(row.map(|set| set as u8));
+        }
+    }
+
+    Ok(out)
+}
+
+/// How deeply macros may expand within each other by default.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+fn escape(text: &str, quote: char) -> String {
+    text.chars()
+        .map(|character| match character {
+            '\n' => String::from("\\n"),
+            '\t' => String::from("\\t"),
+            '\0' => String::from("\\0"),
+            '\\' => String::from("\\\\"),
+            character if character == quote => format!("\\{}", quote),
+            character if character.is_control() => format!("\\x{:02x}", character as u32),
+            character => character.to_string()
+        })
+        .collect()
+}
+
+/// Returns the source text of `token`.
+fn token_text(token: &TokenKind) -> String {
+    match token {
+        TokenKind::Word(word) => word.clone(),
+        TokenKind::Number(number) => number.to_string(),
+        TokenKind::Character(character) => format!("'{}'", escape(&(*character as char).to_string(), '\'')),
+        TokenKind::String(string) => format!("\"{}\"", escape(string, '"')),
+        TokenKind::Comma => String::from(","),
+        TokenKind::Colon => String::from(":"),
+        TokenKind::Percent => String::from("%"),
+        TokenKind::NewLine => String::from("\n"),
+        TokenKind::Backslash => String::from("\\"),
+        TokenKind::LeftParen => String::from("("),
+        TokenKind::RightParen => String::from(")"),
+        TokenKind::Operator(operator) => String::from(operator.as_str()),
+        TokenKind::Hash => String::from("#"),
+        TokenKind::HashHash => String::from("##"),
+        TokenKind::Counter => String::from("\\@"),
+        TokenKind::Equals => String::from("="),
+        TokenKind::Location(0) => String::from("$"),
+        TokenKind::Location(offset) => format!("${:+}", offset),
+        TokenKind::AnonymousLabel(Direction::Backward) => String::from(":-"),
+        TokenKind::AnonymousLabel(Direction::Forward) => String::from(":+")
+    }
+}
+
+/// Writes preprocessed tokens back as source, one statement per line.
+/// Whenever a line doesn't closely follow the previous one in the same file,
+/// it's preceded by a `;#line <row> "<path>"` marker naming its origin.
+pub fn write_source<T: Write>(tokens: &[Token], mut out: T) -> io::Result<()> {
+    let mut origin: Option<(&PathBuf, usize)> = None;
+
+    for line in tokens.split(|token| token.value == TokenKind::NewLine) {
+        let Some(first) = line.first() else {
+            continue
+        };
+
+        let path = &first.span.source.path;
+        let row = first.span.row_num();
+
+        // short gaps are kept as empty lines, like the C preprocessor does
+        match origin {
+            Some((previous_path, previous_row)) if previous_path == path && (previous_row..=previous_row + 8).contains(&row) => {
+                for _ in previous_row + 1..row {
+                    writeln!(out)?;
+                }
+            },
+            _ => writeln!(out, ";#line {} \"{}\"", row, escape(&path.display().to_string(), '"'))?
+        }
+        origin = Some((path, row));
+
+        let label = matches!(line,
+            | [WithSpan { value: TokenKind::Word(_), .. }, WithSpan { value: TokenKind::Colon, .. }, ..]
+            | [WithSpan { value: TokenKind::Colon, .. }, ..]
+        );
+        let mut text = String::from(match label || first.value == TokenKind::Percent {
+            true => "",
+            false => " "
+        });
+
+        for (i, token) in line.iter().enumerate() {
+            let joined = matches!(
+                (i.checked_sub(1).map(|i| &line[i].value), &token.value),
+                | (None, _)
+                | (_, TokenKind::Comma | TokenKind::Colon | TokenKind::RightParen)
+                | (Some(TokenKind::Comma | TokenKind::Percent | TokenKind::LeftParen), _)
+            );
+
+            if !joined {
+                text.push(' ');
+            }
+            text.push_str(&token_text(&token.value));
+        }
+
+        writeln!(out, "{}", text)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    /// the name of the macro in its definition
+    span: Span,
+    /// the parameters of a `%macro`, `None` for a `%define`
+    parameters: Option<Vec<String>>,
+    body: Vec<TokenKind>
+}
+
+fn invalid_paste(message: String, description: &str, span: &Span) -> Message {
+    Message::error(message)
+        .with_id(Code::InvalidPaste)
+        .with_code(String::from(description), span.clone())
+}
+
+/// Names the constant holding the size of the layout `name`, which
+/// `sizeof(name)` is replaced by.
+fn sizeof(name: &str) -> String {
+    format!("sizeof.{}", name)
+}
+
+/// Lowers the members of a `%struct` or `%enum` block to constants named
+/// `Name.member`, and `sizeof.Name` to the size of the layout. Struct
+/// members are given their size after the name, 1 by default, and enum
+/// members may be given their value as `member = value`.
+fn layout(kind: &str, name: &WithSpan<String>, tokens: Tokens) -> core::result::Result<Tokens, Message> {
+    let mut out = vec![];
+    // the last member and the tokens of its size, which the next one follows
+    let mut previous: Option<(Token, Tokens)> = None;
+
+    let constant = |out: &mut Tokens, member: &str, span: &Span, value: Tokens| {
+        out.push(TokenKind::Word(member.to_string()).with_span(span.clone()));
+        out.push(TokenKind::Equals.with_span(span.clone()));
+        out.extend(value);
+        out.push(TokenKind::NewLine.with_span(span.clone()));
+    };
+    let after = |previous: &Option<(Token, Tokens)>, span: &Span| match previous {
+        Some((member, size)) => [
+            vec![member.clone(), TokenKind::Operator(Operator::Plus).with_span(span.clone()), TokenKind::LeftParen.with_span(span.clone())],
+            size.clone(),
+            vec![TokenKind::RightParen.with_span(span.clone())]
+        ].concat(),
+        None => vec![TokenKind::Number(0).with_span(span.clone())]
+    };
+
+    for line in tokens.split(|token| token.value == TokenKind::NewLine).filter(|line| !line.is_empty()) {
+        let (member, rest) = match line {
+            [WithSpan { value: TokenKind::Word(member), span }, rest @ ..] => (WithSpan { value: format!("{}.{}", &name.value, member), span: span.clone() }, rest),
+            [token, ..] => return Err(
+                Message::error(format!("expected {} member, found '{}'", kind, token.span.get_text()))
+                    .with_id(Code::MalformedDirective)
+                    .with_code(String::from("expected identifier"), token.span.clone())
+            ),
+            [] => unreachable!("empty lines are skipped")
+        };
+        let span = &member.span;
+
+        let (value, size) = match (kind, rest) {
+            ("struct", []) => (after(&previous, span), vec![TokenKind::Number(1).with_span(span.clone())]),
+            ("struct", size) => (after(&previous, span), size.to_vec()),
+            (_, []) => (after(&previous, span), vec![TokenKind::Number(1).with_span(span.clone())]),
+            (_, [WithSpan { value: TokenKind::Equals, .. }, value @ ..]) if !value.is_empty() => (value.to_vec(), vec![TokenKind::Number(1).with_span(span.clone())]),
+            (_, [token, ..]) => return Err(
+                Message::error(format!("expected '=' or new line, found '{}'", token.span.get_text()))
+                    .with_id(Code::MalformedDirective)
+                    .with_code(String::from("expected '='"), token.span.clone())
+                    .with_note(String::from("enum members are given their value as 'member = value'"))
+            )
+        };
+
+        constant(&mut out, &member.value, span, value);
+        previous = Some((TokenKind::Word(member.value).with_span(span.clone()), size));
+    }
+
+    let size = match kind {
+        "struct" => after(&previous, &name.span),
+        _ => vec![TokenKind::Number(1).with_span(name.span.clone())]
+    };
+    constant(&mut out, &sizeof(&name.value), &name.span, size);
+
+    Ok(out)
+}
+
+/// Joins the texts of `tokens` to the text they'd have in a macro body.
+fn stringify(tokens: &[Token]) -> String {
+    tokens.iter()
+        .map(|token| token_text(&token.value))
+        .join(" ")
+        .replace(" ,", ",")
+}
+
+impl Macro {
+    /// Returns the body of the macro with parameters replaced by `arguments`,
+    /// `#parameter` by the argument as a string, `\@` by `counter` and
+    /// `a ## b` by the single token `ab`. Tokens of the body get `span`.
+    fn substitute(&self, arguments: &[Vec<Token>], counter: usize, span: &Span) -> core::result::Result<Vec<Token>, Message> {
+        let parameters = self.parameters.as_deref().unwrap_or_default();
+        let argument = |token: Option<&TokenKind>| match token {
+            Some(TokenKind::Word(word)) => parameters.iter()
+                .position(|parameter| parameter == word)
+                .map(|i| &arguments[i]),
+            _ => None
+        };
+
+        let mut substituted = vec![];
+        let mut body = self.body.iter().peekable();
+
+        while let Some(token) = body.next() {
+            match token {
+                TokenKind::Hash => match argument(body.next()) {
+                    Some(argument) => substituted.push(TokenKind::String(stringify(argument)).with_span(span.clone())),
+                    None => return Err(invalid_paste(
+                        String::from("'#' must be followed by a macro parameter"),
+                        "expected parameter after '#'", span
+                    ))
+                },
+                token => match argument(Some(token)) {
+                    Some(argument) => substituted.extend(argument.iter().cloned()),
+                    None => substituted.push(token.clone().with_span(span.clone()))
+                }
+            }
+        }
+
+        let text = |token: &Token| match &token.value {
+            TokenKind::Counter => counter.to_string(),
+            value => token_text(value)
+        };
+
+        let mut pasted: Vec<Token> = vec![];
+        let mut tokens = substituted.into_iter();
+
+        while let Some(token) = tokens.next() {
+            if token.value != TokenKind::HashHash {
+                pasted.push(token);
+                continue;
+            }
+
+            let (Some(left), Some(right)) = (pasted.pop(), tokens.next()) else {
+                return Err(invalid_paste(
+                    String::from("'##' must be between two tokens"),
+                    "nothing to paste", span
+                ));
+            };
+
+            let text = text(&left) + &text(&right);
+            let source = Rc::new(Source::new(text.clone(), PathBuf::from("<paste>")));
+
+            match Lexer::new(&source).lex() {
+                Ok((tokens, _)) if tokens.len() == 1 => pasted.push(tokens[0].value.clone().with_span(span.clone())),
+                _ => return Err(invalid_paste(
+                    format!("pasting '{}' and '{}' does not give a valid token: '{}'", token_text(&left.value), token_text(&right.value), text),
+                    "invalid paste", span
+                ))
+            }
+        }
+
+        pasted.into_iter()
+            .map(|token| match token.value {
+                TokenKind::Counter => u8::try_from(counter)
+                    .map(|counter| TokenKind::Number(counter).with_span(token.span.clone()))
+                    .map_err(|_| invalid_paste(
+                        format!("'\\@' is {}, which does not fit in a byte", counter),
+                        "out of range", &token.span
+                    ).with_note(String::from("paste it into a label instead, e.g. 'label_##\\@'"))),
+                _ => Ok(token)
+            })
+            .collect()
+    }
+}
 
 #[derive(Debug)]
 pub struct Scope<'a> {
     pub tokens: Tokens,
     symbols: Symbols,
+    /// `%allow` directives, open ones ending at `usize::MAX`
+    pub allows: Allows,
     parent: Option<&'a Scope<'a>>
 }
 
@@ -17,11 +382,12 @@ impl<'a> Scope<'a> {
         Self {
             tokens: vec![],
             symbols: HashMap::new(),
+            allows: vec![],
             parent
         }
     }
 
-    fn get_symbol(&self, symbol: &String) -> Option<&Vec<TokenKind>> {
+    fn get_symbol(&self, symbol: &String) -> Option<&Macro> {
         if let token_stream @ Some(..) = self.symbols.get(symbol) {
             return token_stream;
         }
@@ -29,22 +395,92 @@ impl<'a> Scope<'a> {
         self.parent?.get_symbol(symbol)
     }
 
-    fn extract(self) -> (Tokens, Symbols) {
-        (self.tokens, self.symbols)
+    /// Removes the symbol `name` from this scope, if defined.
+    pub fn undefine(&mut self, name: &str) {
+        self.symbols.remove(name);
+    }
+
+    pub fn symbol_names(&self) -> impl Iterator<Item = &String> {
+        self.symbols.keys()
     }
 
-    fn extend(&mut self, extract: (Tokens, Symbols)) {
+    fn extract(self) -> (Tokens, Symbols, Allows) {
+        (self.tokens, self.symbols, self.allows)
+    }
+
+    fn extend(&mut self, extract: (Tokens, Symbols, Allows)) {
         self.tokens.extend(extract.0);
         self.symbols.extend(extract.1);
+        self.allows.extend(extract.2);
     }
 }
 
 pub struct Preprocessor {
     tokens: IntoIter<Token>,
-    current: Option<Token>
+    current: Option<Token>,
+    max_depth: usize,
+    /// the number of macro expansions so far, shared with child preprocessors
+    counter: Rc<Cell<usize>>,
+    /// the files opened so far, shared with child preprocessors
+    opened: Rc<RefCell<Vec<PathBuf>>>,
+    /// the number of blocks being preprocessed that '%end' closes
+    blocks: usize
 }
 
 impl Preprocessor {
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Records the paths of the files opened by `%include`, `%incbin` and
+    /// `%incimage` into `opened`, even those that could not be read.
+    pub fn with_opened(mut self, opened: Rc<RefCell<Vec<PathBuf>>>) -> Self {
+        self.opened = opened;
+        self
+    }
+
+    /// Returns a preprocessor for `tokens` sharing this one's settings.
+    fn child(&self, tokens: Vec<Token>) -> Self {
+        Self {
+            counter: Rc::clone(&self.counter),
+            opened: Rc::clone(&self.opened),
+            ..Self::from(tokens).with_max_depth(self.max_depth)
+        }
+    }
+
+    /// Preprocesses the body of a block into `scope`, up to its '%end'.
+    fn block(&mut self, scope: &mut Scope) -> Result<()> {
+        self.blocks += 1;
+        let result = self.preprocess(scope);
+        self.blocks -= 1;
+
+        result
+    }
+
+    /// Skips the rest of a block that can't be preprocessed, past its '%end'.
+    fn skip_block(&mut self) {
+        let mut depth = 0;
+
+        while let Some(token) = self.current.clone() {
+            if token.value == TokenKind::Percent {
+                match self.advance() {
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if word == "end" && depth == 0 => {
+                        self.advance();
+                        return;
+                    },
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if word == "end" => depth -= 1,
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if BLOCKS.contains(&word.as_str()) => depth += 1,
+                    _ => ()
+                }
+
+                continue;
+            }
+
+            self.advance();
+        }
+    }
+
     pub fn preprocess(&mut self, scope: &mut Scope) -> Result<()> {
         let mut errors = vec![];
         let mut warnings = vec![];
@@ -63,6 +499,7 @@ impl Preprocessor {
                                         Some(WithSpan { span, .. }) => {
                                             errors.push(
                                                 Message::error(format!("expected include path, found {}", span.get_text()))
+                                                    .with_id(Code::MalformedDirective)
                                                     .with_code(String::from("expected string"), span)
                                             );
                                             
@@ -71,6 +508,7 @@ impl Preprocessor {
                                         None => {
                                             errors.push(
                                                 Message::error(String::from("'%include' must be supplied with include path"))
+                                                .with_id(Code::MalformedDirective)
                                                 .with_code(String::from("expected string"), span)
                                             );
                                             
@@ -80,15 +518,15 @@ impl Preprocessor {
 
                                     self.advance();
                                     
-                                    let mut include_path = percent.span.source.path.clone();
-                                    include_path.pop();
-                                    include_path.push(&path_span.value);
+                                    let include_path = resolve_path(&percent.span, &path_span.value);
+                                    self.opened.borrow_mut().push(include_path.clone());
 
                                     let text = match fs::read_to_string(&include_path) {
                                         Ok(program) => program,
                                         Err(_) => {
                                             errors.push(
                                                 Message::error(format!("no such file: {}", include_path.display()))
+                                                    .with_id(Code::FileNotFound)
                                                     .with_code(String::from("invalid include path"), path_span.span.clone())
                                             );
 
@@ -96,28 +534,244 @@ impl Preprocessor {
                                         }
                                     };
 
-                                    let source = Rc::new(Source {
-                                        text,
-                                        path: include_path
-                                    });
+                                    let source = Rc::new(Source::new(text, include_path));
 
-                                    let (tokens, mut w) = Lexer::new(&source).lex()?;
-                                    warnings.append(&mut w);
+                                    // errors are collected rather than returned, so that an
+                                    // enclosing block still reaches its '%end'
+                                    let tokens = match Lexer::new(&source).lex() {
+                                        Ok((tokens, mut w)) => {
+                                            warnings.append(&mut w);
+                                            tokens
+                                        },
+                                        Err(mut e) => {
+                                            errors.append(&mut e);
+
+                                            continue;
+                                        }
+                                    };
 
-                                    let mut preprocessor = Preprocessor::from(tokens);
+                                    let mut preprocessor = self.child(tokens);
 
                                     let mut child_scope = Scope::new(Some(scope));
-                                    let ((), mut w) = preprocessor.preprocess(&mut child_scope)?;
-                                    scope.extend(child_scope.extract());
-                                    warnings.append(&mut w);
+                                    match preprocessor.preprocess(&mut child_scope) {
+                                        Ok(((), mut w)) => {
+                                            scope.extend(child_scope.extract());
+                                            warnings.append(&mut w);
+                                        },
+                                        Err(mut e) => errors.append(&mut e)
+                                    }
+                                }
+                                "module" => {
+                                    let name = match self.advance().clone() {
+                                        Some(WithSpan { value: TokenKind::Word(name), span }) => WithSpan { value: name, span },
+                                        token => {
+                                            errors.push(
+                                                Message::error(String::from("'%module' must be supplied with a name"))
+                                                    .with_id(Code::MalformedDirective)
+                                                    .with_code(String::from("expected identifier"), token.map(|token| token.span).unwrap_or(span))
+                                            );
+                                            self.skip_block();
+
+                                            continue;
+                                        }
+                                    };
+
+                                    if let Some(WithSpan { value, span }) = self.advance().clone() {
+                                        if value != TokenKind::NewLine {
+                                            errors.push(
+                                                Message::error(String::from("'%module' must be followed by a new line"))
+                                                    .with_id(Code::MalformedDirective)
+                                                    .with_code(String::from("expected new line"), span)
+                                            );
+                                            self.skip_block();
 
+                                            continue;
+                                        }
+                                    }
+                                    self.advance();
+
+                                    // the parser sees the block as `%module name` ... `%end`
+                                    let end = Span { begin: percent.span.begin, end: name.span.end, ..percent.span.clone() };
+                                    scope.tokens.push(percent.clone());
+                                    scope.tokens.push(TokenKind::Word(String::from("module")).with_span(span));
+                                    scope.tokens.push(TokenKind::Word(name.value).with_span(name.span));
+                                    scope.tokens.push(TokenKind::NewLine.with_span(end.clone()));
+
+                                    let mut child_scope = Scope::new(Some(scope));
+                                    match self.block(&mut child_scope) {
+                                        Ok(((), mut w)) => {
+                                            warnings.append(&mut w);
+                                            scope.extend(child_scope.extract());
+                                        },
+                                        Err(mut e) => errors.append(&mut e)
+                                    }
+
+                                    scope.tokens.push(TokenKind::Percent.with_span(end.clone()));
+                                    scope.tokens.push(TokenKind::Word(String::from("end")).with_span(end.clone()));
+                                    scope.tokens.push(TokenKind::NewLine.with_span(end));
+                                }
+                                "struct" | "enum" => {
+                                    let layout_name = match self.advance().clone() {
+                                        Some(WithSpan { value: TokenKind::Word(name), span }) => WithSpan { value: name, span },
+                                        token => {
+                                            errors.push(
+                                                Message::error(format!("'%{}' must be supplied with a name", name))
+                                                    .with_id(Code::MalformedDirective)
+                                                    .with_code(String::from("expected identifier"), token.map(|token| token.span).unwrap_or(span))
+                                            );
+                                            self.skip_block();
+
+                                            continue;
+                                        }
+                                    };
+                                    self.advance();
+
+                                    // members are expanded like any other tokens
+                                    let mut child_scope = Scope::new(Some(scope));
+                                    if let Err(mut e) = self.block(&mut child_scope) {
+                                        errors.append(&mut e);
+
+                                        continue;
+                                    }
+                                    let (tokens, symbols, allows) = child_scope.extract();
+                                    scope.extend((vec![], symbols, allows));
+
+                                    match layout(&name, &layout_name, tokens) {
+                                        Ok(tokens) => scope.tokens.extend(tokens),
+                                        Err(error) => errors.push(error)
+                                    }
+
+                                    // members needn't all be used
+                                    scope.allows.push(Allow {
+                                        lint: Code::UnusedLabel,
+                                        path: percent.span.source.path.clone(),
+                                        begin: percent.span.begin,
+                                        end: self.current.as_ref().map(|token| token.span.begin).unwrap_or(usize::MAX)
+                                    });
+                                }
+                                "clobbers" | "preserves" | "export" => {
+                                    scope.tokens.push(percent);
+                                    scope.tokens.push(WithSpan { value: TokenKind::Word(name), span });
+
+                                    while let Some(token) = self.advance().clone() {
+                                        if token.value == TokenKind::NewLine {
+                                            scope.tokens.push(token);
+                                            break;
+                                        }
+
+                                        scope.tokens.push(token);
+                                    }
+                                }
+                                "assert" => {
+                                    // the condition is left to expand like any other tokens
+                                    scope.tokens.push(percent);
+                                    scope.tokens.push(WithSpan { value: TokenKind::Word(name), span });
+                                    self.advance();
+
+                                    continue;
+                                }
+                                "error" | "warning" => {
+                                    let message = match self.arguments() {
+                                        Ok(arguments) => match arguments.as_slice() {
+                                            [text] => string_argument(text, "message"),
+                                            _ => Err(
+                                                Message::error(format!("'%{}' takes 1 argument, but {} were supplied", name, arguments.len()))
+                                                    .with_id(Code::MalformedDirective)
+                                                    .with_code(String::from("wrong number of arguments"), span.clone())
+                                                    .with_note(format!("usage: %{} \"message\"", name))
+                                            )
+                                        },
+                                        Err(error) => Err(error)
+                                    };
+
+                                    match message {
+                                        Ok(message) if name == "error" => errors.push(
+                                            Message::error(message.value)
+                                                .with_id(Code::UserError)
+                                                .with_code(String::from("raised here"), Span { end: message.span.end, ..percent.span.clone() })
+                                        ),
+                                        Ok(message) => warnings.push(
+                                            Message::warning(message.value)
+                                                .with_id(Code::UserWarning)
+                                                .with_code(String::from("raised here"), Span { end: message.span.end, ..percent.span.clone() })
+                                        ),
+                                        Err(error) => {
+                                            errors.push(error);
+
+                                            continue;
+                                        }
+                                    }
+                                }
+                                "incbin" | "incimage" => {
+                                    let arguments = match self.arguments() {
+                                        Ok(arguments) => arguments,
+                                        Err(error) => {
+                                            errors.push(error);
+
+                                            continue;
+                                        }
+                                    };
+
+                                    let bytes = match (name.as_str(), arguments.as_slice()) {
+                                        ("incbin", [path]) => string_argument(path, "include path")
+                                            .and_then(|path| read_binary(&path, &self.opened)),
+                                        ("incbin", [path, offset, length]) => string_argument(path, "include path")
+                                            .and_then(|path| {
+                                                let offset = number_argument(offset, "offset")? as usize;
+                                                let length = number_argument(length, "length")? as usize;
+                                                let bytes = read_binary(&path, &self.opened)?;
+
+                                                bytes.get(offset..offset + length)
+                                                    .map(|bytes| bytes.to_vec())
+                                                    .ok_or(
+                                                        Message::error(format!(
+                                                            "range {}..{} is out of bounds for '{}' ({})",
+                                                            offset, offset + length, &path.value,
+                                                            human_count("byte", bytes.len())
+                                                        ))
+                                                            .with_id(Code::RangeOutOfBounds)
+                                                            .with_code(
+                                                                String::from("out of bounds"),
+                                                                Span::new(arguments[1].span.begin, arguments[2].span.end, Rc::clone(&arguments[1].span.source))
+                                                            )
+                                                    )
+                                            }),
+                                        ("incimage", [path, mode]) => string_argument(path, "image path")
+                                            .and_then(|path| convert_image(&read_binary(&path, &self.opened)?, &path, mode)),
+                                        _ => Err(
+                                            Message::error(format!(
+                                                "'%{}' takes {}, but {} were supplied",
+                                                name,
+                                                if name == "incbin" { "1 or 3 arguments" } else { "2 arguments" },
+                                                arguments.len()
+                                            ))
+                                                .with_id(Code::MalformedDirective)
+                                                .with_code(String::from("wrong number of arguments"), span.clone())
+                                                .with_note(String::from(
+                                                    if name == "incbin" { "usage: %incbin \"file\"[, offset, length], where offset and length are at most 255" }
+                                                    else { "usage: %incimage \"file\", threshold|pack" }
+                                                ))
+                                        )
+                                    };
+
+                                    match bytes {
+                                        Ok(bytes) => {
+                                            let end = arguments.last().map(|argument| argument.span.end).unwrap_or(span.end);
+                                            let span = Span::new(percent.span.begin, end, Rc::clone(&percent.span.source));
+
+                                            scope.tokens.extend(bytes.into_iter()
+                                                .map(|byte| TokenKind::Number(byte).with_span(span.clone())));
+                                        },
+                                        Err(error) => errors.push(error)
+                                    }
                                 }
                                 "define" => {
-                                    let symbol = match self.advance() {
-                                        Some(WithSpan { value: TokenKind::Word(symbol), .. }) => symbol.clone(),
+                                    let (symbol, symbol_span) = match self.advance() {
+                                        Some(WithSpan { value: TokenKind::Word(symbol), span }) => (symbol.clone(), span.clone()),
                                         Some(WithSpan { span, .. }) => {
                                             errors.push(
                                                 Message::error(format!("expected symbol, found '{}'", span.get_text()))
+                                                    .with_id(Code::MalformedDirective)
                                                     .with_code(String::from("expected identifier"), span.clone())
                                             );
 
@@ -126,6 +780,7 @@ impl Preprocessor {
                                         None => {
                                             errors.push(
                                                 Message::error(String::from("'%define' must be supplied with symbol"))
+                                                    .with_id(Code::MalformedDirective)
                                                     .with_code(String::from("expected identifier"), span.clone())
                                             );
 
@@ -140,8 +795,11 @@ impl Preprocessor {
                                         match &current.value {
                                             TokenKind::Backslash => {
                                                 self.advance();
-                                                definition.push(self.current.as_ref().unwrap().value.clone());
-                                                self.advance();
+                                                // a trailing '\' at the end of the file continues nothing
+                                                if let Some(next) = &self.current {
+                                                    definition.push(next.value.clone());
+                                                    self.advance();
+                                                }
                                             }
                                             TokenKind::NewLine => break,
                                             _ => {
@@ -151,7 +809,17 @@ impl Preprocessor {
                                         }
                                     }
 
-                                    scope.symbols.insert(symbol, definition);
+                                    scope.symbols.insert(symbol, Macro { span: symbol_span, parameters: None, body: definition });
+                                }
+                                "macro" => match self.make_macro(&span) {
+                                    Ok((name, definition)) => {
+                                        scope.symbols.insert(name, definition);
+                                    },
+                                    Err(error) => {
+                                        errors.push(error);
+
+                                        continue;
+                                    }
                                 }
                                 "ifndef" => {
                                     let symbol = match self.advance() {
@@ -159,6 +827,7 @@ impl Preprocessor {
                                         Some(WithSpan { span, .. }) => {
                                             errors.push(
                                                 Message::error(format!("expected symbol, found '{}'", span.get_text()))
+                                                    .with_id(Code::MalformedDirective)
                                                     .with_code(String::from("expected identifier"), span.clone())
                                             );
 
@@ -167,6 +836,7 @@ impl Preprocessor {
                                         None => {
                                             errors.push(
                                                 Message::error(String::from("'%ifndef' must be supplied with symbol"))
+                                                    .with_id(Code::MalformedDirective)
                                                     .with_code(String::from("expected identifier"), span.clone())
                                             );
 
@@ -177,16 +847,54 @@ impl Preprocessor {
                                     self.advance();
 
                                     let mut child_scope = Scope::new(Some(scope));
-                                    self.preprocess(&mut child_scope)?;
+                                    let result = self.block(&mut child_scope);
 
-                                    let ndef = scope.get_symbol(&symbol) == None;
+                                    // skipped blocks are still preprocessed, but their messages are dropped
+                                    let ndef = scope.get_symbol(&symbol).is_none();
                                     if ndef {
-                                        scope.extend(child_scope.extract());
-                                    }   
+                                        match result {
+                                            Ok(((), mut w)) => {
+                                                warnings.append(&mut w);
+                                                scope.extend(child_scope.extract());
+                                            },
+                                            Err(mut e) => errors.append(&mut e)
+                                        }
+                                    }
+                                }
+                                "allow" => {
+                                    match self.lints() {
+                                        Ok(lints) => scope.allows.extend(lints.into_iter().map(|lint| Allow {
+                                            lint,
+                                            path: percent.span.source.path.clone(),
+                                            begin: percent.span.begin,
+                                            end: usize::MAX
+                                        })),
+                                        Err(error) => {
+                                            errors.push(error);
+
+                                            continue;
+                                        }
+                                    }
+                                }
+                                "end" if self.blocks == 0 => {
+                                    errors.push(
+                                        Message::error(String::from("'%end' does not close a block"))
+                                            .with_id(Code::MalformedDirective)
+                                            .with_code(String::from("unmatched '%end'"), Span::new(percent.span.begin, span.end, Rc::clone(&span.source)))
+                                            .with_note(String::from("'%end' closes '%ifndef', '%macro', '%module', '%struct' and '%enum'"))
+                                    );
+
+                                    self.advance();
                                 }
                                 "end" => {
                                     self.advance();
 
+                                    for allow in &mut scope.allows {
+                                        if allow.end == usize::MAX && allow.path == percent.span.source.path {
+                                            allow.end = percent.span.begin;
+                                        }
+                                    }
+
                                     return if errors.is_empty() {
                                         Ok(((), warnings))
                                     } else {
@@ -194,10 +902,14 @@ impl Preprocessor {
                                     }
                                 }
                                 _ => {
-                                    errors.push(
-                                        Message::error(format!("use of undeclared macro: '{}'", name))
-                                            .with_code(String::from("unknown macro"), span.clone()),
-                                    );
+                                    let message = Message::error(format!("use of undeclared macro: '{}'", name))
+                                        .with_id(Code::UnknownDirective)
+                                        .with_code(String::from("unknown macro"), span.clone());
+
+                                    errors.push(match did_you_mean(&name, DIRECTIVES.iter().copied()) {
+                                        Some(note) => message.with_note(note),
+                                        None => message
+                                    });
 
                                     self.advance();
 
@@ -210,12 +922,14 @@ impl Preprocessor {
 
                             errors.push(
                                 Message::error(format!("expected macro name, found '{}'", &span.get_text()))
+                                    .with_id(Code::MalformedDirective)
                                     .with_code(String::from("expected identifier"), span.clone())
                             )
                         },
                         None => {
                             errors.push(
                                 Message::error(format!("macro invocation must include macro name"))
+                                    .with_id(Code::MalformedDirective)
                                     .with_code(
                                         String::from("expected identifier"),
                                         Span::new(percent.span.end, percent.span.end + 1, percent.span.source)
@@ -231,6 +945,7 @@ impl Preprocessor {
                         Some(WithSpan { span, .. }) => {
                             errors.push(
                                 Message::error(format!("macro invocations must end with a new line"))
+                                    .with_id(Code::MalformedDirective)
                                     .with_code(
                                         String::from("expected new line"),
                                         span.clone()
@@ -239,16 +954,85 @@ impl Preprocessor {
                         }
                     }
                 }
+                TokenKind::Word(word) if word == "sizeof" && matches!(self.tokens.as_slice(), [WithSpan { value: TokenKind::LeftParen, .. }, ..]) => {
+                    match self.tokens.as_slice() {
+                        [_, WithSpan { value: TokenKind::Word(name), .. }, WithSpan { value: TokenKind::RightParen, span: end }, ..] => {
+                            scope.tokens.push(TokenKind::Word(sizeof(name)).with_span(Span { end: end.end, ..token.span.clone() }));
+
+                            for _ in 0..4 {
+                                self.advance();
+                            }
+                        },
+                        _ => {
+                            errors.push(
+                                Message::error(String::from("'sizeof' must be given the name of a '%struct' or '%enum'"))
+                                    .with_id(Code::InvalidSyntax)
+                                    .with_code(String::from("expected 'sizeof(Name)'"), token.span.clone())
+                            );
+
+                            self.advance();
+                        }
+                    }
+                }
                 TokenKind::Word(word) => {
-                    if let Some(token_stream) = scope.get_symbol(word).cloned() {
-                        let tokens: Vec<Token> = token_stream.into_iter()
-                            .map(|value| Token { value, span: token.span.clone() })
-                            .collect();
-                        let mut preprocessor = Preprocessor::from(tokens);
+                    if let Some(definition) = scope.get_symbol(word).cloned() {
+                        if token.span.expansion_depth() >= self.max_depth {
+                            self.advance();
+
+                            errors.push(
+                                Message::error(format!("recursion limit reached while expanding macro '{}'", word))
+                                    .with_id(Code::MacroRecursionLimit)
+                                    .with_code(String::from("expanded too deeply"), token.span.clone())
+                                    .with_note(format!("macros may expand within each other at most {} times deep, set with '--macro-depth'", self.max_depth))
+                            );
+
+                            continue;
+                        }
+
+                        let arguments = match &definition.parameters {
+                            Some(parameters) => {
+                                let arguments = self.macro_arguments();
+
+                                if arguments.len() != parameters.len() {
+                                    errors.push(
+                                        Message::error(format!("macro '{}' takes {}, but {} were supplied",
+                                            word, human_count("argument", parameters.len()), arguments.len()
+                                        ))
+                                            .with_id(Code::MacroArgumentCount)
+                                            .with_code(String::from("wrong number of arguments"), token.span.clone())
+                                            .with_code_context(String::from("macro defined here"), definition.span.clone())
+                                    );
+
+                                    continue;
+                                }
+
+                                arguments
+                            },
+                            None => vec![]
+                        };
+
+                        let span = token.span.expanded(word.clone(), definition.span.clone());
+                        let counter = self.counter.get();
+                        self.counter.set(counter + 1);
+
+                        let tokens = match definition.substitute(&arguments, counter, &span) {
+                            Ok(tokens) => tokens,
+                            Err(error) => {
+                                errors.push(error);
+                                self.advance();
+
+                                continue;
+                            }
+                        };
+                        let mut preprocessor = self.child(tokens);
                         let mut child_scope = Scope::new(Some(scope));
-                        let ((), mut w) = preprocessor.preprocess(&mut child_scope)?;
-                        scope.extend(child_scope.extract());
-                        warnings.append(&mut w);
+                        match preprocessor.preprocess(&mut child_scope) {
+                            Ok(((), mut w)) => {
+                                scope.extend(child_scope.extract());
+                                warnings.append(&mut w);
+                            },
+                            Err(mut e) => errors.append(&mut e)
+                        }
                     } else {
                         scope.tokens.push(token.clone())
                     }
@@ -270,6 +1054,151 @@ impl Preprocessor {
         }
     }
 
+    /// Collects the comma separated arguments following a macro name or a
+    /// directive, leaving the preprocessor at the terminating new line.
+    fn arguments(&mut self) -> core::result::Result<Vec<Token>, Message> {
+        let mut arguments = vec![];
+
+        loop {
+            match self.advance().clone() {
+                Some(WithSpan { value: TokenKind::NewLine, .. }) | None => break,
+                Some(argument) => arguments.push(argument)
+            }
+
+            match self.advance().clone() {
+                Some(WithSpan { value: TokenKind::Comma, .. }) => (),
+                Some(WithSpan { value: TokenKind::NewLine, .. }) | None => break,
+                Some(WithSpan { span, .. }) => return Err(
+                    Message::error(format!("expected ',', found '{}'", span.get_text()))
+                        .with_id(Code::MalformedDirective)
+                        .with_code(String::from("expected ','"), span)
+                )
+            }
+        }
+
+        Ok(arguments)
+    }
+
+    /// Parses `%macro name [parameter[, parameter]]` and the body up to the
+    /// matching `%end`, leaving the preprocessor at the `end`.
+    fn make_macro(&mut self, directive: &Span) -> core::result::Result<(String, Macro), Message> {
+        let (name, span) = match self.advance().clone() {
+            Some(WithSpan { value: TokenKind::Word(name), span }) => (name, span),
+            token => return Err(
+                Message::error(String::from("'%macro' must be supplied with a name"))
+                    .with_id(Code::MalformedDirective)
+                    .with_code(String::from("expected identifier"), token.map(|token| token.span).unwrap_or(directive.clone()))
+            )
+        };
+
+        let parameters = self.arguments()?.into_iter()
+            .map(|parameter| match parameter.value {
+                TokenKind::Word(word) => Ok(word),
+                _ => Err(
+                    Message::error(format!("expected parameter name, found '{}'", parameter.span.get_text()))
+                        .with_id(Code::MalformedDirective)
+                        .with_code(String::from("expected identifier"), parameter.span)
+                )
+            })
+            .collect::<core::result::Result<Vec<_>, _>>()?;
+
+        let mut body = vec![];
+        let mut depth = 0;
+        self.advance();
+
+        loop {
+            let Some(token) = self.current.clone() else {
+                return Err(
+                    Message::error(format!("macro '{}' must be closed with '%end'", name))
+                        .with_id(Code::MalformedDirective)
+                        .with_code(String::from("unclosed macro"), span)
+                );
+            };
+
+            if token.value == TokenKind::Percent {
+                match self.advance() {
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if word == "end" && depth == 0 => break,
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if word == "end" => depth -= 1,
+                    Some(WithSpan { value: TokenKind::Word(word), .. }) if BLOCKS.contains(&word.as_str()) => depth += 1,
+                    _ => ()
+                }
+
+                body.push(token.value);
+                continue;
+            }
+
+            body.push(token.value);
+            self.advance();
+        }
+
+        self.advance();
+
+        Ok((name, Macro { span, parameters: Some(parameters), body }))
+    }
+
+    /// Collects the comma separated arguments of a macro invocation, leaving
+    /// the preprocessor at the terminating new line.
+    fn macro_arguments(&mut self) -> Vec<Vec<Token>> {
+        let mut arguments: Vec<Vec<Token>> = vec![];
+        let mut depth = 0;
+
+        while let Some(token) = self.advance().clone() {
+            match token.value {
+                TokenKind::NewLine => break,
+                TokenKind::Comma if depth == 0 => {
+                    arguments.push(vec![]);
+                    continue;
+                },
+                TokenKind::LeftParen => depth += 1,
+                TokenKind::RightParen => depth -= 1,
+                _ => ()
+            }
+
+            match arguments.last_mut() {
+                Some(argument) => argument.push(token),
+                None => arguments.push(vec![token])
+            }
+        }
+
+        arguments
+    }
+
+    /// Collects the parenthesized lint names of `%allow(...)`, leaving the
+    /// preprocessor after the closing parenthesis.
+    fn lints(&mut self) -> core::result::Result<Vec<Code>, Message> {
+        let mut lints = vec![];
+
+        let expected = |token: &Option<Token>, what: &str| match token {
+            Some(WithSpan { span, .. }) => Message::error(format!("expected {}, found '{}'", what, span.get_text()))
+                .with_id(Code::MalformedDirective)
+                .with_code(format!("expected {}", what), span.clone())
+                .with_note(String::from("usage: %allow(lint[, lint])")),
+            None => Message::error(format!("expected {}, found end of file", what))
+                .with_id(Code::MalformedDirective)
+        };
+
+        if !matches!(self.advance(), Some(WithSpan { value: TokenKind::LeftParen, .. })) {
+            return Err(expected(&self.current, "'('"));
+        }
+
+        loop {
+            match self.advance().clone() {
+                Some(WithSpan { value: TokenKind::Word(name), span }) => lints.push(lint::lookup(&name, Some(span))?),
+                token => return Err(expected(&token, "lint name"))
+            }
+
+            match self.advance().clone() {
+                Some(WithSpan { value: TokenKind::Comma, .. }) => (),
+                Some(WithSpan { value: TokenKind::RightParen, .. }) => break,
+                token => return Err(expected(&token, "',' or ')'"))
+            }
+        }
+
+        self.advance();
+
+        Ok(lints)
+    }
+
     fn advance(&mut self) -> &Option<Token> {
         self.current = self.tokens.next();
         &self.current
@@ -280,10 +1209,408 @@ impl From<Vec<Token>> for Preprocessor {
     fn from(value: Vec<Token>) -> Self {
         let mut out = Self {
             tokens: value.into_iter(),
-            current: None
+            current: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            counter: Rc::new(Cell::new(0)),
+            opened: Rc::default(),
+            blocks: 0
         };
 
         out.advance();
         out
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{build, preprocess, Options};
+
+    fn source(text: &str) -> Source {
+        Source::new(text.to_string(), PathBuf::from("test.pasm"))
+    }
+
+    fn bytes(text: &str) -> Vec<u8> {
+        match build(source(text), &Options::default()) {
+            Ok((image, _)) => image.bytes,
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        match build(source(text), &Options::default()) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => errors.into_iter().filter_map(|error| error.code).collect()
+        }
+    }
+
+    /// Asserts `text` assembles to the same image before and after being
+    /// written back as source, as with `pasm -E`.
+    fn assert_round_trips(text: &str) {
+        let options = Options::default();
+        let (image, _) = build(source(text), &options).expect("source should assemble");
+
+        let (scope, _) = preprocess(source(text), &options).expect("source should preprocess");
+        let mut written = vec![];
+        write_source(&scope.tokens, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+
+        match build(source(&written), &options) {
+            Ok((preprocessed, _)) => assert_eq!(image.bytes, preprocessed.bytes, "preprocessed source:\n{}", written),
+            Err(errors) => panic!("preprocessed source doesn't assemble: {:?}\n{}", errors, written)
+        }
+    }
+
+    #[test]
+    fn defines_round_trip() {
+        assert_round_trips("\
+%define COUNT 3
+%ifndef GUARD
+%define GUARD
+start:
+ put rx, COUNT
+ put ry, 'a'
+%end
+%ifndef GUARD
+ put rz, 1
+%end
+ jmp start
+");
+    }
+
+    #[test]
+    fn macros_round_trip() {
+        assert_round_trips("\
+%define COUNT 3
+%macro load register, value
+ put register, value
+%end
+%macro named name
+name##_label:
+ %assert 1, #name
+%end
+start:
+ load rx, COUNT
+ named loop
+ jmp start
+");
+    }
+
+    #[test]
+    fn modules_round_trip() {
+        assert_round_trips("\
+ psh back
+ jmp routine
+back:
+ hlt
+%module inner
+%export routine
+routine:
+ jmp helper
+helper:
+ ret
+%end
+");
+    }
+
+    #[test]
+    fn macros_keep_nested_modules() {
+        assert_eq!(bytes("\
+%macro routine name
+%module name
+%export name
+name:
+ ret
+%end
+%end
+ jmp foo
+routine foo
+ hlt
+"), bytes("\
+ jmp foo
+foo:
+ ret
+ hlt
+"));
+    }
+
+    #[test]
+    fn rejects_stray_end() {
+        assert!(matches!(codes(" hlt\n%end\n")[..], [Code::MalformedDirective]));
+        assert!(matches!(codes("%module a\n hlt\n%end\n%end\n")[..], [Code::MalformedDirective]));
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        assert_round_trips("\
+%define WIDTH 4
+SIZE = WIDTH * 2
+HALF = SIZE / 2 - (end - start)
+start:
+ put rx, SIZE
+ put ry, HALF
+end:
+ hlt
+");
+    }
+
+    #[test]
+    fn anonymous_labels_and_locations_round_trip() {
+        assert_round_trips("\
+ jmp :+
+:
+ jmp $+2
+ jmp :-
+:
+ hlt
+");
+    }
+
+    #[test]
+    fn layouts_round_trip() {
+        assert_round_trips("\
+%struct Point
+ x
+ y 2
+%end
+%enum Dir
+ UP = 4
+ DOWN
+%end
+SIZE = sizeof(Point) * 2
+ put rx, sizeof(Point)
+ put ry, Point.y
+ put rz, SIZE
+ hlt
+value: Dir.DOWN
+");
+    }
+
+    #[test]
+    fn macros_keep_nested_layouts() {
+        assert_eq!(bytes("\
+%macro directions name
+%enum name
+ UP
+ DOWN
+%end
+%struct name##Box
+ x
+ y 2
+%end
+%end
+directions Dir
+ put rx, Dir.DOWN
+ put ry, DirBox.y
+ put rz, sizeof(DirBox)
+ hlt
+"), bytes("\
+ put rx, 1
+ put ry, 1
+ put rz, 3
+ hlt
+"));
+    }
+
+    #[test]
+    fn sizeof_is_accepted_in_operands() {
+        assert_eq!(
+            bytes("%struct Point\n x\n y\n%end\n put rx, sizeof(Point)\n hlt\n"),
+            bytes(" put rx, 2\n hlt\n")
+        );
+    }
+
+    #[test]
+    fn literals_round_trip() {
+        assert_round_trips("\
+ put rx, '\\t'
+ put ry, '\\''
+ put rz, '\\x7f'
+ put rx, '\\\\'
+ %assert 1, \"a \\\"quoted\\\"\\tmessage\\x01\"
+\thlt\r
+");
+    }
+
+    fn preprocessed(text: &str) -> Vec<TokenKind> {
+        let (scope, _) = preprocess(source(text), &Options::default()).expect("source should preprocess");
+
+        scope.tokens.into_iter().map(|token| token.value).collect()
+    }
+
+    #[test]
+    fn pastes_tokens() {
+        assert_eq!(
+            bytes("%macro jump n\n jmp label_##n\n%end\n jump 2\nlabel_1:\n hlt\nlabel_2:\n hlt\n"),
+            bytes(" jmp label_2\nlabel_1:\n hlt\nlabel_2:\n hlt\n")
+        );
+        assert!(matches!(codes("%define BAD ## rx\n BAD\n")[..], [Code::InvalidPaste]));
+        assert!(matches!(codes("%define BAD rx ## ,\n BAD\n")[..], [Code::InvalidPaste]));
+    }
+
+    #[test]
+    fn stringifies_arguments() {
+        assert!(preprocessed("%macro name a\n#a\n%end\nname rx\n").contains(&TokenKind::String(String::from("rx"))));
+        assert!(matches!(codes("%macro name a\n#b\n%end\nname rx\n")[..], [Code::InvalidPaste]));
+    }
+
+    #[test]
+    fn counts_expansions() {
+        let counters: Vec<_> = preprocessed("%define COUNT \\@\n COUNT\n COUNT\n").into_iter()
+            .filter_map(|token| match token {
+                TokenKind::Number(counter) => Some(counter),
+                _ => None
+            })
+            .collect();
+
+        assert!(matches!(counters[..], [a, b] if a != b));
+        // each expansion gets its own labels
+        assert_eq!(
+            bytes("%macro skip\n jmp skip_##\\@\nskip_##\\@:\n%end\n skip\n skip\n hlt\n"),
+            [9, 2, 9, 4, 3]
+        );
+    }
+
+    #[test]
+    fn limits_macro_recursion() {
+        assert!(matches!(codes("%define A A\n A\n")[..], [Code::MacroRecursionLimit]));
+        assert!(matches!(codes("%define A B\n%define B A\n A\n")[..], [Code::MacroRecursionLimit]));
+    }
+
+    #[test]
+    fn macro_depth_is_configurable() {
+        let text = "%define ONE 1\n%define TWO ONE\n%define THREE TWO\n put rx, THREE\n hlt\n";
+        let shallow = Options { macro_depth: 2, ..Options::default() };
+
+        assert_eq!(bytes(text), bytes(" put rx, 1\n hlt\n"));
+        match build(source(text), &shallow) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => assert!(matches!(errors[..], [Message { code: Some(Code::MacroRecursionLimit), .. }]))
+        }
+    }
+
+    #[test]
+    fn expanded_tokens_record_their_macros() {
+        let errors = match build(source("%define TARGET nowhere\n%define GO jmp TARGET\n GO\n"), &Options::default()) {
+            Ok(_) => panic!("source shouldn't assemble"),
+            Err(errors) => errors
+        };
+        let span = errors[0].span().expect("error should point at the label");
+        let chain: Vec<_> = span.expansion.as_ref().unwrap().chain()
+            .map(|expansion| (expansion.name.as_str(), expansion.definition.row_num()))
+            .collect();
+
+        assert!(matches!(errors[0].code, Some(Code::UndeclaredLabel)));
+        assert_eq!(chain, [("TARGET", 1), ("GO", 2)]);
+    }
+
+    /// Writes `files` into a fresh directory named after `test` and returns the
+    /// path of a source file in it, so relative include paths find them.
+    fn source_dir(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pasm-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (name, bytes) in files {
+            fs::write(dir.join(name), bytes).unwrap();
+        }
+
+        dir.join("test.pasm")
+    }
+
+    fn build_at(path: PathBuf, text: &str) -> Result<Vec<u8>> {
+        build(Source::new(text.to_string(), path), &Options::default())
+            .map(|(image, warnings)| (image.bytes, warnings))
+    }
+
+    #[test]
+    fn reports_user_messages_and_assertions() {
+        match build(source("%warning \"careful\"\n hlt\n"), &Options::default()) {
+            Ok((image, warnings)) => {
+                assert_eq!(image.bytes, [3]);
+                assert!(matches!(warnings[..], [Message { code: Some(Code::UserWarning), .. }]));
+            },
+            Err(errors) => panic!("source doesn't assemble: {:?}", errors)
+        }
+
+        assert!(matches!(codes("%error \"stop\"\n hlt\n")[..], [Code::UserError]));
+        assert_eq!(bytes("start:\n hlt\n%assert end - start == 1, \"too long\"\nend:\n"), [3]);
+        assert!(matches!(codes("start:\n hlt\n hlt\n%assert end - start == 1, \"too long\"\nend:\n")[..], [Code::AssertionFailed]));
+    }
+
+    #[test]
+    fn skipped_blocks_drop_nested_errors() {
+        let text = "%define OOPS %error \"oops\"\n%ifndef GUARD\nOOPS\n put rx, 1\n%end\n hlt\n";
+
+        assert_eq!(bytes(&format!("%define GUARD\n{}", text)), bytes(" hlt\n"));
+        assert!(matches!(codes(text)[..], [Code::UserError]));
+    }
+
+    #[test]
+    fn skipped_blocks_drop_errors_in_included_files() {
+        let path = source_dir("skipped-include", &[("broken.pasm", b" put rx, 300\n")]);
+        let text = "%ifndef GUARD\n%include \"broken.pasm\"\n%end\n hlt\n";
+
+        assert_eq!(build_at(path.clone(), &format!("%define GUARD\n{}", text)).unwrap().0, [3]);
+        assert!(matches!(
+            build_at(path, text).unwrap_err().into_iter().filter_map(|error| error.code).collect::<Vec<_>>()[..],
+            [Code::NumberOutOfRange]
+        ));
+    }
+
+    #[test]
+    fn incbin_splices_bytes() {
+        let path = source_dir("incbin", &[("data.bin", &[1, 2, 3, 4, 5])]);
+        let (bytes, _) = build_at(path, "%incbin \"data.bin\"\n%incbin \"data.bin\", 1, 2\n").unwrap();
+
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 2, 3]);
+    }
+
+    #[test]
+    fn incbin_rejects_invalid_ranges() {
+        let path = source_dir("incbin-range", &[("data.bin", &[1, 2, 3])]);
+
+        let codes = |text: &str| build_at(path.clone(), text).unwrap_err().into_iter().filter_map(|error| error.code).collect::<Vec<_>>();
+
+        assert!(matches!(codes("%incbin \"data.bin\", 2, 2\n")[..], [Code::RangeOutOfBounds]));
+        assert!(matches!(codes("%incbin \"data.bin\", 1\n")[..], [Code::MalformedDirective]));
+        assert!(matches!(codes("%incbin \"missing.bin\"\n")[..], [Code::FileNotFound]));
+    }
+
+    #[test]
+    fn incimage_converts_pixels() {
+        use image::{DynamicImage, GrayAlphaImage, ImageOutputFormat, LumaA};
+
+        // a 10x1 image of alternating set and clear pixels
+        let image = GrayAlphaImage::from_fn(10, 1, |x, _| LumaA([if x % 2 == 0 { 0xff } else { 0 }, 0xff]));
+        let mut png = vec![];
+        DynamicImage::ImageLumaA8(image).write_to(&mut io::Cursor::new(&mut png), ImageOutputFormat::Png).unwrap();
+
+        let path = source_dir("incimage", &[("image.png", &png)]);
+
+        let (bytes, _) = build_at(path.clone(), "%incimage \"image.png\", threshold\n").unwrap();
+        assert_eq!(bytes, [1, 0, 1, 0, 1, 0, 1, 0, 1, 0]);
+
+        let (bytes, _) = build_at(path.clone(), "%incimage \"image.png\", pack\n").unwrap();
+        assert_eq!(bytes, [0b10101010, 0b10000000]);
+
+        let errors = build_at(path, "%incimage \"image.png\", invert\n").unwrap_err();
+        assert!(matches!(errors[0].code, Some(Code::InvalidImage)));
+    }
+
+    #[test]
+    fn records_opened_files() {
+        let path = source_dir("opened", &[
+            ("outer.pasm", b"%include \"inner.pasm\"\n"),
+            ("inner.pasm", b"%incbin \"data.bin\"\n"),
+            ("data.bin", &[1])
+        ]);
+        let dir = path.parent().unwrap().to_path_buf();
+        let options = Options::default();
+
+        let text = "%include \"outer.pasm\"\n%ifndef SKIPPED\n%include \"missing.pasm\"\n%end\n";
+        assert!(build(Source::new(text.to_string(), path), &options).is_err());
+
+        // files that can't be read are watched too, for when they're created
+        assert_eq!(*options.opened.borrow(), ["outer.pasm", "inner.pasm", "data.bin", "missing.pasm"].map(|name| dir.join(name)));
+    }
+}
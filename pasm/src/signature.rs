@@ -66,24 +66,32 @@ pub fn parse_arguments(tokens: &Vec<Token>) -> Vec<Argument> {
                 _    => Argument::Im
             }
             | TokenKind::Character(..)
-            | TokenKind::Number(..) => Argument::Im,
+            | TokenKind::Number(..)
+            | TokenKind::Location(..)
+            | TokenKind::AnonymousLabel(..)
+            // rejected when compiled, with a diagnostic
+            | TokenKind::String(..) => Argument::Im,
             _                      => unreachable!("should be handled by the parser")
         })
         .collect()
 }
 
+pub fn format_signature(name: &str, signature: &Signature) -> String {
+    format!(
+        "{} {}",
+        name,
+        signature.arguments.iter()
+            .map(|arg| arg.assembly_name())
+            .collect::<Vec<&'static str>>()
+            .join(",")
+    )
+}
+
 pub fn format_instruction_code(code: u8) -> Option<String> {
     for (name, instruction) in &*INSTRUCTIONS {
         for signature in &instruction.signatures {
             if signature.code == code {
-                return Some(format!(
-                    "{} {}",
-                    name,
-                    signature.arguments.iter()
-                        .map(|arg| arg.assembly_name())
-                        .collect::<Vec<&'static str>>()
-                        .join(",")
-                ));
+                return Some(format_signature(name, signature));
             }
         }
     }
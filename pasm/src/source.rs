@@ -2,7 +2,39 @@ use std::{path::PathBuf, rc::Rc, fmt::Debug};
 
 pub struct Source {
     pub text: String,
-    pub path: PathBuf
+    pub path: PathBuf,
+    /// the byte offset every line begins at
+    line_begins: Vec<usize>
+}
+
+impl Source {
+    pub fn new(text: String, path: PathBuf) -> Self {
+        let line_begins = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        Self { text, path, line_begins }
+    }
+
+    /// Returns the index of the line containing the byte `offset`.
+    pub fn line_index(&self, offset: usize) -> usize {
+        match self.line_begins.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1
+        }
+    }
+
+    /// Returns the byte offset the line `line` begins at.
+    pub fn line_begin(&self, line: usize) -> usize {
+        self.line_begins[line]
+    }
+
+    /// Returns the text of the line `line`, without its line ending.
+    pub fn line(&self, line: usize) -> &str {
+        let end = self.line_begins.get(line + 1).copied().unwrap_or(self.text.len());
+
+        self.text[self.line_begins[line]..end].trim_end_matches(['\n', '\r'])
+    }
 }
 
 impl Debug for Source {
@@ -11,52 +43,82 @@ impl Debug for Source {
     }
 }
 
+/// The expansion of a macro that produced a token, linked to the expansion
+/// the macro was itself invoked from.
+#[derive(Debug)]
+pub struct Expansion {
+    pub name: String,
+    /// the name of the macro in its definition
+    pub definition: Span,
+    pub parent: Option<Rc<Expansion>>
+}
+
+impl Expansion {
+    /// Returns the expansions from the innermost one outwards.
+    pub fn chain(self: &Rc<Self>) -> impl Iterator<Item = &Rc<Expansion>> {
+        std::iter::successors(Some(self), |expansion| expansion.parent.as_ref())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Span {
     pub begin: usize,
     pub end: usize,
-    pub source: Rc<Source>
+    pub source: Rc<Source>,
+    /// the macro expansion this span was produced by, if any
+    pub expansion: Option<Rc<Expansion>>
 }
 
 impl Span {
     pub fn new(begin: usize, end: usize, source: Rc<Source>) -> Self {
         Self {
-            begin, end, source
+            begin, end, source,
+            expansion: None
         }
     }
 
+    /// Returns this span as produced by expanding the macro `name`.
+    pub fn expanded(&self, name: String, definition: Span) -> Self {
+        Self {
+            expansion: Some(Rc::new(Expansion {
+                name,
+                definition,
+                parent: self.expansion.clone()
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the number of nested macro expansions that produced this span.
+    pub fn expansion_depth(&self) -> usize {
+        self.expansion.as_ref().map(|expansion| expansion.chain().count()).unwrap_or(0)
+    }
+
     pub fn row_num(&self) -> usize {
-        1 + self.source.text.chars()
-            .take(self.begin)
-            .filter(|ch| *ch == '\n')
-            .count()
+        self.source.line_index(self.begin) + 1
     }
 
     pub fn row_begin(&self) -> usize {
-        self.source.text.chars()
-            .take(self.begin)
-            .enumerate()
-            .filter(|(_, ch)| *ch == '\n')
-            .last()
-            .map(|(i, _)| i + 1)
-            .unwrap_or(0)
+        self.source.line_begin(self.source.line_index(self.begin))
     }
 
-    pub fn row<'a>(&'a self) -> impl Iterator<Item = char> + 'a {
-        self.source.text.chars()
-            .skip(self.row_begin())
-            .take_while(|ch| *ch != '\n')
+    pub fn row(&self) -> &str {
+        self.source.line(self.source.line_index(self.begin))
     }
 
+    /// Returns the column the span begins at, counted in characters.
     pub fn col_num(&self) -> usize {
-        self.begin + 1 - self.row_begin()
+        let begin = self.begin.min(self.source.text.len());
+
+        1 + self.source.text.get(self.row_begin()..begin).map(|text| text.chars().count()).unwrap_or(0)
     }
 
-    pub fn get_text(&self) -> String {
-        self.source.text.chars()
-            .skip(self.begin)
-            .take(self.end - self.begin)
-            .collect()
+    /// Returns the text of the span, which may extend past the end of the
+    /// source for errors about missing tokens.
+    pub fn get_text(&self) -> &str {
+        let end = self.end.min(self.source.text.len());
+
+        self.source.text.get(self.begin.min(end)..end).unwrap_or_default()
     }
 }
 
@@ -74,4 +136,54 @@ impl<T> IntoWithSpan for T {
     fn with_span(self, span: Span) -> WithSpan<Self> where Self: Sized {
         WithSpan { value: self, span }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(text: &str) -> Rc<Source> {
+        Rc::new(Source::new(text.to_string(), PathBuf::from("test.pasm")))
+    }
+
+    #[test]
+    fn indexes_lines() {
+        let source = source("one\r\ntwo\n\nfour");
+
+        assert_eq!((0..source.text.len()).map(|offset| source.line_index(offset)).collect::<Vec<_>>(), [
+            0, 0, 0, 0, 0,
+            1, 1, 1, 1,
+            2,
+            3, 3, 3, 3
+        ]);
+        assert_eq!(source.line_index(source.text.len()), 3);
+        assert_eq!((0..4).map(|line| source.line_begin(line)).collect::<Vec<_>>(), [0, 5, 9, 10]);
+        assert_eq!((0..4).map(|line| source.line(line)).collect::<Vec<_>>(), ["one", "two", "", "four"]);
+    }
+
+    #[test]
+    fn locates_spans() {
+        let source = source("é:\n put rx, 'ü'\n");
+        let span = |text: &str| {
+            let begin = source.text.rfind(text).unwrap();
+            Span::new(begin, begin + text.len(), Rc::clone(&source))
+        };
+
+        let character = span("'ü'");
+        assert_eq!((character.row_num(), character.col_num()), (2, 10));
+        assert_eq!(character.row_begin(), 4);
+        assert_eq!(character.row(), " put rx, 'ü'");
+        assert_eq!(character.get_text(), "'ü'");
+
+        let label = span(":");
+        assert_eq!((label.row_num(), label.col_num()), (1, 2));
+    }
+
+    #[test]
+    fn clamps_spans_past_the_end() {
+        let source = source("hlt");
+        let span = Span::new(3, 4, Rc::clone(&source));
+
+        assert_eq!(span.get_text(), "");
+        assert_eq!((span.row_num(), span.col_num()), (1, 4));
+    }
+}
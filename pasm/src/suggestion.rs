@@ -0,0 +1,71 @@
+/// Levenshtein distance between `a` and `b`, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + (a != *b) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns the candidate closest to `name`, if any is close enough to be a
+/// plausible typo.
+pub fn suggest<'a, I: IntoIterator<Item = &'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    candidates.into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Formats a "did you mean" note for the closest candidate to `name`.
+pub fn did_you_mean<'a, I: IntoIterator<Item = &'a str>>(name: &str, candidates: I) -> Option<String> {
+    suggest(name, candidates).map(|candidate| format!("did you mean '{}'?", candidate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("loop", "loop"), 0);
+        assert_eq!(edit_distance("halt", "hlt"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+    }
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("naïve", "naive"), 1);
+        assert_eq!(edit_distance("你好", "你"), 1);
+    }
+
+    #[test]
+    fn suggest_picks_the_closest_candidate() {
+        assert_eq!(suggest("halt", ["jmp", "hlt", "add"]), Some("hlt"));
+        assert_eq!(suggest("prnitu", ["printu", "prints", "printi"]), Some("printu"));
+    }
+
+    #[test]
+    fn suggest_ignores_distant_and_identical_names() {
+        assert_eq!(suggest("x", ["completely", "different"]), None);
+        assert_eq!(suggest("loop", ["loop"]), None);
+        assert_eq!(did_you_mean("halt", ["hlt"]).as_deref(), Some("did you mean 'hlt'?"));
+    }
+}
@@ -0,0 +1,115 @@
+use pasm::source::{Span, WithSpan};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or
+}
+
+impl Operator {
+    /// Every operator, longer ones before their prefixes.
+    pub const ALL: &'static [Operator] = &[
+        Operator::Equal,
+        Operator::NotEqual,
+        Operator::LessEqual,
+        Operator::GreaterEqual,
+        Operator::And,
+        Operator::Or,
+        Operator::Plus,
+        Operator::Minus,
+        Operator::Star,
+        Operator::Slash,
+        Operator::Percent,
+        Operator::Bang,
+        Operator::Less,
+        Operator::Greater
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Star => "*",
+            Operator::Slash => "/",
+            Operator::Percent => "%",
+            Operator::Bang => "!",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::And => "&&",
+            Operator::Or => "||"
+        }
+    }
+
+    /// Binding strength of binary operators, loosest first as in C.
+    pub fn precedence(&self) -> Option<u8> {
+        Some(match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal | Operator::NotEqual => 3,
+            Operator::Less | Operator::LessEqual | Operator::Greater | Operator::GreaterEqual => 4,
+            Operator::Plus | Operator::Minus => 5,
+            Operator::Star | Operator::Slash | Operator::Percent => 6,
+            Operator::Bang => return None
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ExpressionKind {
+    Number(u8),
+    /// evaluates to the address of the null-terminated string
+    String(String),
+    Variable(String),
+    Call { name: WithSpan<String>, arguments: Vec<Expression> },
+    Unary(Operator, Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>)
+}
+
+pub type Expression = WithSpan<ExpressionKind>;
+
+#[derive(Debug)]
+pub enum StatementKind {
+    Var { name: WithSpan<String>, value: Option<Expression> },
+    Assign { name: WithSpan<String>, value: Expression },
+    If { condition: Expression, then: Vec<Statement>, otherwise: Vec<Statement> },
+    While { condition: Expression, body: Vec<Statement> },
+    Break,
+    Continue,
+    Return(Option<Expression>),
+    Expression(Expression)
+}
+
+pub type Statement = WithSpan<StatementKind>;
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: WithSpan<String>,
+    pub parameters: Vec<WithSpan<String>>,
+    pub body: Vec<Statement>,
+    /// the closing brace, where falling off the end returns
+    pub end: Span
+}
+
+#[derive(Debug)]
+pub enum ItemKind {
+    Global { name: WithSpan<String>, value: Option<WithSpan<u8>> },
+    Function(Function)
+}
+
+pub type Item = WithSpan<ItemKind>;
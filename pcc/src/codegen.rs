@@ -0,0 +1,666 @@
+use std::collections::{HashMap, BTreeSet};
+
+use pasm::{parser::{Node, NodeKind}, lexer::TokenKind, source::{Span, WithSpan, IntoWithSpan}, message::{Message, Result, human_count}, code::Code};
+
+use crate::ast::{Operator, Expression, ExpressionKind, Statement, StatementKind, Function, Item, ItemKind};
+
+/// A routine of `examples/std`, called when a program uses its name
+/// without declaring a function of its own.
+pub struct Routine {
+    pub name: &'static str,
+    pub arguments: usize,
+    /// the register holding the result, moved to `rx` after the call
+    pub result: Option<&'static str>
+}
+
+pub const RUNTIME: &[Routine] = &[
+    Routine { name: "mul", arguments: 2, result: Some("rz") },
+    Routine { name: "udivmod", arguments: 2, result: Some("rz") },
+    Routine { name: "idivmod", arguments: 2, result: Some("rz") },
+    Routine { name: "uge", arguments: 2, result: Some("rx") },
+    Routine { name: "ige", arguments: 2, result: Some("rx") },
+    Routine { name: "parseu", arguments: 1, result: Some("ry") },
+    Routine { name: "printu", arguments: 1, result: None },
+    Routine { name: "printi", arguments: 1, result: None },
+    Routine { name: "prints", arguments: 1, result: None }
+];
+
+/// Registers holding the arguments of a call, in order.
+const ARGUMENTS: [&str; 3] = ["rx", "ry", "rz"];
+
+fn word(text: &str) -> TokenKind {
+    TokenKind::Word(String::from(text))
+}
+
+/// Returns the label of the function `name`. User symbols are kept apart
+/// from the labels of the std routines and from each other this way.
+fn function_label(name: &str) -> String {
+    format!("pcc::fn::{}", name)
+}
+
+fn global_label(name: &str) -> String {
+    format!("pcc::var::{}", name)
+}
+
+fn redefinition(what: &str, name: &WithSpan<String>, previous: &Span) -> Message {
+    Message::error(format!("redefinition of {} '{}'", what, &name.value))
+        .with_id(Code::LabelRedefinition)
+        .with_code(String::from("already defined"), name.span.clone())
+        .with_code_context(String::from("previously defined here"), previous.clone())
+}
+
+/// Returns whether control never reaches the end of `statements`.
+fn terminates(statements: &[Statement]) -> bool {
+    match statements.last().map(|statement| &statement.value) {
+        Some(StatementKind::Return(_) | StatementKind::Break | StatementKind::Continue) => true,
+        Some(StatementKind::If { then, otherwise, .. }) => terminates(then) && terminates(otherwise),
+        _ => false
+    }
+}
+
+/// The function being generated.
+struct Frame {
+    /// the label of the function
+    name: String,
+    /// variables and parameters, by the labels of their cells
+    locals: HashMap<String, (String, Span)>,
+    /// the `continue` and `break` labels of the enclosing loops
+    loops: Vec<(String, String)>
+}
+
+/// Generates pasm nodes from the items of a program. Every variable is a
+/// byte cell after the code, so functions aren't reentrant. Expressions are
+/// evaluated into `rx`, keeping intermediate values on the stack, and calls
+/// follow the std convention of passing arguments in `rx`, `ry` and `rz`
+/// and pushing the return address.
+#[derive(Default)]
+pub struct Generator {
+    out: Vec<Node>,
+    data: Vec<Node>,
+    globals: HashMap<String, (String, Span)>,
+    /// parameter counts of the declared functions
+    functions: HashMap<String, (usize, Span)>,
+    /// names of the std routines called
+    runtime: BTreeSet<&'static str>,
+    errors: Vec<Message>,
+    count: usize
+}
+
+impl Generator {
+    fn instruction(&mut self, name: &str, arguments: Vec<TokenKind>, span: &Span) {
+        self.out.push(NodeKind::Instruction {
+            name: String::from(name).with_span(span.clone()),
+            arguments: arguments.into_iter().map(|argument| argument.with_span(span.clone())).collect()
+        }.with_span(span.clone()));
+    }
+
+    fn label(&mut self, name: &str, span: &Span) {
+        self.out.push(NodeKind::Label { name: String::from(name).with_span(span.clone()) }.with_span(span.clone()));
+    }
+
+    /// Declares a byte cell named `label` after the code.
+    fn cell(&mut self, label: &str, value: u8, span: &Span) {
+        self.data.push(NodeKind::Label { name: String::from(label).with_span(span.clone()) }.with_span(span.clone()));
+        self.data.push(NodeKind::Value { value: TokenKind::Number(value).with_span(span.clone()) }.with_span(span.clone()));
+    }
+
+    /// Returns a new label in the function of `frame`. It starts with a
+    /// digit, so it can't be the label of a variable.
+    fn unique(&mut self, frame: &Frame, kind: &str) -> String {
+        self.count += 1;
+        format!("{}::{}_{}", frame.name, self.count, kind)
+    }
+
+    /// Calls `routine` by pushing the address after the jump.
+    fn call(&mut self, frame: &Frame, routine: &str, span: &Span) {
+        let ret = self.unique(frame, "ret");
+
+        self.instruction("psh", vec![word(&ret)], span);
+        self.instruction("jmp", vec![word(routine)], span);
+        self.label(&ret, span);
+    }
+
+    /// Sets `rx` to 1 if it's 0, and to 0 otherwise.
+    fn not(&mut self, frame: &Frame, span: &Span) {
+        let (zero, end) = (self.unique(frame, "zero"), self.unique(frame, "end"));
+
+        self.instruction("jpz", vec![word(&zero), word("rx")], span);
+        self.instruction("put", vec![word("rx"), TokenKind::Number(0)], span);
+        self.instruction("jmp", vec![word(&end)], span);
+        self.label(&zero, span);
+        self.instruction("put", vec![word("rx"), TokenKind::Number(1)], span);
+        self.label(&end, span);
+    }
+
+    fn variable(&mut self, frame: &Frame, name: &str, span: &Span) -> Option<String> {
+        match frame.locals.get(name).or_else(|| self.globals.get(name)) {
+            Some((label, _)) => Some(label.clone()),
+            None => {
+                self.errors.push(
+                    Message::error(format!("use of undeclared variable '{}'", name))
+                        .with_id(Code::UndeclaredLabel)
+                        .with_code(String::from("unknown variable"), span.clone())
+                );
+                None
+            }
+        }
+    }
+
+    /// Generates `peek(address)` and `poke(address, value)`, which read and
+    /// write memory and devices.
+    fn builtin(&mut self, frame: &Frame, name: &WithSpan<String>, arguments: &[Expression], span: &Span) -> bool {
+        match (name.value.as_str(), arguments) {
+            ("peek", [address]) => {
+                self.expression(frame, address);
+                self.instruction("put", vec![word("ry"), word("rx")], span);
+                self.instruction("ldr", vec![word("rx"), word("ry")], span);
+            },
+            ("poke", [address, value]) => {
+                self.expression(frame, address);
+                self.instruction("psh", vec![word("rx")], span);
+                self.expression(frame, value);
+                self.instruction("put", vec![word("ry"), word("rx")], span);
+                self.instruction("pop", vec![word("rx")], span);
+                self.instruction("str", vec![word("rx"), word("ry")], span);
+            },
+            ("peek" | "poke", _) => self.errors.push(
+                Message::error(format!("'{}' takes {}, but {} were supplied",
+                    &name.value,
+                    human_count("argument", if name.value == "peek" { 1 } else { 2 }),
+                    arguments.len()
+                ))
+                    .with_id(Code::WrongArgumentCount)
+                    .with_code(String::from("wrong number of arguments"), span.clone())
+            ),
+            _ => return false
+        }
+
+        true
+    }
+
+    fn call_expression(&mut self, frame: &Frame, name: &WithSpan<String>, arguments: &[Expression], span: &Span) {
+        if !self.functions.contains_key(&name.value) && self.builtin(frame, name, arguments, span) {
+            return
+        }
+
+        let (label, expected, result) = match self.functions.get(&name.value) {
+            Some((parameters, _)) => (function_label(&name.value), *parameters, Some("rx")),
+            None => match RUNTIME.iter().find(|routine| routine.name == name.value) {
+                Some(routine) => {
+                    self.runtime.insert(routine.name);
+                    (String::from(routine.name), routine.arguments, routine.result)
+                },
+                None => {
+                    self.errors.push(
+                        Message::error(format!("use of undeclared function '{}'", &name.value))
+                            .with_id(Code::UndeclaredLabel)
+                            .with_code(String::from("unknown function"), name.span.clone())
+                            .with_note(format!("the builtins are 'peek' and 'poke', and the std routines are: {}",
+                                RUNTIME.iter().map(|routine| format!("'{}'", routine.name)).collect::<Vec<_>>().join(", ")
+                            ))
+                    );
+                    return
+                }
+            }
+        };
+
+        if arguments.len() != expected {
+            self.errors.push(
+                Message::error(format!("function '{}' takes {}, but {} were supplied", &name.value, human_count("argument", expected), arguments.len()))
+                    .with_id(Code::WrongArgumentCount)
+                    .with_code(String::from("wrong number of arguments"), span.clone())
+            );
+            return
+        }
+
+        for argument in arguments {
+            self.expression(frame, argument);
+            self.instruction("psh", vec![word("rx")], &argument.span);
+        }
+        for register in ARGUMENTS[..arguments.len()].iter().rev() {
+            self.instruction("pop", vec![word(register)], span);
+        }
+
+        self.call(frame, &label, span);
+
+        if let Some(register) = result.filter(|register| *register != "rx") {
+            self.instruction("put", vec![word("rx"), word(register)], span);
+        }
+    }
+
+    /// Evaluates `expression` into `rx`.
+    fn expression(&mut self, frame: &Frame, expression: &Expression) {
+        let span = &expression.span;
+
+        match &expression.value {
+            ExpressionKind::Number(number) => self.instruction("put", vec![word("rx"), TokenKind::Number(*number)], span),
+            ExpressionKind::String(string) => {
+                self.count += 1;
+                let label = format!("pcc::string_{}", self.count);
+
+                self.data.push(NodeKind::Label { name: label.clone().with_span(span.clone()) }.with_span(span.clone()));
+                for byte in string.bytes().chain([0]) {
+                    self.data.push(NodeKind::Value { value: TokenKind::Number(byte).with_span(span.clone()) }.with_span(span.clone()));
+                }
+
+                self.instruction("put", vec![word("rx"), word(&label)], span);
+            },
+            ExpressionKind::Variable(name) => if let Some(label) = self.variable(frame, name, span) {
+                self.instruction("ldr", vec![word("rx"), word(&label)], span);
+            },
+            ExpressionKind::Call { name, arguments } => self.call_expression(frame, name, arguments, span),
+            ExpressionKind::Unary(operator, operand) => {
+                self.expression(frame, operand);
+
+                match operator {
+                    Operator::Minus => self.instruction("neg", vec![word("rx")], span),
+                    _ => self.not(frame, span)
+                }
+            },
+            ExpressionKind::Binary(Operator::And, left, right) => {
+                let end = self.unique(frame, "and");
+
+                self.expression(frame, left);
+                self.instruction("jpz", vec![word(&end), word("rx")], span);
+                self.expression(frame, right);
+                self.instruction("jpz", vec![word(&end), word("rx")], span);
+                self.instruction("put", vec![word("rx"), TokenKind::Number(1)], span);
+                self.label(&end, span);
+            },
+            ExpressionKind::Binary(Operator::Or, left, right) => {
+                let (right_label, end) = (self.unique(frame, "or"), self.unique(frame, "end"));
+
+                self.expression(frame, left);
+                self.instruction("jpz", vec![word(&right_label), word("rx")], span);
+                self.instruction("put", vec![word("rx"), TokenKind::Number(1)], span);
+                self.instruction("jmp", vec![word(&end)], span);
+                self.label(&right_label, span);
+                self.expression(frame, right);
+                self.instruction("jpz", vec![word(&end), word("rx")], span);
+                self.instruction("put", vec![word("rx"), TokenKind::Number(1)], span);
+                self.label(&end, span);
+            },
+            ExpressionKind::Binary(operator, left, right) => {
+                self.expression(frame, left);
+                self.instruction("psh", vec![word("rx")], span);
+                self.expression(frame, right);
+                self.instruction("put", vec![word("ry"), word("rx")], span);
+                self.instruction("pop", vec![word("rx")], span);
+
+                // `a > b` is `b < a` and `a <= b` is `b >= a`
+                if matches!(operator, Operator::Greater | Operator::LessEqual) {
+                    self.instruction("psh", vec![word("rx")], span);
+                    self.instruction("put", vec![word("rx"), word("ry")], span);
+                    self.instruction("pop", vec![word("ry")], span);
+                }
+
+                match operator {
+                    Operator::Plus => self.instruction("add", vec![word("rx"), word("ry")], span),
+                    Operator::Minus => self.instruction("sub", vec![word("rx"), word("ry")], span),
+                    Operator::Star | Operator::Slash | Operator::Percent => {
+                        let routine = match operator {
+                            Operator::Star => "mul",
+                            _ => "udivmod"
+                        };
+                        self.runtime.insert(routine);
+                        self.call(frame, routine, span);
+
+                        // the remainder of 'udivmod' is already in rx
+                        if *operator != Operator::Percent {
+                            self.instruction("put", vec![word("rx"), word("rz")], span);
+                        }
+                    },
+                    Operator::Equal => {
+                        self.instruction("sub", vec![word("rx"), word("ry")], span);
+                        self.not(frame, span);
+                    },
+                    Operator::NotEqual => {
+                        let end = self.unique(frame, "end");
+
+                        self.instruction("sub", vec![word("rx"), word("ry")], span);
+                        self.instruction("jpz", vec![word(&end), word("rx")], span);
+                        self.instruction("put", vec![word("rx"), TokenKind::Number(1)], span);
+                        self.label(&end, span);
+                    },
+                    Operator::Less | Operator::Greater => {
+                        self.runtime.insert("uge");
+                        self.call(frame, "uge", span);
+                        self.not(frame, span);
+                    },
+                    Operator::GreaterEqual | Operator::LessEqual => {
+                        self.runtime.insert("uge");
+                        self.call(frame, "uge", span);
+                    },
+                    Operator::And | Operator::Or | Operator::Bang => unreachable!("should be handled above")
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, frame: &mut Frame, name: &WithSpan<String>) -> String {
+        let label = format!("{}::{}", frame.name, &name.value);
+
+        match frame.locals.get(&name.value) {
+            Some((_, previous)) => self.errors.push(redefinition("variable", name, previous)),
+            None => {
+                self.cell(&label, 0, &name.span);
+                frame.locals.insert(name.value.clone(), (label.clone(), name.span.clone()));
+            }
+        }
+
+        label
+    }
+
+    fn statements(&mut self, frame: &mut Frame, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(frame, statement);
+        }
+    }
+
+    fn statement(&mut self, frame: &mut Frame, statement: &Statement) {
+        let span = &statement.span;
+
+        match &statement.value {
+            StatementKind::Var { name, value } => {
+                if let Some(value) = value {
+                    self.expression(frame, value);
+                }
+
+                let label = self.declare(frame, name);
+
+                if value.is_some() {
+                    self.instruction("str", vec![word(&label), word("rx")], span);
+                }
+            },
+            StatementKind::Assign { name, value } => {
+                self.expression(frame, value);
+
+                if let Some(label) = self.variable(frame, &name.value, &name.span) {
+                    self.instruction("str", vec![word(&label), word("rx")], span);
+                }
+            },
+            StatementKind::If { condition, then, otherwise } => {
+                let (otherwise_label, end) = (self.unique(frame, "else"), self.unique(frame, "end"));
+
+                self.expression(frame, condition);
+
+                if otherwise.is_empty() {
+                    self.instruction("jpz", vec![word(&end), word("rx")], &condition.span);
+                    self.statements(frame, then);
+                    self.label(&end, span);
+                    return
+                }
+
+                self.instruction("jpz", vec![word(&otherwise_label), word("rx")], &condition.span);
+                self.statements(frame, then);
+
+                // branches that return don't need to jump past the other one
+                let falls_through = !terminates(then);
+                if falls_through {
+                    self.instruction("jmp", vec![word(&end)], span);
+                }
+
+                self.label(&otherwise_label, span);
+                self.statements(frame, otherwise);
+
+                if falls_through {
+                    self.label(&end, span);
+                }
+            },
+            StatementKind::While { condition, body } => {
+                let (begin, end) = (self.unique(frame, "while"), self.unique(frame, "end"));
+
+                self.label(&begin, span);
+                self.expression(frame, condition);
+                self.instruction("jpz", vec![word(&end), word("rx")], &condition.span);
+
+                frame.loops.push((begin.clone(), end.clone()));
+                self.statements(frame, body);
+                frame.loops.pop();
+
+                if !terminates(body) {
+                    self.instruction("jmp", vec![word(&begin)], span);
+                }
+                self.label(&end, span);
+            },
+            StatementKind::Break | StatementKind::Continue => {
+                let keyword = match statement.value {
+                    StatementKind::Break => "break",
+                    _ => "continue"
+                };
+
+                match frame.loops.last() {
+                    Some((begin, end)) => {
+                        let target = match statement.value {
+                            StatementKind::Break => end.clone(),
+                            _ => begin.clone()
+                        };
+                        self.instruction("jmp", vec![word(&target)], span);
+                    },
+                    None => self.errors.push(
+                        Message::error(format!("'{}' must be inside of a loop", keyword))
+                            .with_id(Code::InvalidControlFlow)
+                            .with_code(String::from("outside of a loop"), span.clone())
+                    )
+                }
+            },
+            StatementKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(frame, value);
+                }
+
+                self.instruction("ret", vec![], span);
+            },
+            StatementKind::Expression(expression) => self.expression(frame, expression)
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        let mut frame = Frame {
+            name: function_label(&function.name.value),
+            locals: HashMap::new(),
+            loops: vec![]
+        };
+
+        self.label(&frame.name, &function.name.span);
+
+        for (parameter, register) in function.parameters.iter().zip(ARGUMENTS) {
+            let label = self.declare(&mut frame, parameter);
+            self.instruction("str", vec![word(&label), word(register)], &parameter.span);
+        }
+
+        self.statements(&mut frame, &function.body);
+
+        if !terminates(&function.body) {
+            self.instruction("ret", vec![], &function.end);
+        }
+    }
+
+    /// Generates the program, starting by calling `main` and halting once
+    /// it returns.
+    pub fn generate(mut self, items: &[Item]) -> Result<(Vec<Node>, BTreeSet<&'static str>)> {
+        for item in items {
+            match &item.value {
+                ItemKind::Global { name, value } => match self.globals.get(&name.value) {
+                    Some((_, previous)) => self.errors.push(redefinition("variable", name, previous)),
+                    None => {
+                        let label = global_label(&name.value);
+
+                        self.cell(&label, value.as_ref().map(|value| value.value).unwrap_or(0), &name.span);
+                        self.globals.insert(name.value.clone(), (label, name.span.clone()));
+                    }
+                },
+                ItemKind::Function(function) => match self.functions.get(&function.name.value) {
+                    Some((_, previous)) => self.errors.push(redefinition("function", &function.name, previous)),
+                    None => {
+                        self.functions.insert(function.name.value.clone(), (function.parameters.len(), function.name.span.clone()));
+                    }
+                }
+            }
+        }
+
+        match self.functions.get("main") {
+            Some((0, span)) => {
+                let span = span.clone();
+
+                self.instruction("psh", vec![word("pcc::exit")], &span);
+                self.instruction("jmp", vec![word(&function_label("main"))], &span);
+                self.label("pcc::exit", &span);
+                self.instruction("hlt", vec![], &span);
+            },
+            Some((_, span)) => self.errors.push(
+                Message::error(String::from("'main' must not take parameters"))
+                    .with_id(Code::InvalidEntryPoint)
+                    .with_code(String::from("declared with parameters"), span.clone())
+            ),
+            None => self.errors.push(Message::error(String::from("the program has no 'main' function")).with_id(Code::InvalidEntryPoint))
+        }
+
+        for item in items {
+            if let ItemKind::Function(function) = &item.value {
+                self.function(function);
+            }
+        }
+
+        if self.errors.is_empty() {
+            let mut out = self.out;
+            out.append(&mut self.data);
+
+            Ok(((out, self.runtime), vec![]))
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, rc::Rc};
+
+    use pasm::source::Source;
+
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    fn generate(text: &str) -> Result<(Vec<Node>, BTreeSet<&'static str>)> {
+        let source = Rc::new(Source::new(text.to_string(), PathBuf::from("test.pc")));
+        let (tokens, _) = Lexer::new(&source).lex().expect("source should lex");
+        let end = Span::new(source.text.len(), source.text.len(), Rc::clone(&source));
+        let (items, _) = Parser::new(tokens, end).parse().expect("source should parse");
+
+        Generator::default().generate(&items)
+    }
+
+    /// Generates `text`, which only declares `main`, and renders its code,
+    /// without the prelude calling it and the variable cells after it. The
+    /// labels of `main` are given without the function's prefix.
+    fn main(text: &str) -> Vec<String> {
+        let ((nodes, _), _) = generate(text).expect("source should generate");
+
+        let begin = nodes.iter()
+            .position(|node| matches!(&node.value, NodeKind::Label { name } if name.value == "pcc::fn::main"))
+            .expect("'main' should be generated");
+        // each cell is a label followed by its value
+        let end = nodes.iter()
+            .position(|node| matches!(node.value, NodeKind::Value { .. }))
+            .map(|value| value - 1)
+            .unwrap_or(nodes.len());
+
+        nodes[begin + 1..end].iter().map(|node| match &node.value {
+            NodeKind::Instruction { name, arguments } => {
+                let arguments: Vec<_> = arguments.iter().map(|argument| match &argument.value {
+                    TokenKind::Word(word) => word.clone(),
+                    TokenKind::Number(number) => number.to_string(),
+                    other => panic!("unexpected argument {:?}", other)
+                }).collect();
+                format!("{} {}", name.value, arguments.join(", ")).trim_end().to_string()
+            },
+            NodeKind::Label { name } => format!("{}:", name.value),
+            other => panic!("unexpected node {:?}", other)
+        }).map(|line| line.replace("pcc::fn::main::", "")).collect()
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        generate(text).expect_err("source shouldn't generate").into_iter().filter_map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn short_circuits_logical_operators() {
+        assert_eq!(main("fn main() { var a; var b; a = a && b; }"), [
+            "ldr rx, a", "jpz 1_and, rx", "ldr rx, b", "jpz 1_and, rx", "put rx, 1", "1_and:",
+            "str a, rx", "ret"
+        ]);
+        assert_eq!(main("fn main() { var a; var b; a = a || b; }"), [
+            "ldr rx, a", "jpz 1_or, rx", "put rx, 1", "jmp 2_end", "1_or:",
+            "ldr rx, b", "jpz 2_end, rx", "put rx, 1", "2_end:",
+            "str a, rx", "ret"
+        ]);
+    }
+
+    #[test]
+    fn swaps_the_operands_of_greater_and_less_equal() {
+        let compare = |operator: &str| main(&format!("fn main() {{ var a; var b; a = a {} b; }}", operator));
+        let swap = ["psh rx", "put rx, ry", "pop ry"];
+
+        let less = compare("<");
+        let greater = compare(">");
+        assert_eq!(&less[..5], ["ldr rx, a", "psh rx", "ldr rx, b", "put ry, rx", "pop rx"]);
+        assert_eq!(&less[5..8], ["psh 1_ret", "jmp uge", "1_ret:"]);
+        assert_eq!(greater[..5], less[..5]);
+        assert_eq!(&greater[5..8], swap);
+        assert_eq!(greater[8..], less[5..]);
+
+        let greater_equal = compare(">=");
+        let less_equal = compare("<=");
+        assert_eq!(&less_equal[5..8], swap);
+        assert_eq!(less_equal[8..], greater_equal[5..]);
+    }
+
+    #[test]
+    fn generates_else_if_chains() {
+        assert_eq!(main("fn main() { var a; var b; if (a) { return 1; } else if (b) { return 2; } }"), [
+            "ldr rx, a", "jpz 1_else, rx", "put rx, 1", "ret", "1_else:",
+            "ldr rx, b", "jpz 4_end, rx", "put rx, 2", "ret", "4_end:",
+            "ret"
+        ]);
+    }
+
+    #[test]
+    fn jumps_out_of_and_back_into_loops() {
+        assert_eq!(main("fn main() { var a; var b; while (a) { if (b) { break; } continue; } }"), [
+            "1_while:", "ldr rx, a", "jpz 2_end, rx",
+            "ldr rx, b", "jpz 4_end, rx", "jmp 2_end", "4_end:",
+            "jmp 1_while", "2_end:",
+            "ret"
+        ]);
+        assert!(matches!(codes("fn main() { break; }")[..], [Code::InvalidControlFlow]));
+    }
+
+    #[test]
+    fn keeps_user_symbols_apart_from_std_routines() {
+        let text = "var mul; fn udivmod(a, b) { return a; } fn main() { mul = 2 * 3; mul = udivmod(7, 3); }";
+        let ((_, runtime), _) = generate(text).expect("source should generate");
+        let main = main(text);
+
+        assert!(runtime.contains("mul"));
+        assert!(main.iter().any(|line| line == "jmp mul"));
+        assert!(main.iter().any(|line| line == "str pcc::var::mul, rx"));
+        assert!(main.iter().any(|line| line == "jmp pcc::fn::udivmod"));
+    }
+
+    #[test]
+    fn checks_argument_counts() {
+        assert!(matches!(
+            codes("fn f(a) {} fn main() { f(); mul(1); poke(1); }")[..],
+            [Code::WrongArgumentCount, Code::WrongArgumentCount, Code::WrongArgumentCount]
+        ));
+        assert!(matches!(codes("fn main() { g(); a = 1; }")[..], [Code::UndeclaredLabel, Code::UndeclaredLabel]));
+    }
+
+    #[test]
+    fn requires_main() {
+        assert!(matches!(codes("fn f() {}")[..], [Code::InvalidEntryPoint]));
+        assert!(matches!(codes("fn main(a) {}")[..], [Code::InvalidEntryPoint]));
+        assert!(matches!(codes("var a; var a; fn main() {}")[..], [Code::LabelRedefinition]));
+    }
+}
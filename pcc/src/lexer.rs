@@ -0,0 +1,253 @@
+use std::{str::Chars, rc::Rc, num::IntErrorKind};
+
+use pasm::{source::{Span, WithSpan, IntoWithSpan, Source}, message::{Message, Result}, code::Code};
+
+use crate::ast::Operator;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Word(String),
+    Number(u8),
+    String(String),
+    Operator(Operator),
+    /// `=`, assigning a variable
+    Equals,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon
+}
+
+pub type Token = WithSpan<TokenKind>;
+
+pub struct Lexer<'a> {
+    text: Chars<'a>,
+    index: usize,
+    current: Option<char>,
+    source: Rc<Source>
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a Rc<Source>) -> Self {
+        let mut out = Self {
+            text: source.text.chars(),
+            index: 0,
+            current: None,
+            source: Rc::clone(source)
+        };
+        out.advance();
+        out
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        if let Some(current) = self.current {
+            self.index += current.len_utf8();
+        }
+        self.current = self.text.next();
+        self.current
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text.clone().next()
+    }
+
+    fn span(&self, begin: usize) -> Span {
+        Span::new(begin, self.index, Rc::clone(&self.source))
+    }
+
+    fn make_word(&mut self) -> Token {
+        let begin = self.index;
+        let mut text = String::new();
+
+        while let Some(current) = self.current.filter(|current| current.is_alphanumeric() || *current == '_') {
+            text.push(current);
+            self.advance();
+        }
+
+        TokenKind::Word(text).with_span(self.span(begin))
+    }
+
+    fn make_number(&mut self) -> core::result::Result<Token, Message> {
+        let begin = self.index;
+        let mut text = String::new();
+
+        while let Some(current) = self.current.filter(|current| current.is_alphanumeric() || *current == '_') {
+            if current != '_' {
+                text.push(current);
+            }
+            self.advance();
+        }
+
+        let span = self.span(begin);
+        let (digits, radix) = match text.get(..2) {
+            Some("0x") => (&text[2..], 16),
+            Some("0b") => (&text[2..], 2),
+            _ => (text.as_str(), 10)
+        };
+
+        let number = u8::from_str_radix(digits, radix).map_err(|error| match error.kind() {
+            IntErrorKind::PosOverflow => Message::error(format!("integer overflow: {} can't fit in a byte", &text))
+                .with_id(Code::NumberOutOfRange)
+                .with_code(String::from("too big"), span.clone()),
+            _ => Message::error(String::from("numbers must contain valid digits"))
+                .with_id(Code::InvalidNumber)
+                .with_code(String::from("includes invalid digits"), span.clone())
+        })?;
+
+        Ok(TokenKind::Number(number).with_span(span))
+    }
+
+    /// Reads a character of a character or string literal, unescaping it.
+    fn escaped_char(&mut self) -> Option<char> {
+        let character = match self.current? {
+            '\\' => match self.advance()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                character => character
+            },
+            character => character
+        };
+        self.advance();
+
+        Some(character)
+    }
+
+    fn make_character(&mut self) -> core::result::Result<Token, Message> {
+        let begin = self.index;
+        self.advance();
+
+        let character = self.escaped_char();
+
+        if self.current != Some('\'') {
+            return Err(
+                Message::error(String::from("character literals must be closed"))
+                    .with_id(Code::InvalidCharacterLiteral)
+                    .with_code(String::from("expected '''"), self.span(begin))
+            );
+        }
+        self.advance();
+
+        let span = self.span(begin);
+
+        match character.map(u8::try_from) {
+            Some(Ok(byte)) => Ok(TokenKind::Number(byte).with_span(span)),
+            _ => Err(
+                Message::error(String::from("characters must fit in a byte"))
+                    .with_id(Code::InvalidCharacterLiteral)
+                    .with_code(String::from("does not fit in a byte"), span)
+            )
+        }
+    }
+
+    fn make_string(&mut self) -> core::result::Result<Token, Message> {
+        let begin = self.index;
+        let mut text = String::new();
+        self.advance();
+
+        while self.current != Some('"') {
+            match self.escaped_char() {
+                Some(character) => text.push(character),
+                None => return Err(
+                    Message::error(String::from("strings must be closed"))
+                        .with_id(Code::UnterminatedString)
+                        .with_code(String::from("expected '\"'"), self.span(begin))
+                )
+            }
+        }
+        self.advance();
+
+        Ok(TokenKind::String(text).with_span(self.span(begin)))
+    }
+
+    fn make_singleton(&mut self, kind: TokenKind) -> Token {
+        let begin = self.index;
+        self.advance();
+
+        kind.with_span(self.span(begin))
+    }
+
+    fn make_operator(&mut self) -> Option<Token> {
+        let begin = self.index;
+        let current = self.current?;
+
+        let operator = *Operator::ALL.iter().find(|operator| {
+            let mut text = operator.as_str().chars();
+            text.next() == Some(current) && text.eq(self.text.clone().take(operator.as_str().len() - 1))
+        })?;
+
+        for _ in 0..operator.as_str().len() {
+            self.advance();
+        }
+
+        Some(TokenKind::Operator(operator).with_span(self.span(begin)))
+    }
+
+    fn chop_whitespace_and_comments(&mut self) {
+        loop {
+            match self.current {
+                Some(current) if current.is_whitespace() => {
+                    self.advance();
+                },
+                Some('/') if self.peek() == Some('/') => while !matches!(self.advance(), Some('\n') | None) {},
+                _ => break
+            }
+        }
+    }
+
+    pub fn lex(&mut self) -> Result<Vec<Token>> {
+        let mut errors = vec![];
+        let mut items = vec![];
+
+        for item in self {
+            match item {
+                Ok(item) => items.push(item),
+                Err(error) => errors.push(error)
+            }
+        }
+
+        if errors.is_empty() {
+            Ok((items, vec![]))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = core::result::Result<Token, Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chop_whitespace_and_comments();
+
+        let current = self.current?;
+        Some(match current {
+            _ if current.is_alphabetic() || current == '_' => Ok(self.make_word()),
+            _ if current.is_ascii_digit() => self.make_number(),
+            '"'  => self.make_string(),
+            '\'' => self.make_character(),
+            '='  if self.peek() != Some('=') => Ok(self.make_singleton(TokenKind::Equals)),
+            '('  => Ok(self.make_singleton(TokenKind::LeftParen)),
+            ')'  => Ok(self.make_singleton(TokenKind::RightParen)),
+            '{'  => Ok(self.make_singleton(TokenKind::LeftBrace)),
+            '}'  => Ok(self.make_singleton(TokenKind::RightBrace)),
+            ','  => Ok(self.make_singleton(TokenKind::Comma)),
+            ';'  => Ok(self.make_singleton(TokenKind::Semicolon)),
+            _    => match self.make_operator() {
+                Some(operator) => Ok(operator),
+                None => {
+                    let begin = self.index;
+                    self.advance();
+
+                    Err(
+                        Message::error(format!("'{}' is not a valid character", current))
+                            .with_id(Code::IllegalCharacter)
+                            .with_code(String::from("illegal character"), self.span(begin))
+                    )
+                }
+            }
+        })
+    }
+}
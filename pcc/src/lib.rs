@@ -0,0 +1,117 @@
+pub mod lexer;
+pub mod ast;
+pub mod parser;
+pub mod codegen;
+
+use std::{rc::Rc, path::Path};
+
+use pasm::{compiler::{self, Image, Options}, parser::Parser as NodeParser, source::{Source, Span}, message::Result};
+
+use crate::{lexer::Lexer, parser::Parser, codegen::Generator};
+
+/// The devices the std routines print to and read from, unless defined with
+/// `-D`: the STI terminal and the keyboard.
+const DEVICES: &str = "\
+%ifndef OUT
+%define OUT 0xff
+%end
+%ifndef IN
+%define IN 0xfc
+%end
+";
+
+/// Compiles `source` into an image without writing it, taking the std
+/// routines it calls from the files of `std`.
+pub fn build(source: Source, std: &Path, options: &Options) -> Result<Image> {
+    let mut warnings = vec![];
+    let source = Rc::new(source);
+
+    if options.verbose {
+        println!("parsing...")
+    }
+
+    let (tokens, mut w) = Lexer::new(&source).lex()?;
+    warnings.append(&mut w);
+
+    let end = Span::new(source.text.len(), source.text.len(), Rc::clone(&source));
+    let (items, mut w) = Parser::new(tokens, end).parse()?;
+    warnings.append(&mut w);
+
+    if options.verbose {
+        println!("generating nodes...")
+    }
+
+    let ((mut nodes, runtime), mut w) = Generator::default().generate(&items)?;
+    warnings.append(&mut w);
+
+    // the routines are included as pasm, so they're preprocessed as usual
+    let runtime = Source::new(
+        DEVICES.to_string() + &runtime.iter().map(|routine| format!("%include \"{}.pasm\"\n", routine)).collect::<String>(),
+        std.join("<runtime>")
+    );
+
+    let (scope, mut w) = compiler::preprocess(runtime, options)?;
+    warnings.append(&mut w);
+
+    let macros = scope.symbol_names().cloned().collect();
+    let (mut runtime, mut w) = NodeParser::new(scope.tokens).parse()?;
+    warnings.append(&mut w);
+    nodes.append(&mut runtime);
+
+    let (image, mut w) = compiler::assemble(nodes, macros, options)?;
+    warnings.append(&mut w);
+
+    let (warnings, errors) = options.lints.apply(warnings, &scope.allows);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok((image, warnings))
+}
+
+pub fn compile(source: Source, std: &Path, options: &Options) -> Result<()> {
+    let (image, warnings) = build(source, std, options)?;
+
+    image.write(options)?;
+    Ok(((), warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn build_text(text: &str) -> Result<Image> {
+        let std = Path::new(env!("CARGO_MANIFEST_DIR")).join("../pasm/examples/std");
+
+        build(Source::new(text.to_string(), PathBuf::from("test.pc")), &std, &Options::default())
+    }
+
+    #[test]
+    fn user_symbols_do_not_clash_with_std() {
+        for text in [
+            "var mul; fn main() { var x = 2 * 3; mul = x; }",
+            "fn udivmod(a, b) { return a; } fn main() { var x = 7 / 3; x = udivmod(x, 1); }",
+            "var f; fn f() {} fn main() { f = 1; f(); }"
+        ] {
+            if let Err(errors) = build_text(text) {
+                panic!("{:?} doesn't compile: {:?}", text, errors);
+            }
+        }
+    }
+
+    #[test]
+    fn compiles_the_examples() {
+        let text = include_str!("../examples/fibonacci.pc");
+
+        match build_text(text) {
+            Ok((image, warnings)) => {
+                assert!(!image.bytes.is_empty());
+                assert!(warnings.is_empty(), "{:?}", warnings);
+            },
+            Err(errors) => panic!("the example doesn't compile: {:?}", errors)
+        }
+    }
+}
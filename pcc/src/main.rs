@@ -0,0 +1,110 @@
+use std::{fs, path::{PathBuf, Path}, io::{self, Write}, process::ExitCode};
+
+use clap::{command, arg, crate_version, ArgAction};
+use itertools::Itertools;
+use pasm::{compiler::{Options, Define}, source::Source, message::{Message, ColorChoice, human_count}, lint::Lints, preprocessor::DEFAULT_MAX_DEPTH, code::Code};
+
+fn report<T: Write>(errors: &[Message], input_path: &Path, mut out: T) {
+    for error in errors {
+        error.format(&mut out)
+    }
+    Message::error(format!("could not compile '{}' due to previous {}",
+        input_path.display(), human_count("error", errors.len())
+    )).format(&mut out);
+
+    let codes: Vec<String> = errors.iter()
+        .filter_map(|error| error.code)
+        .unique()
+        .map(|code| code.to_string())
+        .collect();
+    if let Some(code) = codes.first() {
+        writeln!(out, "For more information about an error, try `pasm --explain {}`.", code).unwrap();
+    }
+}
+
+fn main() -> ExitCode {
+    let mut stdout = io::stdout();
+
+    let matches = command!()
+        .about("Compiler of a small C-like language for the POC-8 computer architecture")
+        .version(crate_version!())
+
+        .arg(arg!(                     <input>                             "Input file"))
+        .arg(arg!(-o        --output    <FILE>                            "Output file"))
+        .arg(arg!(-s  --"image-size"    <SIZE>  "The size of the output image in bytes"))
+        .arg(arg!(-v        --verbose                        "Toggle verbose reporting"))
+        .arg(arg!(          --std        <DIR>     "Directory of the std pasm routines")
+            .required(true))
+        .arg(arg!(-D        --define   <SYMBOL>  "Define a symbol for the std routines")
+            .action(ArgAction::Append))
+        .arg(arg!(          --color     <WHEN>    "Colour diagnostics: auto, always or never")
+            .value_parser(["auto", "always", "never"])
+            .default_value("auto"))
+
+        .get_matches();
+
+    matches.get_one::<String>("color")
+        .and_then(|color| ColorChoice::from_name(color))
+        .unwrap_or(ColorChoice::Auto)
+        .apply();
+
+    let input_path = PathBuf::from(matches.get_one::<String>("input")
+        .expect("Input should be present"));
+    let output_path = match matches.get_one::<String>("output") {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let mut output = input_path.clone();
+            output.set_extension("bin");
+            output
+        }
+    };
+    let image_size = match matches.get_one::<String>("image-size").map(|image_size| image_size.parse::<usize>()) {
+        Some(Ok(image_size)) => Some(image_size),
+        Some(Err(error)) => {
+            Message::error(format!("invalid image size: {}", error)).with_id(Code::InvalidNumber).format(&mut stdout);
+            return ExitCode::FAILURE;
+        },
+        None => None
+    };
+    let std = PathBuf::from(matches.get_one::<String>("std")
+        .expect("std should be present"));
+
+    let text = match fs::read_to_string(&input_path) {
+        Ok(text) => text,
+        Err(error) => {
+            Message::error(format!("could not read '{}': {}", input_path.display(), error)).with_id(Code::FileNotFound).format(&mut stdout);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let options = Options {
+        output_path,
+        image_size,
+        verbose: matches.get_flag("verbose"),
+        check_stack: false,
+        cfg_path: None,
+        lints: Lints::default(),
+        macro_depth: DEFAULT_MAX_DEPTH,
+        defines: matches.get_many::<String>("define").into_iter().flatten()
+            .map(|argument| match argument.split_once('=') {
+                Some((name, value)) => Define::Set { name: name.to_string(), value: Some(value.to_string()) },
+                None => Define::Set { name: argument.clone(), value: None }
+            })
+            .collect(),
+        opened: Default::default()
+    };
+
+    match pcc::compile(Source::new(text, input_path.clone()), &std, &options) {
+        Ok(((), warnings)) => {
+            for warning in warnings {
+                warning.format(&mut stdout)
+            }
+
+            ExitCode::SUCCESS
+        },
+        Err(errors) => {
+            report(&errors, &input_path, &mut stdout);
+            ExitCode::FAILURE
+        }
+    }
+}
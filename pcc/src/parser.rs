@@ -0,0 +1,473 @@
+use std::rc::Rc;
+
+use pasm::{source::{Span, WithSpan, IntoWithSpan}, message::{Message, Result}, code::Code};
+
+use crate::{lexer::{Token, TokenKind}, ast::{Operator, Expression, ExpressionKind, Statement, StatementKind, Function, Item, ItemKind}};
+
+type Parsed<T> = core::result::Result<T, Message>;
+
+fn join(begin: &Span, end: &Span) -> Span {
+    Span {
+        end: end.end,
+        ..begin.clone()
+    }
+}
+
+/// The most arguments a function can take, one per register.
+pub const MAX_PARAMETERS: usize = 3;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    /// where the source ends, for errors about missing tokens
+    end: Span
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>, end: Span) -> Self {
+        Self { tokens, index: 0, end }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn at(&self, kind: &TokenKind) -> bool {
+        self.peek().is_some_and(|token| &token.value == kind)
+    }
+
+    fn at_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(WithSpan { value: TokenKind::Word(current), .. }) if current == word)
+    }
+
+    fn expected(&self, what: &str) -> Message {
+        let span = self.peek()
+            .map(|token| token.span.clone())
+            .unwrap_or_else(|| Span::new(self.end.end, self.end.end + 1, Rc::clone(&self.end.source)));
+
+        Message::error(format!("expected {}, found '{}'", what, span.get_text()))
+            .with_id(Code::InvalidSyntax)
+            .with_code(format!("expected {}", what), span)
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Parsed<Span> {
+        match self.at(&kind) {
+            true => Ok(self.next().expect("token should be present").span),
+            false => Err(self.expected(what))
+        }
+    }
+
+    fn name(&mut self) -> Parsed<WithSpan<String>> {
+        match self.peek() {
+            Some(WithSpan { value: TokenKind::Word(word), span }) if !is_keyword(word) => {
+                let name = word.clone().with_span(span.clone());
+                self.index += 1;
+                Ok(name)
+            },
+            _ => Err(self.expected("name"))
+        }
+    }
+
+    fn primary(&mut self) -> Parsed<Expression> {
+        let Some(token) = self.next() else {
+            return Err(self.expected("expression"));
+        };
+
+        let kind = match token.value {
+            TokenKind::Number(number) => ExpressionKind::Number(number),
+            TokenKind::String(string) => ExpressionKind::String(string),
+            TokenKind::Word(word) if !is_keyword(&word) => match self.at(&TokenKind::LeftParen) {
+                true => {
+                    self.index += 1;
+                    let mut arguments = vec![];
+
+                    while !self.at(&TokenKind::RightParen) {
+                        arguments.push(self.expression()?);
+
+                        if !self.at(&TokenKind::RightParen) {
+                            self.expect(TokenKind::Comma, "',' or ')'")?;
+                        }
+                    }
+                    let end = self.expect(TokenKind::RightParen, "')'")?;
+
+                    return Ok(WithSpan {
+                        value: ExpressionKind::Call { name: word.with_span(token.span.clone()), arguments },
+                        span: join(&token.span, &end)
+                    });
+                },
+                false => ExpressionKind::Variable(word)
+            },
+            TokenKind::Operator(operator @ (Operator::Minus | Operator::Bang)) => {
+                let operand = self.primary()?;
+                let span = join(&token.span, &operand.span);
+
+                return Ok(WithSpan { value: ExpressionKind::Unary(operator, Box::new(operand)), span });
+            },
+            TokenKind::LeftParen => {
+                let inner = self.expression()?;
+                let end = self.expect(TokenKind::RightParen, "')'")?;
+
+                return Ok(WithSpan { value: inner.value, span: join(&token.span, &end) });
+            },
+            _ => {
+                self.index -= 1;
+                return Err(self.expected("expression"));
+            }
+        };
+
+        Ok(WithSpan { value: kind, span: token.span })
+    }
+
+    /// Parses operators binding tighter than `precedence` by precedence climbing.
+    fn binary(&mut self, precedence: u8) -> Parsed<Expression> {
+        let mut left = self.primary()?;
+
+        while let Some(WithSpan { value: TokenKind::Operator(operator), .. }) = self.peek() {
+            let operator = *operator;
+
+            match operator.precedence() {
+                Some(next) if next > precedence => {
+                    self.index += 1;
+
+                    let right = self.binary(next)?;
+                    let span = join(&left.span, &right.span);
+
+                    left = WithSpan { value: ExpressionKind::Binary(operator, Box::new(left), Box::new(right)), span };
+                },
+                _ => break
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn expression(&mut self) -> Parsed<Expression> {
+        self.binary(0)
+    }
+
+    fn block(&mut self) -> Parsed<(Vec<Statement>, Span)> {
+        self.expect(TokenKind::LeftBrace, "'{'")?;
+        let mut statements = vec![];
+
+        while !self.at(&TokenKind::RightBrace) {
+            if self.peek().is_none() {
+                return Err(self.expected("'}'"));
+            }
+
+            statements.push(self.statement()?);
+        }
+        let end = self.expect(TokenKind::RightBrace, "'}'")?;
+
+        Ok((statements, end))
+    }
+
+    fn statement(&mut self) -> Parsed<Statement> {
+        let begin = self.peek().expect("statement should have tokens").span.clone();
+        let keyword = match self.peek() {
+            Some(WithSpan { value: TokenKind::Word(word), .. }) if is_keyword(word) => Some(word.clone()),
+            _ => None
+        };
+
+        let kind = match keyword.as_deref() {
+            Some("var") => {
+                self.index += 1;
+                let name = self.name()?;
+                let value = match self.at(&TokenKind::Equals) {
+                    true => {
+                        self.index += 1;
+                        Some(self.expression()?)
+                    },
+                    false => None
+                };
+
+                StatementKind::Var { name, value }
+            },
+            Some("if") => {
+                self.index += 1;
+                self.expect(TokenKind::LeftParen, "'('")?;
+                let condition = self.expression()?;
+                self.expect(TokenKind::RightParen, "')'")?;
+                let (then, mut end) = self.block()?;
+
+                let otherwise = match self.at_word("else") {
+                    true => {
+                        self.index += 1;
+
+                        match self.at_word("if") {
+                            true => {
+                                let statement = self.statement()?;
+                                end = statement.span.clone();
+                                vec![statement]
+                            },
+                            false => {
+                                let (otherwise, otherwise_end) = self.block()?;
+                                end = otherwise_end;
+                                otherwise
+                            }
+                        }
+                    },
+                    false => vec![]
+                };
+
+                return Ok(StatementKind::If { condition, then, otherwise }.with_span(join(&begin, &end)));
+            },
+            Some("while") => {
+                self.index += 1;
+                self.expect(TokenKind::LeftParen, "'('")?;
+                let condition = self.expression()?;
+                self.expect(TokenKind::RightParen, "')'")?;
+                let (body, end) = self.block()?;
+
+                return Ok(StatementKind::While { condition, body }.with_span(join(&begin, &end)));
+            },
+            Some("break") => {
+                self.index += 1;
+                StatementKind::Break
+            },
+            Some("continue") => {
+                self.index += 1;
+                StatementKind::Continue
+            },
+            Some("return") => {
+                self.index += 1;
+
+                match self.at(&TokenKind::Semicolon) {
+                    true => StatementKind::Return(None),
+                    false => StatementKind::Return(Some(self.expression()?))
+                }
+            },
+            Some(_) => return Err(self.expected("statement")),
+            None => match self.tokens.get(self.index + 1).map(|token| &token.value) {
+                Some(TokenKind::Equals) => {
+                    let name = self.name()?;
+                    self.index += 1;
+
+                    StatementKind::Assign { name, value: self.expression()? }
+                },
+                _ => StatementKind::Expression(self.expression()?)
+            }
+        };
+
+        let end = self.expect(TokenKind::Semicolon, "';'")?;
+
+        Ok(kind.with_span(join(&begin, &end)))
+    }
+
+    fn global(&mut self, begin: Span) -> Parsed<Item> {
+        let name = self.name()?;
+        let value = match self.at(&TokenKind::Equals) {
+            true => {
+                self.index += 1;
+
+                match self.next() {
+                    Some(WithSpan { value: TokenKind::Number(number), span }) => Some(number.with_span(span)),
+                    _ => {
+                        self.index -= 1;
+                        return Err(self.expected("number").with_note(String::from("globals are initialized with constants")));
+                    }
+                }
+            },
+            false => None
+        };
+        let end = self.expect(TokenKind::Semicolon, "';'")?;
+
+        Ok(ItemKind::Global { name, value }.with_span(join(&begin, &end)))
+    }
+
+    fn function(&mut self, begin: Span) -> Parsed<Item> {
+        let name = self.name()?;
+        self.expect(TokenKind::LeftParen, "'('")?;
+
+        let mut parameters = vec![];
+        while !self.at(&TokenKind::RightParen) {
+            parameters.push(self.name()?);
+
+            if !self.at(&TokenKind::RightParen) {
+                self.expect(TokenKind::Comma, "',' or ')'")?;
+            }
+        }
+        self.expect(TokenKind::RightParen, "')'")?;
+
+        if let Some(parameter) = parameters.get(MAX_PARAMETERS) {
+            return Err(
+                Message::error(format!("function '{}' takes more than {} parameters", &name.value, MAX_PARAMETERS))
+                    .with_id(Code::InvalidSyntax)
+                    .with_code(String::from("too many parameters"), parameter.span.clone())
+                    .with_note(String::from("arguments are passed in 'rx', 'ry' and 'rz'"))
+            );
+        }
+
+        let (body, end) = self.block()?;
+
+        Ok(ItemKind::Function(Function { name, parameters, body, end: end.clone() }).with_span(join(&begin, &end)))
+    }
+
+    fn item(&mut self) -> Parsed<Item> {
+        match self.next() {
+            Some(WithSpan { value: TokenKind::Word(word), span }) if word == "var" => self.global(span),
+            Some(WithSpan { value: TokenKind::Word(word), span }) if word == "fn" => self.function(span),
+            _ => {
+                self.index -= 1;
+                Err(self.expected("'fn' or 'var'"))
+            }
+        }
+    }
+
+    /// Skips to the next function after an error.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+
+        while let Some(token) = self.peek() {
+            match &token.value {
+                TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightBrace => depth -= 1,
+                TokenKind::Word(word) if depth <= 0 && word == "fn" => break,
+                _ => ()
+            }
+
+            self.index += 1;
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Item>> {
+        let mut errors = vec![];
+        let mut items = vec![];
+
+        while self.peek().is_some() {
+            match self.item() {
+                Ok(item) => items.push(item),
+                Err(error) => {
+                    errors.push(error);
+                    self.index += 1;
+                    self.synchronize();
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok((items, vec![]))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub fn is_keyword(word: &str) -> bool {
+    matches!(word, "fn" | "var" | "if" | "else" | "while" | "break" | "continue" | "return")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pasm::source::Source;
+
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(text: &str) -> Result<Vec<Item>> {
+        let source = Rc::new(Source::new(text.to_string(), PathBuf::from("test.pc")));
+        let (tokens, _) = Lexer::new(&source).lex().expect("source should lex");
+        let end = Span::new(source.text.len(), source.text.len(), Rc::clone(&source));
+
+        Parser::new(tokens, end).parse()
+    }
+
+    /// Renders `expression` with every operation in parentheses.
+    fn render(expression: &Expression) -> String {
+        match &expression.value {
+            ExpressionKind::Number(number) => number.to_string(),
+            ExpressionKind::String(string) => format!("{:?}", string),
+            ExpressionKind::Variable(name) => name.clone(),
+            ExpressionKind::Call { name, arguments } => format!(
+                "{}({})", &name.value, arguments.iter().map(render).collect::<Vec<_>>().join(", ")
+            ),
+            ExpressionKind::Unary(operator, operand) => format!("({}{})", operator.as_str(), render(operand)),
+            ExpressionKind::Binary(operator, left, right) => format!("({} {} {})", render(left), operator.as_str(), render(right))
+        }
+    }
+
+    /// Parses `text` as the body of `main` and returns its statements.
+    fn statements(text: &str) -> Vec<Statement> {
+        let (mut items, _) = parse(&format!("fn main() {{ {} }}", text)).expect("source should parse");
+
+        match items.pop().map(|item| item.value) {
+            Some(ItemKind::Function(function)) => function.body,
+            other => panic!("expected a function, found {:?}", other)
+        }
+    }
+
+    fn expression(text: &str) -> String {
+        match &statements(&format!("{};", text))[..] {
+            [WithSpan { value: StatementKind::Expression(expression), .. }] => render(expression),
+            other => panic!("expected an expression, found {:?}", other)
+        }
+    }
+
+    fn codes(text: &str) -> Vec<Code> {
+        parse(text).expect_err("source shouldn't parse").into_iter().filter_map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn binds_tighter_operators_first() {
+        assert_eq!(expression("1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(expression("a || b && c"), "(a || (b && c))");
+        assert_eq!(expression("a == b < c + d"), "(a == (b < (c + d)))");
+        assert_eq!(expression("-a * !b % c"), "(((-a) * (!b)) % c)");
+        assert_eq!(expression("(1 + 2) * f(3, x)"), "((1 + 2) * f(3, x))");
+    }
+
+    #[test]
+    fn groups_left_to_right() {
+        assert_eq!(expression("10 - 4 - 3"), "((10 - 4) - 3)");
+        assert_eq!(expression("a / b * c"), "((a / b) * c)");
+        assert_eq!(expression("a <= b >= c"), "((a <= b) >= c)");
+    }
+
+    #[test]
+    fn parses_else_if_as_a_nested_if() {
+        let statements = statements("if (a) { return 1; } else if (b) { return 2; } else { return 3; }");
+
+        let [WithSpan { value: StatementKind::If { then, otherwise, .. }, .. }] = &statements[..] else {
+            panic!("expected an 'if', found {:?}", statements);
+        };
+        let [WithSpan { value: StatementKind::If { condition, otherwise: last, .. }, .. }] = &otherwise[..] else {
+            panic!("expected an 'else if', found {:?}", otherwise);
+        };
+
+        assert_eq!(then.len(), 1);
+        assert_eq!(render(condition), "b");
+        assert_eq!(last.len(), 1);
+    }
+
+    #[test]
+    fn parses_statements() {
+        let kinds: Vec<_> = statements("var x = 1; x = x + 1; while (x) { break; continue; } return; f();").into_iter()
+            .map(|statement| match statement.value {
+                StatementKind::Var { .. } => "var",
+                StatementKind::Assign { .. } => "assign",
+                StatementKind::While { .. } => "while",
+                StatementKind::Return(None) => "return",
+                StatementKind::Expression(_) => "expression",
+                _ => "other"
+            })
+            .collect();
+
+        assert_eq!(kinds, ["var", "assign", "while", "return", "expression"]);
+    }
+
+    #[test]
+    fn rejects_invalid_syntax() {
+        assert!(matches!(codes("fn main() { var x = ; }")[..], [Code::InvalidSyntax]));
+        assert!(matches!(codes("fn main() { x = 1 }")[..], [Code::InvalidSyntax]));
+        assert!(matches!(codes("fn f(a, b, c, d) {}")[..], [Code::InvalidSyntax]));
+        // parsing resumes at the next function
+        assert!(matches!(codes("fn f() { ) } fn g() { ( }")[..], [Code::InvalidSyntax, Code::InvalidSyntax]));
+    }
+}